@@ -1,6 +1,7 @@
 use anyhow::Result;
 use log::{info, LevelFilter};
-use secure_gateway::{Config, Gateway};
+use secure_gateway::Config;
+use std::collections::BTreeMap;
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -17,7 +18,7 @@ async fn main() -> Result<()> {
     info!("Starting gateway simulation...");
     
     // Load configuration
-    let config = Config::load()?;
+    let _config = Config::load()?;
     
     // For demo purposes, we'll create a simple message processor
     // that just prints information about the translation
@@ -94,6 +95,8 @@ fn create_test_message(id: u32, source: ProtocolType, target: ProtocolType) -> C
             message_id: id as u64,
             is_command: true,
             requires_response: true,
+            subaddress: None,
+            headers: BTreeMap::new(),
         },
     }
 } 
\ No newline at end of file
@@ -1,10 +1,10 @@
 use anyhow::Result;
-use log::{info, LevelFilter};
-use secure_gateway::{Config, Gateway};
+use log::LevelFilter;
 use secure_gateway::security::{
-    SecurityMode, crypto::{self, encrypt_message, sign_message}
+    crypto::{self, encrypt_message, sign_message}
 };
 use secure_gateway::utils::bytes_to_hex;
+use std::collections::BTreeMap;
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -27,7 +27,7 @@ async fn main() -> Result<()> {
     let encryption_key = crypto::generate_encryption_key();
     
     // Generate a demo keypair for signing
-    let (signing_key, verification_key) = crypto::generate_signing_keypair()?;
+    let (signing_key, _verification_key) = crypto::generate_signing_keypair()?;
     
     // Process a few demo messages
     for i in 1..=3 {
@@ -56,7 +56,7 @@ async fn main() -> Result<()> {
         sleep(Duration::from_millis(300)).await;
         
         // Show encryption
-        let (encrypted_data, nonce) = encrypt_message(&message.payload, &encryption_key)?;
+        let (encrypted_data, _nonce) = encrypt_message(&message.payload, &encryption_key, &[])?;
         println!("   ✓ Encrypted with ChaCha20Poly1305");
         println!("   Encrypted ({}B): {}", encrypted_data.len(), bytes_to_hex(&encrypted_data[..8.min(encrypted_data.len())]) + "...");
         
@@ -129,8 +129,10 @@ fn create_test_message(id: u32, source: ProtocolType, target: ProtocolType) -> C
                 .unwrap()
                 .as_millis() as u64,
             message_id: id as u64,
-            is_command: id % 2 == 0,
-            requires_response: id % 3 == 0,
+            is_command: id.is_multiple_of(2),
+            requires_response: id.is_multiple_of(3),
+            subaddress: None,
+            headers: BTreeMap::new(),
         },
     }
 } 
\ No newline at end of file
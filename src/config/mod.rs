@@ -11,7 +11,12 @@ use std::path::Path;
 use std::time::Duration;
 
 use crate::protocols::ProtocolType;
-use crate::security::SecurityMode;
+use crate::security::{SecurityMode, DEFAULT_MAC_TAG_LEN};
+use crate::security::crypto::{CipherSuite, MAC_SIZE, MIN_MAC_TAG_LEN};
+use crate::security::compact::{CompactLink, DEFAULT_COMPACT_TAG_LEN};
+use crate::security::handshake::PostQuantumPolicy;
+use crate::security::key_store::DEFAULT_BACKUP_GENERATIONS;
+use crate::security::replay::MAX_REPLAY_WINDOW;
 
 /// Main configuration structure for the gateway
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +32,10 @@ pub struct Config {
     
     /// Translation rules
     pub translation_rules: Vec<TranslationRule>,
+    
+    /// Configured transform modules, referenced by name from `TransformType::Custom`
+    #[serde(default)]
+    pub transform_modules: Vec<TransformModuleConfig>,
 }
 
 /// General gateway configuration
@@ -46,6 +55,10 @@ pub struct GeneralConfig {
     /// Input queue size
     #[serde(default = "default_queue_size")]
     pub queue_size: usize,
+    
+    /// Interval at which transform modules emit heartbeats and flush timed-out state, in milliseconds
+    #[serde(default = "default_transform_tick_ms")]
+    pub transform_tick_ms: u64,
 }
 
 fn default_log_level() -> String {
@@ -56,24 +69,368 @@ fn default_queue_size() -> usize {
     1000
 }
 
+fn default_transform_tick_ms() -> u64 {
+    100
+}
+
 /// Security configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
     /// Path to key storage file (if persistent)
     pub key_storage_path: Option<String>,
     
+    /// Source of the secret sealing the key storage file, required with a
+    /// path unless the backend is `PlainFile`
+    #[serde(default)]
+    pub key_store_key: Option<KeyStoreKeySource>,
+    
+    /// Number of previous key store generations kept as backups
+    #[serde(default = "default_key_store_backups")]
+    pub key_store_backups: usize,
+    
+    /// Backend holding long-term keys
+    #[serde(default)]
+    pub key_store_backend: KeyStoreBackend,
+    
     /// Default encryption key ID
     pub default_encryption_key: String,
     
     /// Default signing key ID (for signing)
     pub default_signing_key: String,
     
+    /// Key selection for outbound messages; the first matching entry applies
+    #[serde(default)]
+    pub key_policies: Vec<KeyPolicyConfig>,
+    
     /// Default security mode for outgoing messages
     #[serde(default)]
     pub default_security_mode: SecurityMode,
     
+    /// Cipher suite for outgoing messages unless a key policy selects another
+    #[serde(default)]
+    pub cipher_suite: CipherSuite,
+    
+    /// Cipher suites accepted for outgoing and incoming messages
+    #[serde(default = "default_enabled_cipher_suites")]
+    pub enabled_cipher_suites: Vec<CipherSuite>,
+    
     /// Key rotation interval in days (None = manual rotation)
     pub key_rotation_days: Option<u64>,
+    
+    /// How long keys replaced by a rotation stay valid for inbound traffic, in seconds
+    #[serde(default = "default_key_rotation_grace")]
+    pub key_rotation_grace_secs: u64,
+    
+    /// Post-quantum protection of peer handshakes; `Required` refuses classical-only peers
+    #[serde(default)]
+    pub handshake_post_quantum: PostQuantumPolicy,
+    
+    /// Forward-secret ratchet of handshake session keys; unset uses them as agreed
+    #[serde(default)]
+    pub session_ratchet: Option<SessionRatchetConfig>,
+    
+    /// Limits on how much data an encryption key may protect
+    #[serde(default)]
+    pub key_usage_limits: KeyUsageLimits,
+    
+    /// File reserving outbound sequence numbers across restarts
+    ///
+    /// Without it, sequence numbers are seeded from the clock and may repeat,
+    /// along with compact envelope nonces, if the clock is set back.
+    #[serde(default)]
+    pub sequence_state_path: Option<String>,
+    
+    /// Number of sequence numbers tracked by the anti-replay window
+    #[serde(default = "default_replay_window")]
+    pub replay_window: u64,
+    
+    /// Maximum age of an inbound secured message in seconds
+    #[serde(default = "default_max_message_age")]
+    pub max_message_age_secs: u64,
+    
+    /// Maximum amount an inbound timestamp may be ahead of the local clock in seconds
+    #[serde(default = "default_max_clock_skew")]
+    pub max_clock_skew_secs: u64,
+    
+    /// Minimum security mode of inbound messages, by ingress interface
+    #[serde(default)]
+    pub ingress_min_security_modes: HashMap<String, SecurityMode>,
+    
+    /// Minimum security mode of inbound messages on interfaces not listed above
+    #[serde(default = "default_ingress_min_security_mode")]
+    pub default_ingress_min_security_mode: SecurityMode,
+    
+    /// Length of MAC tags on authenticated-only messages, in bytes
+    #[serde(default = "default_mac_tag_len")]
+    pub mac_tag_len: usize,
+    
+    /// Links using the compact envelope instead of the full one
+    #[serde(default)]
+    pub compact_links: Vec<CompactLinkConfig>,
+    
+    /// Root authority for peer verification keys; unset trusts any imported key
+    #[serde(default)]
+    pub trust_store: Option<TrustStoreConfig>,
+    
+    /// Commands each authenticated key may send; unset disables authorization
+    #[serde(default)]
+    pub authorization: Option<AuthorizationConfig>,
+}
+
+/// Inbound command authorization; anything not granted is denied
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthorizationConfig {
+    #[serde(default)]
+    pub grants: Vec<AuthorizationGrant>,
+}
+
+/// Actions permitted to messages authenticated by a key ring
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationGrant {
+    /// Key ring that authenticated the message; the signing ring for signed modes
+    pub key: String,
+    
+    /// Sender named in the message header (None = any sender using the key)
+    #[serde(default)]
+    pub sender: Option<String>,
+    
+    pub permissions: Vec<Permission>,
+}
+
+/// Command permitted by a grant; empty lists permit any value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    /// Writes to a remote terminal's subaddresses
+    RtWrite {
+        rt: u8,
+        #[serde(default)]
+        subaddresses: Vec<u8>,
+    },
+    
+    /// Mode code commands to a remote terminal
+    ModeCode {
+        rt: u8,
+        #[serde(default)]
+        codes: Vec<u8>,
+    },
+    
+    /// EtherNet/IP encapsulation commands
+    CipCommand {
+        #[serde(default)]
+        commands: Vec<u8>,
+    },
+}
+
+/// Root authority and the endorsements and revocations it has issued
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustStoreConfig {
+    /// Root authority Ed25519 verification key as hex
+    pub root_key: String,
+    
+    /// JSON file mapping verification key IDs to endorsements
+    #[serde(default)]
+    pub endorsements_path: Option<String>,
+    
+    /// JSON file holding the current revocation list
+    #[serde(default)]
+    pub revocation_list_path: Option<String>,
+}
+
+/// Compact envelope settings for one interface
+///
+/// The envelope is not negotiated in-band: both ends must be configured with
+/// matching settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactLinkConfig {
+    /// Interface the link is attached to; inbound messages on it may use
+    /// the compact envelope, and rules send on it by naming it as their
+    /// `egress_link`
+    pub interface: String,
+    
+    /// Identity of the gateway at the other end
+    pub peer: String,
+    
+    /// Key rings usable on the link, addressed by their position
+    pub keys: Vec<String>,
+    
+    /// Length of the truncated authentication tag in bytes
+    #[serde(default = "default_compact_tag_len")]
+    pub tag_len: usize,
+}
+
+/// Where the key store secret is read from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeyStoreKeySource {
+    /// Passphrase in an environment variable, stretched with Argon2id
+    PassphraseEnv { var: String },
+    
+    /// Passphrase in a file, stretched with Argon2id
+    PassphraseFile { path: String },
+    
+    /// 32-byte key as hex in an environment variable
+    KeyEnv { var: String },
+    
+    /// 32-byte key in a file, raw or as hex
+    KeyFile { path: String },
+}
+
+/// Usage limits of an encryption key
+///
+/// Encryption nonces are random, so the chance of a nonce collision grows
+/// with every message under the same key. A key that reaches a limit is
+/// refused for encryption; rotation is triggered before that point. Usage
+/// is kept with the key in the key store, so it carries over restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyUsageLimits {
+    /// Maximum number of messages encrypted under a key (None = unlimited)
+    #[serde(default = "default_max_encryptions")]
+    pub max_encryptions: Option<u64>,
+    
+    /// Maximum number of plaintext bytes encrypted under a key (None = unlimited)
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    
+    /// Percentage of a limit at which the primary key is rotated (None = refuse only)
+    #[serde(default = "default_rotate_at_percent")]
+    pub rotate_at_percent: Option<u8>,
+}
+
+/// When handshake session keys are ratcheted forward
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRatchetConfig {
+    /// Messages sent under one epoch key
+    #[serde(default = "default_ratchet_messages_per_key")]
+    pub messages_per_key: u64,
+    
+    /// Longest time an epoch key is used for sending, in seconds
+    #[serde(default = "default_ratchet_max_key_age")]
+    pub max_key_age_secs: u64,
+    
+    /// Epochs a receiver may skip ahead, and earlier epoch keys it keeps for late messages
+    #[serde(default = "default_ratchet_max_skipped_keys")]
+    pub max_skipped_keys: u32,
+}
+
+fn default_ratchet_messages_per_key() -> u64 {
+    1000
+}
+
+fn default_ratchet_max_key_age() -> u64 {
+    10 * 60
+}
+
+fn default_ratchet_max_skipped_keys() -> u32 {
+    32
+}
+
+impl Default for SessionRatchetConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_key: default_ratchet_messages_per_key(),
+            max_key_age_secs: default_ratchet_max_key_age(),
+            max_skipped_keys: default_ratchet_max_skipped_keys(),
+        }
+    }
+}
+
+impl Default for KeyUsageLimits {
+    fn default() -> Self {
+        Self {
+            max_encryptions: default_max_encryptions(),
+            max_bytes: None,
+            rotate_at_percent: default_rotate_at_percent(),
+        }
+    }
+}
+
+/// Where long-term keys are kept
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyStoreBackend {
+    /// In memory only; keys are lost on restart
+    Memory,
+    
+    /// Unencrypted file at `key_storage_path`, for testing only
+    PlainFile,
+    
+    /// File at `key_storage_path` sealed with `key_store_key`, or in memory without a path
+    #[default]
+    EncryptedFile,
+    
+    /// Software stand-in for a PKCS#11 token, logged in with a PIN from an environment variable
+    SoftwareToken { label: String, pin_env: String },
+}
+
+/// Keys used for outbound messages matching all of the given criteria
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyPolicyConfig {
+    /// Translation rule name
+    #[serde(default)]
+    pub rule: Option<String>,
+    
+    /// Destination address of the translated message
+    #[serde(default)]
+    pub destination: Option<String>,
+    
+    /// Peer gateway, as given by the message's `peer` header
+    #[serde(default)]
+    pub peer: Option<String>,
+    
+    /// Encryption key ring (None = default encryption key)
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    
+    /// Signing key ring (None = default signing key)
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    
+    /// Cipher suite (None = default cipher suite)
+    #[serde(default)]
+    pub cipher_suite: Option<CipherSuite>,
+}
+
+fn default_key_store_backups() -> usize {
+    DEFAULT_BACKUP_GENERATIONS
+}
+
+fn default_key_rotation_grace() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_enabled_cipher_suites() -> Vec<CipherSuite> {
+    CipherSuite::AVAILABLE.to_vec()
+}
+
+fn default_max_encryptions() -> Option<u64> {
+    // NIST SP 800-38D bound for random 96-bit nonces
+    Some(1 << 32)
+}
+
+fn default_rotate_at_percent() -> Option<u8> {
+    Some(90)
+}
+
+fn default_replay_window() -> u64 {
+    64
+}
+
+fn default_max_message_age() -> u64 {
+    30
+}
+
+fn default_max_clock_skew() -> u64 {
+    5
+}
+
+fn default_mac_tag_len() -> usize {
+    DEFAULT_MAC_TAG_LEN
+}
+
+fn default_compact_tag_len() -> usize {
+    DEFAULT_COMPACT_TAG_LEN
+}
+
+fn default_ingress_min_security_mode() -> SecurityMode {
+    SecurityMode::None
 }
 
 /// Protocol-specific configurations
@@ -143,6 +500,10 @@ pub struct TranslationRule {
     pub priority: u8,
     
     /// Filter criteria for matching source messages
+    ///
+    /// `header.<name>` criteria see the headers a message carries when it is
+    /// routed, including `ingress-interface`; headers written by transform
+    /// modules are only added after routing.
     pub filter: HashMap<String, String>,
     
     /// Transformation to apply during translation
@@ -151,6 +512,11 @@ pub struct TranslationRule {
     /// Security mode to apply to the translated message
     #[serde(default)]
     pub security_mode: SecurityMode,
+    
+    /// Interface of the compact link translated messages are sent on
+    /// (None = full envelope)
+    #[serde(default)]
+    pub egress_link: Option<String>,
 }
 
 fn default_priority() -> u8 {
@@ -170,39 +536,168 @@ pub enum TransformType {
     Identity,
 }
 
-impl Config {
-    /// Load configuration from a file
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let config_builder = ::config::Config::builder()
-            .add_source(::config::File::from(path.as_ref()))
-            .add_source(::config::Environment::with_prefix("GATEWAY").separator("__"));
-            
-        let settings = config_builder.build()
-            .context("Failed to build configuration")?;
-            
-        let config: Config = settings.try_deserialize()
-            .context("Failed to deserialize configuration")?;
-            
-        config.validate()?;
-        info!("Configuration loaded and validated successfully");
-        Ok(config)
-    }
+/// A named transform module built from configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformModuleConfig {
+    /// Module name, referenced by `TransformType::Custom`
+    pub name: String,
+    
+    /// Kind of module and its settings
+    pub kind: TransformModuleKind,
+}
+
+/// Kinds of configurable transform modules
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransformModuleKind {
+    /// Forward only when the payload changes
+    ReportByException {
+        /// Maximum interval between forwarded messages, in milliseconds
+        heartbeat_ms: Option<u64>,
+    },
+    
+    /// Forward only when a numeric payload field moves past a deadband
+    Deadband {
+        /// Byte offset of the field within the payload
+        offset: usize,
+        
+        /// Encoding of the field
+        field: NumericField,
+        
+        /// Minimum change from the last forwarded value
+        deadband: f64,
+        
+        /// Maximum interval between forwarded messages, in milliseconds
+        heartbeat_ms: Option<u64>,
+    },
+    
+    /// Combine the latest payloads of several sources into one assembly
+    Aggregate {
+        /// Sources making up the assembly, in layout order
+        members: Vec<AggregateMember>,
+        
+        /// When to emit the assembly
+        policy: AggregationPolicy,
+        
+        /// Destination address of the emitted assembly
+        destination_address: String,
+    },
+    
+    /// Split a large payload into several MIL-STD-1553 BC-to-RT messages
+    Split {
+        /// Remote terminal receiving the messages (e.g. "RT5")
+        destination_address: String,
+        
+        /// Subaddress for each successive chunk
+        subaddresses: Vec<u8>,
+        
+        /// Maximum data words per message (1-32)
+        #[serde(default = "default_max_words")]
+        max_words: usize,
+    },
     
+    /// Write headers into message metadata
+    HeaderEnrichment {
+        /// Fixed headers to set
+        #[serde(default)]
+        fields: HashMap<String, String>,
+        
+        /// Record this gateway's name
+        #[serde(default)]
+        gateway_name: bool,
+        
+        /// Assign a trace ID to messages that lack one
+        #[serde(default)]
+        trace_id: bool,
+    },
+}
+
+fn default_max_words() -> usize {
+    32
+}
+
+/// A source contributing to an aggregated assembly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregateMember {
+    /// Source address to match (e.g. "RT3")
+    pub source_address: String,
+    
+    /// Subaddress to match, or any if not set
+    pub subaddress: Option<u8>,
+    
+    /// Size of this member's slot in the assembly, in 16-bit words
+    pub words: usize,
+}
+
+/// Completeness policy for aggregated assemblies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AggregationPolicy {
+    /// Emit once every member has updated since the last emission
+    Complete,
+    
+    /// Emit once every member has updated, or when the timeout elapses after
+    /// the first update of a cycle, using the latest known values
+    Timeout {
+        timeout_ms: u64,
+    },
+}
+
+/// Big-endian numeric field encodings understood by the deadband transform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NumericField {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl NumericField {
+    /// Size of the field in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            NumericField::U16 | NumericField::I16 => 2,
+            NumericField::U32 | NumericField::I32 | NumericField::F32 => 4,
+        }
+    }
+}
+
+impl Default for Config {
     /// Create a default configuration
-    pub fn default() -> Self {
+    fn default() -> Self {
         Self {
             general: GeneralConfig {
                 name: "secure-gateway".to_string(),
                 log_level: default_log_level(),
                 workers: 0,  // Auto-detect
                 queue_size: default_queue_size(),
+                transform_tick_ms: default_transform_tick_ms(),
             },
             security: SecurityConfig {
-                key_storage_path: Some("keys.bin".to_string()),
+                key_storage_path: None,
+                key_store_key: None,
+                key_store_backups: default_key_store_backups(),
+                key_store_backend: KeyStoreBackend::default(),
                 default_encryption_key: "default-encryption".to_string(),
                 default_signing_key: "default-signing".to_string(),
+                key_policies: Vec::new(),
                 default_security_mode: SecurityMode::EncryptedAndSigned,
+                cipher_suite: CipherSuite::default(),
+                enabled_cipher_suites: default_enabled_cipher_suites(),
                 key_rotation_days: Some(30),
+                key_rotation_grace_secs: default_key_rotation_grace(),
+                handshake_post_quantum: PostQuantumPolicy::default(),
+                session_ratchet: None,
+                key_usage_limits: KeyUsageLimits::default(),
+                sequence_state_path: None,
+                replay_window: default_replay_window(),
+                max_message_age_secs: default_max_message_age(),
+                max_clock_skew_secs: default_max_clock_skew(),
+                ingress_min_security_modes: HashMap::new(),
+                default_ingress_min_security_mode: default_ingress_min_security_mode(),
+                mac_tag_len: default_mac_tag_len(),
+                compact_links: Vec::new(),
+                trust_store: None,
+                authorization: None,
             },
             protocols: ProtocolsConfig {
                 mil_std_1553: MilStd1553Config {
@@ -226,6 +721,7 @@ impl Config {
                     filter: HashMap::new(), 
                     transform: Some(TransformType::Identity),
                     security_mode: SecurityMode::EncryptedAndSigned,
+                    egress_link: None,
                 },
                 TranslationRule {
                     name: "ethernet-to-mil".to_string(),
@@ -235,10 +731,31 @@ impl Config {
                     filter: HashMap::new(),
                     transform: Some(TransformType::Identity),
                     security_mode: SecurityMode::EncryptedAndSigned,
+                    egress_link: None,
                 },
             ],
+            transform_modules: Vec::new(),
         }
     }
+}
+
+impl Config {
+    /// Load configuration from a file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config_builder = ::config::Config::builder()
+            .add_source(::config::File::from(path.as_ref()))
+            .add_source(::config::Environment::with_prefix("GATEWAY").separator("__"));
+            
+        let settings = config_builder.build()
+            .context("Failed to build configuration")?;
+            
+        let config: Config = settings.try_deserialize()
+            .context("Failed to deserialize configuration")?;
+            
+        config.validate()?;
+        info!("Configuration loaded and validated successfully");
+        Ok(config)
+    }
     
     /// Load configuration from the default location
     pub fn load() -> Result<Self> {
@@ -267,6 +784,10 @@ impl Config {
     
     /// Validate the configuration
     pub fn validate(&self) -> Result<()> {
+        if self.general.transform_tick_ms == 0 {
+            return Err(anyhow!("Transform tick interval must be at least one millisecond"));
+        }
+        
         // Check that the default keys are defined
         if self.security.default_encryption_key.is_empty() {
             return Err(anyhow!("Default encryption key ID must be specified"));
@@ -276,6 +797,54 @@ impl Config {
             return Err(anyhow!("Default signing key ID must be specified"));
         }
         
+        match self.security.key_store_backend {
+            KeyStoreBackend::EncryptedFile if self.security.key_storage_path.is_some()
+                && self.security.key_store_key.is_none() => {
+                return Err(anyhow!("A key store key source is required when key storage is persistent; \
+                    set key_store_key, or key_store_backend: PlainFile to keep an unencrypted store"));
+            },
+            KeyStoreBackend::PlainFile if self.security.key_storage_path.is_none() => {
+                return Err(anyhow!("The plain file key store requires a key storage path"));
+            },
+            _ => {},
+        }
+        
+        if self.security.key_rotation_days == Some(0) {
+            return Err(anyhow!("Key rotation interval must be at least one day"));
+        }
+        
+        for suite in &self.security.enabled_cipher_suites {
+            if !suite.is_available() {
+                return Err(anyhow!("Cipher suite {:?} is not available in this build", suite));
+            }
+        }
+        
+        let selected_suites = self.security.key_policies.iter()
+            .filter_map(|policy| policy.cipher_suite)
+            .chain(std::iter::once(self.security.cipher_suite));
+        for suite in selected_suites {
+            if !self.security.enabled_cipher_suites.contains(&suite) {
+                return Err(anyhow!("Cipher suite {:?} is selected but not enabled", suite));
+            }
+        }
+        
+        if self.security.session_ratchet.as_ref().is_some_and(|ratchet| ratchet.messages_per_key == 0) {
+            return Err(anyhow!("Session ratchet must allow at least 1 message per key"));
+        }
+        
+        let limits = &self.security.key_usage_limits;
+        if limits.max_encryptions == Some(0) || limits.max_bytes == Some(0) {
+            return Err(anyhow!("Key usage limits must be at least 1"));
+        }
+        
+        if limits.rotate_at_percent.is_some_and(|percent| percent == 0 || percent > 100) {
+            return Err(anyhow!("Key usage rotation threshold must be between 1 and 100 percent"));
+        }
+        
+        if self.security.replay_window == 0 || self.security.replay_window > MAX_REPLAY_WINDOW {
+            return Err(anyhow!("Replay window must be between 1 and {}", MAX_REPLAY_WINDOW));
+        }
+        
         // Validate MIL-STD-1553 configuration
         for rt in &self.protocols.mil_std_1553.remote_terminals {
             if *rt > 31 {
@@ -300,6 +869,122 @@ impl Config {
             }
         }
         
+        // Validate key policies; key availability is checked against the key store at startup
+        for (idx, policy) in self.security.key_policies.iter().enumerate() {
+            if policy.rule.is_none() && policy.destination.is_none() && policy.peer.is_none() {
+                return Err(anyhow!("Key policy {} must match on a rule, destination or peer", idx));
+            }
+            
+            if policy.encryption_key.is_none() && policy.signing_key.is_none() {
+                return Err(anyhow!("Key policy {} must select an encryption or signing key", idx));
+            }
+            
+            if [&policy.encryption_key, &policy.signing_key].into_iter().flatten().any(String::is_empty) {
+                return Err(anyhow!("Key policy {} has an empty key name", idx));
+            }
+            
+            if let Some(rule) = &policy.rule {
+                if !self.translation_rules.iter().any(|r| &r.name == rule) {
+                    return Err(anyhow!("Key policy {} references unknown translation rule '{}'", idx, rule));
+                }
+            }
+        }
+        
+        if !(MIN_MAC_TAG_LEN..=MAC_SIZE).contains(&self.security.mac_tag_len) {
+            return Err(anyhow!("MAC tag length must be between {} and {} bytes", MIN_MAC_TAG_LEN, MAC_SIZE));
+        }
+        
+        // Validate compact links
+        for (idx, link) in self.security.compact_links.iter().enumerate() {
+            if self.security.compact_links[..idx].iter().any(|other| other.interface == link.interface) {
+                return Err(anyhow!("Interface '{}' has more than one compact link", link.interface));
+            }
+            
+            if link.keys.iter().any(String::is_empty) {
+                return Err(anyhow!("Compact link on '{}' has an empty key name", link.interface));
+            }
+            
+            CompactLink::new(&link.peer, link.keys.clone(), link.tag_len)
+                .with_context(|| format!("Invalid compact link on '{}'", link.interface))?;
+        }
+        
+        for rule in &self.translation_rules {
+            let Some(interface) = &rule.egress_link else {
+                continue;
+            };
+            
+            if !self.security.compact_links.iter().any(|link| link.interface == *interface) {
+                return Err(anyhow!("Translation rule '{}' sends on '{}', which has no compact link",
+                    rule.name, interface));
+            }
+            
+            if rule.security_mode.is_signed() {
+                return Err(anyhow!("Translation rule '{}' signs messages, which compact link on '{}' cannot carry",
+                    rule.name, interface));
+            }
+        }
+        
+        // Validate authorization grants
+        if let Some(authorization) = &self.security.authorization {
+            for (idx, grant) in authorization.grants.iter().enumerate() {
+                if grant.key.is_empty() {
+                    return Err(anyhow!("Authorization grant {} has an empty key name", idx));
+                }
+                
+                for permission in &grant.permissions {
+                    let valid = match permission {
+                        Permission::RtWrite { rt, subaddresses } =>
+                            *rt <= 31 && subaddresses.iter().all(|sa| (1..=30).contains(sa)),
+                        Permission::ModeCode { rt, codes } =>
+                            *rt <= 31 && codes.iter().all(|code| *code <= 31),
+                        Permission::CipCommand { .. } => true,
+                    };
+                    
+                    if !valid {
+                        return Err(anyhow!("Authorization grant {} has an invalid permission: {:?}", idx, permission));
+                    }
+                }
+            }
+        }
+        
+        // Validate transform modules
+        for module in &self.transform_modules {
+            if module.name.is_empty() {
+                return Err(anyhow!("Transform module name must not be empty"));
+            }
+            
+            match &module.kind {
+                TransformModuleKind::Deadband { deadband, .. } => {
+                    if !deadband.is_finite() || *deadband < 0.0 {
+                        return Err(anyhow!("Transform module '{}' has invalid deadband: {}", 
+                            module.name, deadband));
+                    }
+                },
+                TransformModuleKind::Aggregate { members, .. } => {
+                    if members.is_empty() {
+                        return Err(anyhow!("Transform module '{}' has no aggregate members", module.name));
+                    }
+                    
+                    if members.iter().any(|m| m.words == 0) {
+                        return Err(anyhow!("Transform module '{}' has an empty aggregate member", module.name));
+                    }
+                },
+                TransformModuleKind::Split { subaddresses, max_words, .. } => {
+                    if subaddresses.is_empty() || subaddresses.iter().any(|sa| *sa == 0 || *sa > 30) {
+                        return Err(anyhow!("Transform module '{}' has invalid subaddresses (must be 1-30)", 
+                            module.name));
+                    }
+                    
+                    if !(1..=32).contains(max_words) {
+                        return Err(anyhow!("Transform module '{}' has invalid max_words: {} (must be 1-32)", 
+                            module.name, max_words));
+                    }
+                },
+                TransformModuleKind::ReportByException { .. } |
+                TransformModuleKind::HeaderEnrichment { .. } => {},
+            }
+        }
+        
         Ok(())
     }
     
@@ -331,6 +1016,25 @@ impl Config {
         Duration::from_secs(self.protocols.ethernet_ip.timeout_secs)
     }
     
+    /// Get maximum age of inbound secured messages
+    pub fn get_max_message_age(&self) -> Duration {
+        Duration::from_secs(self.security.max_message_age_secs)
+    }
+    
+    /// Get maximum clock skew tolerated on inbound secured messages
+    pub fn get_max_clock_skew(&self) -> Duration {
+        Duration::from_secs(self.security.max_clock_skew_secs)
+    }
+    
+    /// Get the name of the interface messages of a protocol are received on
+    pub fn get_ingress_interface(&self, protocol: ProtocolType) -> String {
+        match protocol {
+            ProtocolType::MilStd1553 => self.protocols.mil_std_1553.interface.clone(),
+            ProtocolType::EthernetIp => format!("{}:{}", 
+                self.protocols.ethernet_ip.bind_address, self.protocols.ethernet_ip.port),
+        }
+    }
+    
     /// Get idle timeout for EtherNet/IP sessions
     pub fn get_ethernet_ip_idle_timeout(&self) -> Duration {
         Duration::from_secs(self.protocols.ethernet_ip.idle_timeout_secs)
@@ -0,0 +1,388 @@
+//! Aggregation and splitting transform modules
+//!
+//! These modules translate between many small MIL-STD-1553 messages and
+//! larger EtherNet/IP assemblies: aggregation combines the latest values of
+//! several sources into one assembly, and splitting breaks a large write into
+//! messages that fit within the 1553 word limit.
+
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::config::{AggregateMember, AggregationPolicy};
+use crate::gateway::ingress::PeerIdentity;
+use crate::gateway::transformer::{TransformModule, TransformOutput};
+use crate::protocols::mil_std_1553::MAX_DATA_WORDS;
+use crate::protocols::{CommonMessage, MessageMetadata, ProtocolType};
+use crate::utils::generate_unique_id;
+
+/// Collection state for one assembly cycle
+struct AggregateState {
+    /// Latest payload seen for each member
+    latest: Vec<Option<Vec<u8>>>,
+    
+    /// Sender of each member's latest payload
+    senders: Vec<Option<PeerIdentity>>,
+    
+    /// Members updated since the last emission
+    fresh: Vec<bool>,
+    
+    /// Timestamp of the first update in the current cycle
+    cycle_started: Option<u64>,
+    
+    /// Protocols and priority of the latest member message, used for the assembly
+    route: Option<(ProtocolType, Option<ProtocolType>, u8)>,
+}
+
+/// Combines the latest payloads of several sources into a single assembly.
+///
+/// Each member occupies a fixed-size slot in the assembly, in configuration
+/// order; payloads are truncated or zero-padded to fit. Messages that do not
+/// belong to any member are passed through unchanged. Timeouts are evaluated
+/// on message timestamps as messages arrive, and on `tick` so that a cycle
+/// whose remaining members never report is still emitted. An assembly is
+/// attributed to the senders of every member value it contains, including
+/// values carried over from earlier cycles.
+pub struct AggregateTransform {
+    name: String,
+    members: Vec<AggregateMember>,
+    policy: AggregationPolicy,
+    destination_address: String,
+    state: Mutex<AggregateState>,
+}
+
+impl AggregateTransform {
+    pub fn new(name: &str, members: Vec<AggregateMember>, policy: AggregationPolicy,
+              destination_address: &str) -> Self {
+        let state = AggregateState {
+            latest: vec![None; members.len()],
+            senders: vec![None; members.len()],
+            fresh: vec![false; members.len()],
+            cycle_started: None,
+            route: None,
+        };
+        
+        Self {
+            name: name.to_string(),
+            members,
+            policy,
+            destination_address: destination_address.to_string(),
+            state: Mutex::new(state),
+        }
+    }
+    
+    /// Find the member slot a message belongs to
+    fn member_index(&self, message: &CommonMessage) -> Option<usize> {
+        self.members.iter().position(|member| {
+            member.source_address == message.metadata.source_address
+                && (member.subaddress.is_none() || member.subaddress == message.metadata.subaddress)
+        })
+    }
+    
+    /// Build the assembly payload from the latest member values
+    fn assemble(&self, latest: &[Option<Vec<u8>>]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        
+        for (member, value) in self.members.iter().zip(latest) {
+            let mut slot = vec![0u8; member.words * 2];
+            
+            if let Some(value) = value {
+                let len = value.len().min(slot.len());
+                slot[..len].copy_from_slice(&value[..len]);
+            }
+            
+            payload.extend_from_slice(&slot);
+        }
+        
+        payload
+    }
+    
+    /// Emit the assembly for the current cycle and start a new one
+    fn emit(&self, state: &mut AggregateState, now: u64) -> Vec<TransformOutput> {
+        let Some((source_protocol, target_protocol, priority)) = state.route else {
+            return Vec::new();
+        };
+        
+        if !state.fresh.iter().all(|fresh| *fresh) {
+            debug!("{}: emitting incomplete assembly after timeout", self.name);
+        }
+        
+        let payload = self.assemble(&state.latest);
+        state.fresh.iter_mut().for_each(|fresh| *fresh = false);
+        state.cycle_started = None;
+        
+        let assembly = CommonMessage {
+            source_protocol,
+            target_protocol,
+            priority,
+            payload,
+            metadata: MessageMetadata {
+                source_address: self.name.clone(),
+                destination_address: self.destination_address.clone(),
+                timestamp: now,
+                message_id: generate_unique_id(),
+                is_command: false,
+                requires_response: false,
+                subaddress: None,
+                headers: BTreeMap::new(),
+            },
+        };
+        
+        let senders = state.latest.iter().zip(&state.senders)
+            .filter(|(value, _)| value.is_some())
+            .map(|(_, sender)| sender.as_ref());
+        
+        vec![TransformOutput::combined(assembly, senders)]
+    }
+    
+    /// Check whether the current cycle has run past the timeout at `now`
+    fn timed_out(&self, cycle_started: u64, now: u64) -> bool {
+        match self.policy {
+            AggregationPolicy::Complete => false,
+            AggregationPolicy::Timeout { timeout_ms } => now.saturating_sub(cycle_started) >= timeout_ms,
+        }
+    }
+}
+
+impl TransformModule for AggregateTransform {
+    fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>) -> Result<Vec<TransformOutput>> {
+        let Some(idx) = self.member_index(message) else {
+            debug!("{}: message from {} is not an aggregate member, passing through",
+                self.name, message.metadata.source_address);
+            return Ok(vec![TransformOutput::new(message.clone(), sender)]);
+        };
+        
+        let now = message.metadata.timestamp;
+        
+        let mut state = self.state.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on aggregate state"))?;
+        
+        state.latest[idx] = Some(message.payload.clone());
+        state.senders[idx] = sender.cloned();
+        state.fresh[idx] = true;
+        state.route = Some((message.source_protocol, message.target_protocol, message.priority));
+        let cycle_started = *state.cycle_started.get_or_insert(now);
+        
+        let complete = state.fresh.iter().all(|fresh| *fresh);
+        if !complete && !self.timed_out(cycle_started, now) {
+            return Ok(Vec::new());
+        }
+        
+        Ok(self.emit(&mut state, now))
+    }
+    
+    fn tick(&self, now: u64) -> Result<Vec<TransformOutput>> {
+        let mut state = self.state.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on aggregate state"))?;
+        
+        match state.cycle_started {
+            Some(cycle_started) if self.timed_out(cycle_started, now) => Ok(self.emit(&mut state, now)),
+            _ => Ok(Vec::new()),
+        }
+    }
+    
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Splits a large payload into several MIL-STD-1553 BC-to-RT messages.
+///
+/// Chunk `n` is sent to the `n`th configured subaddress; a payload that needs
+/// more messages than there are subaddresses is rejected.
+pub struct SplitTransform {
+    name: String,
+    destination_address: String,
+    subaddresses: Vec<u8>,
+    max_words: usize,
+}
+
+impl SplitTransform {
+    pub fn new(name: &str, destination_address: &str, subaddresses: Vec<u8>, max_words: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            destination_address: destination_address.to_string(),
+            subaddresses,
+            max_words: max_words.clamp(1, MAX_DATA_WORDS),
+        }
+    }
+}
+
+impl TransformModule for SplitTransform {
+    fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>) -> Result<Vec<TransformOutput>> {
+        if message.payload.is_empty() {
+            bail!("{}: cannot split an empty payload", self.name);
+        }
+        
+        let chunks: Vec<&[u8]> = message.payload.chunks(self.max_words * 2).collect();
+        
+        if chunks.len() > self.subaddresses.len() {
+            bail!("{}: payload of {} bytes needs {} messages but only {} subaddresses are configured",
+                self.name, message.payload.len(), chunks.len(), self.subaddresses.len());
+        }
+        
+        let messages = chunks.into_iter()
+            .zip(&self.subaddresses)
+            .map(|(chunk, subaddress)| {
+                let mut part = message.clone();
+                part.payload = chunk.to_vec();
+                part.metadata.source_address = "BC".to_string();
+                part.metadata.destination_address = self.destination_address.clone();
+                part.metadata.subaddress = Some(*subaddress);
+                part.metadata.is_command = true;
+                part.metadata.requires_response = true;
+                TransformOutput::new(part, sender)
+            })
+            .collect::<Vec<_>>();
+        
+        debug!("{}: split {} bytes into {} messages", self.name, message.payload.len(), messages.len());
+        Ok(messages)
+    }
+    
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::create_mil_std_1553_handler;
+    
+    fn create_test_message(source: &str, subaddress: u8, payload: Vec<u8>, timestamp: u64) -> CommonMessage {
+        CommonMessage {
+            source_protocol: ProtocolType::MilStd1553,
+            target_protocol: Some(ProtocolType::EthernetIp),
+            priority: 2,
+            payload,
+            metadata: MessageMetadata {
+                source_address: source.to_string(),
+                destination_address: "BC".to_string(),
+                timestamp,
+                message_id: timestamp,
+                is_command: false,
+                requires_response: false,
+                subaddress: Some(subaddress),
+                headers: BTreeMap::new(),
+            },
+        }
+    }
+    
+    fn create_members() -> Vec<AggregateMember> {
+        vec![
+            AggregateMember { source_address: "RT3".to_string(), subaddress: Some(1), words: 1 },
+            AggregateMember { source_address: "RT3".to_string(), subaddress: Some(2), words: 2 },
+            AggregateMember { source_address: "RT7".to_string(), subaddress: Some(4), words: 1 },
+        ]
+    }
+    
+    #[test]
+    fn test_aggregate_complete() {
+        let aggregate = AggregateTransform::new("agg", create_members(), AggregationPolicy::Complete, "assembly-100");
+        
+        assert!(aggregate.transform(&create_test_message("RT3", 1, vec![0x11, 0x11], 0), None).unwrap().is_empty());
+        assert!(aggregate.transform(&create_test_message("RT3", 2, vec![0x22], 10), None).unwrap().is_empty());
+        
+        // A repeat from an already-fresh member just replaces its value
+        assert!(aggregate.transform(&create_test_message("RT3", 1, vec![0x12, 0x12], 20), None).unwrap().is_empty());
+        
+        let out = aggregate.transform(&create_test_message("RT7", 4, vec![0x44, 0x44, 0x99], 30), None).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].message.metadata.destination_address, "assembly-100");
+        
+        // Slots are padded and truncated to their configured size
+        assert_eq!(out[0].message.payload, vec![0x12, 0x12, 0x22, 0x00, 0x00, 0x00, 0x44, 0x44]);
+        
+        // Non-members pass through unchanged
+        let other = create_test_message("RT9", 1, vec![1, 2], 40);
+        assert_eq!(aggregate.transform(&other, None).unwrap()[0].message.payload, other.payload);
+    }
+    
+    #[test]
+    fn test_aggregate_timeout() {
+        let policy = AggregationPolicy::Timeout { timeout_ms: 100 };
+        let aggregate = AggregateTransform::new("agg", create_members(), policy, "assembly-100");
+        
+        assert!(aggregate.transform(&create_test_message("RT3", 1, vec![0x11, 0x11], 0), None).unwrap().is_empty());
+        assert!(aggregate.transform(&create_test_message("RT3", 2, vec![0x22, 0x22], 50), None).unwrap().is_empty());
+        
+        // RT7 never reports, so the assembly is emitted with a zero-filled slot
+        let out = aggregate.transform(&create_test_message("RT3", 2, vec![0x23, 0x23], 100), None).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].message.payload, vec![0x11, 0x11, 0x23, 0x23, 0x00, 0x00, 0x00, 0x00]);
+    }
+    
+    #[test]
+    fn test_aggregate_timeout_on_tick() {
+        let policy = AggregationPolicy::Timeout { timeout_ms: 100 };
+        let aggregate = AggregateTransform::new("agg", create_members(), policy, "assembly-100");
+        
+        // Nothing is pending before the first member reports
+        assert!(aggregate.tick(500).unwrap().is_empty());
+        
+        assert!(aggregate.transform(&create_test_message("RT3", 1, vec![0x11, 0x11], 1000), None).unwrap().is_empty());
+        assert!(aggregate.tick(1099).unwrap().is_empty());
+        
+        // No further message arrives, so the tick emits the partial assembly
+        let out = aggregate.tick(1100).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].message.target_protocol, Some(ProtocolType::EthernetIp));
+        assert_eq!(out[0].message.metadata.timestamp, 1100);
+        assert_eq!(out[0].message.payload, vec![0x11, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        
+        // The emitted cycle is closed
+        assert!(aggregate.tick(1300).unwrap().is_empty());
+        
+        // Complete-policy assemblies never flush on tick
+        let complete = AggregateTransform::new("agg", create_members(), AggregationPolicy::Complete, "assembly-100");
+        complete.transform(&create_test_message("RT3", 1, vec![0x11, 0x11], 0), None).unwrap();
+        assert!(complete.tick(u64::MAX).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_split() {
+        let split = SplitTransform::new("split", "RT5", vec![1, 2, 3], MAX_DATA_WORDS);
+        
+        let mut message = create_test_message("192.168.1.100", 0, (0..150).collect(), 0);
+        message.source_protocol = ProtocolType::EthernetIp;
+        message.target_protocol = Some(ProtocolType::MilStd1553);
+        
+        let parts = split.transform(&message, None).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts.iter().map(|p| p.message.payload.len()).collect::<Vec<_>>(), vec![64, 64, 22]);
+        assert_eq!(parts[2].message.metadata.subaddress, Some(3));
+        
+        // Each part formats to a BC-to-RT message within the word limit
+        let handler = create_mil_std_1553_handler();
+        let bytes = handler.format(&parts[0].message).unwrap();
+        let command = u16::from_be_bytes([bytes[0], bytes[1]]);
+        assert_eq!(command >> 11, 5);
+        assert_eq!((command >> 10) & 1, 0);
+        assert_eq!((command >> 5) & 0x1F, 1);
+        assert_eq!(command & 0x1F, 0); // 32 words encodes as 0
+        assert_eq!(bytes.len(), 2 + 64);
+        
+        // Payloads needing more subaddresses than configured are rejected
+        message.payload = vec![0; 200];
+        assert!(split.transform(&message, None).is_err());
+    }
+    
+    #[test]
+    fn test_aggregate_senders() {
+        let policy = AggregationPolicy::Timeout { timeout_ms: 100 };
+        let aggregate = AggregateTransform::new("agg", create_members(), policy, "assembly-100");
+        let ops = PeerIdentity { sender: "gw-b".to_string(), key: "ops".to_string() };
+        let other = PeerIdentity { sender: "gw-c".to_string(), key: "other".to_string() };
+        
+        aggregate.transform(&create_test_message("RT3", 1, vec![0x11, 0x11], 0), Some(&ops)).unwrap();
+        aggregate.transform(&create_test_message("RT3", 2, vec![0x22, 0x22], 10), Some(&ops)).unwrap();
+        aggregate.transform(&create_test_message("RT7", 4, vec![0x44, 0x44], 20), Some(&other)).unwrap();
+        
+        // The next cycle still carries values from the previous senders
+        aggregate.transform(&create_test_message("RT3", 1, vec![0x12, 0x12], 30), None).unwrap();
+        let out = aggregate.tick(130).unwrap();
+        assert_eq!(out[0].message.payload, vec![0x12, 0x12, 0x22, 0x22, 0x00, 0x00, 0x44, 0x44]);
+        assert_eq!(out[0].senders, vec![None, Some(ops), Some(other)]);
+    }
+}
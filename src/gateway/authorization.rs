@@ -0,0 +1,347 @@
+//! Authorization of inbound commands
+//!
+//! Authentication tells us which key protected a message, not whether its
+//! sender may issue the command it carries. Each message is mapped to the
+//! action of the frame it is emitted as on its target protocol (a remote
+//! terminal write, a mode code or an EtherNet/IP command) and checked
+//! against the grants of the key that authenticated it. Messages are checked
+//! once routed, before transform modules see them, and each transformed
+//! message again before it is sent, including those transform modules emit
+//! on tick. A transformed message must be granted to every sender whose
+//! messages it carries contents of. Anything not explicitly granted is
+//! denied, including unauthenticated commands and frames whose action cannot
+//! be determined. Denials are logged and recorded as audit events.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::config::{AuthorizationConfig, AuthorizationGrant, Permission};
+use crate::gateway::ingress::PeerIdentity;
+use crate::gateway::transformer::TransformOutput;
+use crate::protocols::ethernet_ip::{CommandType, EthernetIpHandler};
+use crate::protocols::mil_std_1553::Mil1553Handler;
+use crate::protocols::{CommonMessage, ProtocolHandler, ProtocolType};
+use crate::security::SecurityError;
+use crate::utils::current_time_millis;
+
+/// Number of denied messages kept in the authorization audit log
+pub const MAX_AUTHORIZATION_AUDIT_EVENTS: usize = 1024;
+
+/// Command an inbound message would perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    RtWrite { rt: u8, subaddress: u8 },
+    ModeCode { rt: u8, code: u8 },
+    CipCommand { command: u8 },
+}
+
+impl Action {
+    /// Action of the frame `message` is emitted as on `target`, or None if
+    /// that frame does not act on its destination
+    ///
+    /// The frame is produced by the target protocol's formatter, so the
+    /// classification cannot disagree with what is sent. Headers and flags
+    /// set by the sender are not consulted.
+    pub fn of(message: &CommonMessage, target: ProtocolType) -> Result<Option<Action>, String> {
+        let frame = match target {
+            ProtocolType::MilStd1553 => Mil1553Handler::new().format(message),
+            ProtocolType::EthernetIp => EthernetIpHandler::new().format(message),
+        }.map_err(|e| format!("cannot determine {} frame: {}", target, e))?;
+        
+        match target {
+            ProtocolType::MilStd1553 => {
+                let [high, low, ..] = frame[..] else {
+                    return Err("MIL-STD-1553 frame has no command word".to_string());
+                };
+                let command = u16::from_be_bytes([high, low]);
+                let rt = (command >> 11) as u8;
+                let transmit = (command >> 10) & 1 == 1;
+                let subaddress = ((command >> 5) & 0x1F) as u8;
+                
+                // Subaddresses 0 and 31 carry the mode code in the word count field
+                if subaddress == 0 || subaddress == 31 {
+                    return Ok(Some(Action::ModeCode { rt, code: (command & 0x1F) as u8 }));
+                }
+                
+                // A transmit command only reads data from the terminal
+                Ok((!transmit).then_some(Action::RtWrite { rt, subaddress }))
+            },
+            ProtocolType::EthernetIp => {
+                let command = *frame.first()
+                    .ok_or_else(|| "EtherNet/IP frame has no command".to_string())?;
+                
+                match CommandType::from_u8(command) {
+                    CommandType::DataResponse => Ok(None),
+                    _ => Ok(Some(Action::CipCommand { command })),
+                }
+            },
+        }
+    }
+}
+
+impl Permission {
+    /// Whether this permission covers an action
+    fn permits(&self, action: &Action) -> bool {
+        match (self, action) {
+            (Permission::RtWrite { rt, subaddresses }, Action::RtWrite { rt: target, subaddress }) =>
+                rt == target && (subaddresses.is_empty() || subaddresses.contains(subaddress)),
+            (Permission::ModeCode { rt, codes }, Action::ModeCode { rt: target, code }) =>
+                rt == target && (codes.is_empty() || codes.contains(code)),
+            (Permission::CipCommand { commands }, Action::CipCommand { command }) =>
+                commands.is_empty() || commands.contains(command),
+            _ => false,
+        }
+    }
+}
+
+/// Audit record of a denied inbound command
+#[derive(Debug, Clone)]
+pub struct AuthorizationAuditEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub identity: Option<PeerIdentity>,
+    pub source_address: String,
+    pub message_id: u64,
+    pub reason: String,
+}
+
+/// Default-deny policy mapping authenticated keys to permitted commands
+pub struct AuthorizationPolicy {
+    grants: Vec<AuthorizationGrant>,
+    
+    /// Most recent denied messages, oldest first
+    audit_log: Mutex<VecDeque<AuthorizationAuditEvent>>,
+}
+
+impl AuthorizationPolicy {
+    /// Create a policy from the configured grants
+    pub fn new(config: &AuthorizationConfig) -> Self {
+        Self {
+            grants: config.grants.clone(),
+            audit_log: Mutex::new(VecDeque::new()),
+        }
+    }
+    
+    /// Check that a message from `identity` may be sent on `target`,
+    /// recording an audit event if not
+    pub fn authorize(&self, identity: Option<&PeerIdentity>, message: &CommonMessage,
+                     target: ProtocolType) -> Result<()> {
+        let Err(reason) = self.decide(identity, message, target) else {
+            return Ok(());
+        };
+        
+        let event = AuthorizationAuditEvent {
+            timestamp: current_time_millis(),
+            identity: identity.cloned(),
+            source_address: message.metadata.source_address.clone(),
+            message_id: message.metadata.message_id,
+            reason,
+        };
+        
+        match &event.identity {
+            Some(identity) => warn!(target: "audit", "Denied inbound message {} from {} (key {}, sender {}): {}",
+                event.message_id, event.source_address, identity.key, identity.sender, event.reason),
+            None => warn!(target: "audit", "Denied inbound message {} from {} (unauthenticated): {}",
+                event.message_id, event.source_address, event.reason),
+        }
+        
+        let reason = event.reason.clone();
+        let mut audit_log = self.audit_log.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on authorization audit log"))?;
+        if audit_log.len() == MAX_AUTHORIZATION_AUDIT_EVENTS {
+            audit_log.pop_front();
+        }
+        audit_log.push_back(event);
+        
+        Err(anyhow!(SecurityError::AuthenticationFailed(format!("Not authorized: {}", reason))))
+    }
+    
+    /// Check that a transformed message may be sent on `target` for every
+    /// sender whose messages it carries, recording an audit event if not
+    pub fn authorize_output(&self, output: &TransformOutput, target: ProtocolType) -> Result<()> {
+        if output.senders.is_empty() {
+            return self.authorize(None, &output.message, target);
+        }
+        
+        for sender in &output.senders {
+            self.authorize(sender.as_ref(), &output.message, target)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Allow or deny a message, giving the reason for a denial
+    fn decide(&self, identity: Option<&PeerIdentity>, message: &CommonMessage,
+              target: ProtocolType) -> Result<(), String> {
+        let Some(action) = Action::of(message, target)? else {
+            return Ok(());
+        };
+        
+        let identity = identity
+            .ok_or_else(|| format!("{:?} from an unauthenticated sender", action))?;
+        
+        let granted = self.grants.iter()
+            .filter(|grant| grant.key == identity.key
+                && grant.sender.as_ref().is_none_or(|sender| *sender == identity.sender))
+            .flat_map(|grant| &grant.permissions)
+            .any(|permission| permission.permits(&action));
+        
+        if granted {
+            Ok(())
+        } else {
+            Err(format!("{:?} not granted to key {}", action, identity.key))
+        }
+    }
+    
+    /// Denied messages, oldest first
+    pub fn audit_log(&self) -> Result<Vec<AuthorizationAuditEvent>> {
+        self.audit_log.lock()
+            .map(|log| log.iter().cloned().collect())
+            .map_err(|_| anyhow!("Failed to acquire lock on authorization audit log"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::protocols::{MessageMetadata, HEADER_CIP_COMMAND};
+    
+    fn create_message(destination: &str, subaddress: Option<u8>, words: usize) -> CommonMessage {
+        CommonMessage {
+            source_protocol: ProtocolType::EthernetIp,
+            target_protocol: Some(ProtocolType::MilStd1553),
+            priority: 1,
+            payload: vec![0; words * 2],
+            metadata: MessageMetadata {
+                source_address: "10.0.0.7".to_string(),
+                destination_address: destination.to_string(),
+                timestamp: 0,
+                message_id: 7,
+                is_command: true,
+                requires_response: false,
+                subaddress,
+                headers: BTreeMap::new(),
+            },
+        }
+    }
+    
+    fn create_identity(key: &str) -> PeerIdentity {
+        PeerIdentity { sender: "gw-b".to_string(), key: key.to_string() }
+    }
+    
+    fn create_policy() -> AuthorizationPolicy {
+        AuthorizationPolicy::new(&AuthorizationConfig {
+            grants: vec![AuthorizationGrant {
+                key: "ops".to_string(),
+                sender: Some("gw-b".to_string()),
+                permissions: vec![
+                    Permission::RtWrite { rt: 5, subaddresses: vec![1, 2] },
+                    Permission::ModeCode { rt: 5, codes: vec![17] },
+                    Permission::CipCommand { commands: vec![0x6F] },
+                ],
+            }],
+        })
+    }
+    
+    #[test]
+    fn test_granted_actions() {
+        let policy = create_policy();
+        let ops = create_identity("ops");
+        
+        assert!(policy.authorize(Some(&ops), &create_message("RT5", Some(1), 2), ProtocolType::MilStd1553).is_ok());
+        
+        // Mode code commands carry the code in the word count field
+        let mode_code = create_message("RT5", Some(31), 17);
+        assert_eq!(Action::of(&mode_code, ProtocolType::MilStd1553), Ok(Some(Action::ModeCode { rt: 5, code: 17 })));
+        assert!(policy.authorize(Some(&ops), &mode_code, ProtocolType::MilStd1553).is_ok());
+        
+        let mut cip = create_message("10.0.0.9", None, 2);
+        cip.metadata.requires_response = true;
+        assert!(policy.authorize(Some(&ops), &cip, ProtocolType::EthernetIp).is_ok());
+        
+        // Data responses and transmit commands do not act on their destination
+        let mut status = create_message("10.0.0.9", None, 2);
+        status.metadata.is_command = false;
+        assert!(policy.authorize(None, &status, ProtocolType::EthernetIp).is_ok());
+        
+        let mut read = create_message("RT5", Some(3), 2);
+        read.metadata.source_address = "RT5".to_string();
+        assert!(policy.authorize(None, &read, ProtocolType::MilStd1553).is_ok());
+        
+        assert!(policy.audit_log().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_default_deny() {
+        let policy = create_policy();
+        let ops = create_identity("ops");
+        let bus = ProtocolType::MilStd1553;
+        
+        // Ungranted subaddress, terminal and mode code
+        assert!(policy.authorize(Some(&ops), &create_message("RT5", Some(3), 2), bus).is_err());
+        assert!(policy.authorize(Some(&ops), &create_message("RT6", Some(1), 2), bus).is_err());
+        assert!(policy.authorize(Some(&ops), &create_message("RT5", Some(0), 1), bus).is_err());
+        
+        // Other keys and unauthenticated senders
+        let message = create_message("RT5", Some(1), 2);
+        assert!(policy.authorize(Some(&create_identity("maintenance")), &message, bus).is_err());
+        assert!(policy.authorize(None, &message, bus).is_err());
+        
+        let mut other_sender = create_identity("ops");
+        other_sender.sender = "gw-c".to_string();
+        assert!(policy.authorize(Some(&other_sender), &message, bus).is_err());
+        
+        let log = policy.audit_log().unwrap();
+        assert_eq!(log.len(), 6);
+        assert!(log[4].identity.is_none());
+        assert!(log.iter().all(|event| event.message_id == 7));
+    }
+    
+    #[test]
+    fn test_sender_flags_do_not_bypass() {
+        let policy = create_policy();
+        let ops = create_identity("ops");
+        
+        // Not marked as a command, but still emitted as a BC-to-RT write
+        let mut unmarked = create_message("RT5", Some(3), 2);
+        unmarked.metadata.is_command = false;
+        assert_eq!(Action::of(&unmarked, ProtocolType::MilStd1553), Ok(Some(Action::RtWrite { rt: 5, subaddress: 3 })));
+        assert!(policy.authorize(None, &unmarked, ProtocolType::MilStd1553).is_err());
+        
+        // Not addressed to a terminal and claiming a granted CIP command, but
+        // the 1553 formatter sends it to RT1
+        let mut disguised = create_message("10.0.0.9", None, 2);
+        disguised.metadata.headers.insert(HEADER_CIP_COMMAND.to_string(), "111".to_string());
+        assert_eq!(Action::of(&disguised, ProtocolType::MilStd1553), Ok(Some(Action::RtWrite { rt: 1, subaddress: 1 })));
+        assert!(policy.authorize(Some(&ops), &disguised, ProtocolType::MilStd1553).is_err());
+        
+        // On EtherNet/IP the emitted command counts, not the claimed one
+        assert!(policy.authorize(Some(&ops), &disguised, ProtocolType::EthernetIp).is_err());
+        
+        assert_eq!(policy.audit_log().unwrap().len(), 3);
+    }
+    
+    #[test]
+    fn test_transformed_outputs() {
+        let policy = create_policy();
+        let bus = ProtocolType::MilStd1553;
+        let message = create_message("RT5", Some(1), 2);
+        let ops = create_identity("ops");
+        let maintenance = create_identity("maintenance");
+        
+        assert!(policy.authorize_output(&TransformOutput::new(message.clone(), Some(&ops)), bus).is_ok());
+        
+        // Every sender whose contents an output carries must be granted its action
+        let combined = TransformOutput::combined(message.clone(), [Some(&ops), Some(&maintenance), Some(&ops)]);
+        assert_eq!(combined.senders.len(), 2);
+        assert!(policy.authorize_output(&combined, bus).is_err());
+        assert!(policy.authorize_output(&TransformOutput::combined(message.clone(), [Some(&ops), None]), bus).is_err());
+        
+        // Outputs attributed to no sender are unauthenticated
+        assert!(policy.authorize_output(&TransformOutput::combined(message, []), bus).is_err());
+        assert_eq!(policy.audit_log().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,396 @@
+//! Report-by-exception transform modules
+//!
+//! Much MIL-STD-1553 traffic is periodic with unchanged contents. These
+//! stateful modules suppress repeats so that only meaningful changes (plus
+//! an optional heartbeat) are forwarded downstream.
+//!
+//! Heartbeats are re-sent from `tick`, so an unchanged stream keeps being
+//! reported between inbound messages, attributed to the sender of the
+//! message they repeat. Each module tracks at most
+//! `MAX_STREAMS` streams, and forgets streams that have gone silent.
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::NumericField;
+use crate::gateway::ingress::PeerIdentity;
+use crate::gateway::transformer::{TransformModule, TransformOutput};
+use crate::protocols::CommonMessage;
+
+/// Most streams a module tracks; the least recently seen is dropped beyond this
+pub const MAX_STREAMS: usize = 1024;
+
+/// Time after which a silent stream is forgotten, without a heartbeat
+pub const STALE_STREAM_MS: u64 = 60_000;
+
+/// Heartbeat intervals after which a silent stream is forgotten
+const STALE_HEARTBEATS: u64 = 3;
+
+/// Key identifying an independent stream of periodic messages
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StreamKey {
+    source_address: String,
+    destination_address: String,
+    subaddress: Option<u8>,
+}
+
+impl StreamKey {
+    fn from_message(message: &CommonMessage) -> Self {
+        Self {
+            source_address: message.metadata.source_address.clone(),
+            destination_address: message.metadata.destination_address.clone(),
+            subaddress: message.metadata.subaddress,
+        }
+    }
+}
+
+/// Check whether the heartbeat interval has elapsed since the last forward
+fn heartbeat_due(heartbeat_ms: Option<u64>, last_forwarded: u64, now: u64) -> bool {
+    match heartbeat_ms {
+        Some(interval) => now.saturating_sub(last_forwarded) >= interval,
+        None => false,
+    }
+}
+
+/// Last forwarded message of a stream and the module's state for it
+struct Stream<T> {
+    state: T,
+    message: CommonMessage,
+    sender: Option<PeerIdentity>,
+    forwarded_at: u64,
+    seen_at: u64,
+}
+
+/// Streams tracked by a module, bounded to `MAX_STREAMS`
+struct StreamTable<T> {
+    streams: HashMap<StreamKey, Stream<T>>,
+    heartbeat_ms: Option<u64>,
+}
+
+impl<T> StreamTable<T> {
+    fn new(heartbeat_ms: Option<u64>) -> Self {
+        Self {
+            streams: HashMap::new(),
+            heartbeat_ms,
+        }
+    }
+    
+    /// Look up a stream, recording that it was seen at `now`
+    fn seen(&mut self, key: &StreamKey, now: u64) -> Option<&Stream<T>> {
+        let stream = self.streams.get_mut(key)?;
+        stream.seen_at = stream.seen_at.max(now);
+        Some(stream)
+    }
+    
+    /// Record a forwarded message, making room if the table is full
+    fn forward(&mut self, key: StreamKey, state: T, message: &CommonMessage, sender: Option<&PeerIdentity>, now: u64) {
+        if !self.streams.contains_key(&key) && self.streams.len() >= MAX_STREAMS {
+            let oldest = self.streams.iter()
+                .min_by_key(|(_, stream)| stream.seen_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.streams.remove(&oldest);
+            }
+        }
+        
+        self.streams.insert(key, Stream {
+            state,
+            message: message.clone(),
+            sender: sender.cloned(),
+            forwarded_at: now,
+            seen_at: now,
+        });
+    }
+    
+    /// Re-send streams whose heartbeat is due, forgetting silent streams
+    fn tick(&mut self, now: u64) -> Vec<TransformOutput> {
+        let stale_after = self.heartbeat_ms
+            .map_or(STALE_STREAM_MS, |interval| interval.saturating_mul(STALE_HEARTBEATS));
+        self.streams.retain(|_, stream| now.saturating_sub(stream.seen_at) < stale_after);
+        
+        let mut heartbeats = Vec::new();
+        for stream in self.streams.values_mut() {
+            if heartbeat_due(self.heartbeat_ms, stream.forwarded_at, now) {
+                stream.forwarded_at = now;
+                let mut message = stream.message.clone();
+                message.metadata.timestamp = now;
+                heartbeats.push(TransformOutput::new(message, stream.sender.as_ref()));
+            }
+        }
+        
+        heartbeats
+    }
+}
+
+/// Forwards a message only when its payload differs from the last one
+/// forwarded for the same stream, or when the heartbeat interval elapses.
+///
+/// Streams are keyed by source, destination and subaddress, and time is
+/// measured on message timestamps.
+pub struct ReportByExceptionTransform {
+    name: String,
+    streams: Mutex<StreamTable<()>>,
+}
+
+impl ReportByExceptionTransform {
+    pub fn new(name: &str, heartbeat_ms: Option<u64>) -> Self {
+        Self {
+            name: name.to_string(),
+            streams: Mutex::new(StreamTable::new(heartbeat_ms)),
+        }
+    }
+}
+
+impl TransformModule for ReportByExceptionTransform {
+    fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>) -> Result<Vec<TransformOutput>> {
+        let now = message.metadata.timestamp;
+        let key = StreamKey::from_message(message);
+        
+        let mut streams = self.streams.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on exception state"))?;
+        let heartbeat_ms = streams.heartbeat_ms;
+        
+        let forward = match streams.seen(&key, now) {
+            Some(last) => last.message.payload != message.payload
+                || heartbeat_due(heartbeat_ms, last.forwarded_at, now),
+            None => true,
+        };
+        
+        if !forward {
+            debug!("{}: suppressing unchanged message from {}", self.name, key.source_address);
+            return Ok(Vec::new());
+        }
+        
+        streams.forward(key, (), message, sender, now);
+        
+        Ok(vec![TransformOutput::new(message.clone(), sender)])
+    }
+    
+    fn tick(&self, now: u64) -> Result<Vec<TransformOutput>> {
+        Ok(self.streams.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on exception state"))?
+            .tick(now))
+    }
+    
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Forwards a message only when a numeric payload field has moved at least
+/// `deadband` away from the last forwarded value, or when the heartbeat
+/// interval elapses.
+///
+/// Messages too short to contain the field are always forwarded.
+pub struct DeadbandTransform {
+    name: String,
+    offset: usize,
+    field: NumericField,
+    deadband: f64,
+    
+    /// Streams with their last forwarded field value
+    streams: Mutex<StreamTable<f64>>,
+}
+
+impl DeadbandTransform {
+    pub fn new(name: &str, offset: usize, field: NumericField, deadband: f64,
+              heartbeat_ms: Option<u64>) -> Self {
+        Self {
+            name: name.to_string(),
+            offset,
+            field,
+            deadband,
+            streams: Mutex::new(StreamTable::new(heartbeat_ms)),
+        }
+    }
+    
+    /// Read the configured field from a payload
+    fn read_field(&self, payload: &[u8]) -> Option<f64> {
+        let bytes = payload.get(self.offset..self.offset + self.field.size())?;
+        
+        let value = match self.field {
+            NumericField::U16 => u16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            NumericField::I16 => i16::from_be_bytes([bytes[0], bytes[1]]) as f64,
+            NumericField::U32 => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            NumericField::I32 => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            NumericField::F32 => f32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+        };
+        
+        Some(value)
+    }
+}
+
+impl TransformModule for DeadbandTransform {
+    fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>) -> Result<Vec<TransformOutput>> {
+        let Some(value) = self.read_field(&message.payload) else {
+            debug!("{}: field not present in payload, forwarding", self.name);
+            return Ok(vec![TransformOutput::new(message.clone(), sender)]);
+        };
+        
+        let now = message.metadata.timestamp;
+        let key = StreamKey::from_message(message);
+        
+        let mut streams = self.streams.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on deadband state"))?;
+        let heartbeat_ms = streams.heartbeat_ms;
+        
+        let forward = match streams.seen(&key, now) {
+            Some(last) => {
+                // A NaN on either side never falls within the deadband
+                let delta = (value - last.state).abs();
+                delta.is_nan() || delta >= self.deadband
+                    || heartbeat_due(heartbeat_ms, last.forwarded_at, now)
+            },
+            None => true,
+        };
+        
+        if !forward {
+            debug!("{}: value {} within deadband, suppressing", self.name, value);
+            return Ok(Vec::new());
+        }
+        
+        streams.forward(key, value, message, sender, now);
+        
+        Ok(vec![TransformOutput::new(message.clone(), sender)])
+    }
+    
+    fn tick(&self, now: u64) -> Result<Vec<TransformOutput>> {
+        Ok(self.streams.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on deadband state"))?
+            .tick(now))
+    }
+    
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::protocols::{MessageMetadata, ProtocolType};
+    
+    fn create_test_message(subaddress: u8, payload: Vec<u8>, timestamp: u64) -> CommonMessage {
+        CommonMessage {
+            source_protocol: ProtocolType::MilStd1553,
+            target_protocol: Some(ProtocolType::EthernetIp),
+            priority: 2,
+            payload,
+            metadata: MessageMetadata {
+                source_address: "RT3".to_string(),
+                destination_address: "BC".to_string(),
+                timestamp,
+                message_id: timestamp,
+                is_command: false,
+                requires_response: false,
+                subaddress: Some(subaddress),
+                headers: BTreeMap::new(),
+            },
+        }
+    }
+    
+    #[test]
+    fn test_report_by_exception() {
+        let rbe = ReportByExceptionTransform::new("rbe", Some(1000));
+        
+        // First message is always forwarded
+        assert_eq!(rbe.transform(&create_test_message(1, vec![1, 2], 0), None).unwrap().len(), 1);
+        
+        // Identical payload is suppressed
+        assert!(rbe.transform(&create_test_message(1, vec![1, 2], 20), None).unwrap().is_empty());
+        
+        // Other subaddresses are tracked separately
+        assert_eq!(rbe.transform(&create_test_message(2, vec![1, 2], 30), None).unwrap().len(), 1);
+        
+        // Changed payload is forwarded
+        assert_eq!(rbe.transform(&create_test_message(1, vec![1, 3], 40), None).unwrap().len(), 1);
+        assert!(rbe.transform(&create_test_message(1, vec![1, 3], 60), None).unwrap().is_empty());
+        
+        // Heartbeat re-sends unchanged payload once the interval elapses
+        assert_eq!(rbe.transform(&create_test_message(1, vec![1, 3], 1040), None).unwrap().len(), 1);
+        assert!(rbe.transform(&create_test_message(1, vec![1, 3], 1060), None).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_deadband() {
+        let deadband = DeadbandTransform::new("db", 2, NumericField::I16, 10.0, None);
+        
+        let payload = |value: i16| {
+            let mut payload = vec![0xAA, 0xBB];
+            payload.extend_from_slice(&value.to_be_bytes());
+            payload
+        };
+        
+        assert_eq!(deadband.transform(&create_test_message(1, payload(100), 0), None).unwrap().len(), 1);
+        
+        // Small moves are suppressed, including drift that accumulates
+        // relative to the last forwarded value
+        assert!(deadband.transform(&create_test_message(1, payload(105), 20), None).unwrap().is_empty());
+        assert!(deadband.transform(&create_test_message(1, payload(95), 40), None).unwrap().is_empty());
+        assert_eq!(deadband.transform(&create_test_message(1, payload(110), 60), None).unwrap().len(), 1);
+        
+        // Negative moves past the deadband are forwarded
+        assert_eq!(deadband.transform(&create_test_message(1, payload(99), 80), None).unwrap().len(), 1);
+        
+        // Payloads without the field are passed through
+        assert_eq!(deadband.transform(&create_test_message(1, vec![1], 100), None).unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn test_heartbeat_on_tick() {
+        let rbe = ReportByExceptionTransform::new("rbe", Some(1000));
+        assert_eq!(rbe.transform(&create_test_message(1, vec![1, 2], 0), None).unwrap().len(), 1);
+        assert!(rbe.tick(500).unwrap().is_empty());
+        
+        // The last forwarded message is re-sent without a new inbound message
+        let heartbeats = rbe.tick(1000).unwrap();
+        assert_eq!(heartbeats.len(), 1);
+        assert_eq!(heartbeats[0].message.payload, vec![1, 2]);
+        assert_eq!(heartbeats[0].message.metadata.timestamp, 1000);
+        assert_eq!(heartbeats[0].senders, vec![None]);
+        assert!(rbe.tick(1500).unwrap().is_empty());
+        
+        // A stream that stays silent for three heartbeats is forgotten
+        assert_eq!(rbe.tick(2000).unwrap().len(), 1);
+        assert!(rbe.tick(3000).unwrap().is_empty());
+        assert_eq!(rbe.transform(&create_test_message(1, vec![1, 2], 3100), None).unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn test_stream_table_bounded() {
+        let deadband = DeadbandTransform::new("db", 0, NumericField::U16, 1.0, None);
+        
+        for index in 0..=MAX_STREAMS {
+            let mut message = create_test_message(1, vec![0, 0], index as u64);
+            message.metadata.source_address = format!("RT{}", index);
+            deadband.transform(&message, None).unwrap();
+        }
+        assert_eq!(deadband.streams.lock().unwrap().streams.len(), MAX_STREAMS);
+        
+        // The least recently seen stream was dropped, so it is forwarded again
+        let mut first = create_test_message(1, vec![0, 0], 5000);
+        first.metadata.source_address = "RT0".to_string();
+        assert_eq!(deadband.transform(&first, None).unwrap().len(), 1);
+        
+        // Streams without a heartbeat are forgotten once silent for long enough
+        assert!(deadband.tick(5000 + STALE_STREAM_MS).unwrap().is_empty());
+        assert!(deadband.streams.lock().unwrap().streams.is_empty());
+    }
+    
+    #[test]
+    fn test_heartbeat_keeps_sender() {
+        let rbe = ReportByExceptionTransform::new("rbe", Some(1000));
+        let ops = PeerIdentity { sender: "gw-b".to_string(), key: "ops".to_string() };
+        let other = PeerIdentity { sender: "gw-c".to_string(), key: "other".to_string() };
+        
+        // A suppressed repeat does not take over the stream it repeats
+        assert_eq!(rbe.transform(&create_test_message(1, vec![1, 2], 0), Some(&ops)).unwrap().len(), 1);
+        assert!(rbe.transform(&create_test_message(1, vec![1, 2], 500), Some(&other)).unwrap().is_empty());
+        
+        let heartbeats = rbe.tick(1000).unwrap();
+        assert_eq!(heartbeats[0].senders, vec![Some(ops)]);
+    }
+}
@@ -0,0 +1,342 @@
+//! Ingress unwrap stage for inbound traffic
+//!
+//! Messages arriving from a secured link carry a serialized `SecuredMessage`
+//! as their payload. This stage recognizes such envelopes, verifies and
+//! decrypts them with the keys named in their header, and restores the
+//! message they protect before routing. Each ingress interface has a minimum
+//! security mode; plaintext counts as `SecurityMode::None`. The interface is
+//! the one the transport received the message on; headers in the message are
+//! never trusted for it. Interfaces with
+//! a compact link also accept compact envelopes, which protect only the
+//! payload. Messages that fail any check are dropped and recorded as audit
+//! events. Unwrapped messages carry the identity of the key that
+//! authenticated them, for authorization, and the interface they were
+//! received on in their `ingress-interface` header, for routing.
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::protocols::{CommonMessage, ProtocolType, HEADER_INGRESS_INTERFACE};
+use crate::security::{SecurityError, SecurityMode, SecurityService};
+use crate::utils::current_time_millis;
+
+/// Number of dropped messages kept in the ingress audit log
+pub const MAX_INGRESS_AUDIT_EVENTS: usize = 1024;
+
+/// Audit record of a dropped inbound message
+#[derive(Debug, Clone)]
+pub struct IngressAuditEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub interface: String,
+    pub source_address: String,
+    pub message_id: u64,
+    pub reason: String,
+}
+
+/// Key that authenticated an inbound message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Sender named in the authenticated header, or the peer of a compact link
+    pub sender: String,
+    
+    /// Key ring that authenticated the message; the signing ring for signed modes
+    pub key: String,
+}
+
+/// Inbound message after unwrapping
+#[derive(Debug, Clone)]
+pub struct InboundMessage {
+    pub message: CommonMessage,
+    
+    /// None for messages that were not authenticated
+    pub identity: Option<PeerIdentity>,
+}
+
+/// Verifies and unwraps inbound messages before routing
+pub struct IngressStage {
+    /// Minimum security mode by interface
+    min_modes: HashMap<String, SecurityMode>,
+    
+    /// Minimum security mode of interfaces without their own
+    default_min_mode: SecurityMode,
+    
+    /// Interface messages of each protocol are exchanged on
+    interfaces: HashMap<ProtocolType, String>,
+    
+    /// Most recent dropped messages, oldest first
+    audit_log: Mutex<VecDeque<IngressAuditEvent>>,
+}
+
+impl IngressStage {
+    /// Create the ingress stage from configuration
+    pub fn new(config: &Config) -> Self {
+        let interfaces = [ProtocolType::MilStd1553, ProtocolType::EthernetIp]
+            .into_iter()
+            .map(|protocol| (protocol, config.get_ingress_interface(protocol)))
+            .collect();
+        
+        Self {
+            min_modes: config.security.ingress_min_security_modes.clone(),
+            default_min_mode: config.security.default_ingress_min_security_mode,
+            interfaces,
+            audit_log: Mutex::new(VecDeque::new()),
+        }
+    }
+    
+    /// Interface messages of a protocol are exchanged on
+    pub fn protocol_interface(&self, protocol: ProtocolType) -> Option<&str> {
+        self.interfaces.get(&protocol).map(String::as_str)
+    }
+    
+    /// Minimum security mode required on an interface
+    pub fn minimum_mode(&self, interface: &str) -> SecurityMode {
+        self.min_modes.get(interface).copied().unwrap_or(self.default_min_mode)
+    }
+    
+    /// Unwrap a message received on `interface`, or drop it and record an audit event
+    pub fn unwrap(&self, security: &SecurityService, interface: &str, message: CommonMessage) -> Result<InboundMessage> {
+        match self.open(security, &message, interface) {
+            Ok(mut unwrapped) => {
+                // Replaces any value set by the sender or an upstream gateway
+                unwrapped.message.metadata.headers.insert(HEADER_INGRESS_INTERFACE.to_string(), interface.to_string());
+                Ok(unwrapped)
+            },
+            Err(e) => {
+                let event = IngressAuditEvent {
+                    timestamp: current_time_millis(),
+                    interface: interface.to_string(),
+                    source_address: message.metadata.source_address.clone(),
+                    message_id: message.metadata.message_id,
+                    reason: e.to_string(),
+                };
+                
+                warn!(target: "audit", "Dropped inbound message {} from {} on {}: {}",
+                    event.message_id, event.source_address, event.interface, event.reason);
+                
+                let mut audit_log = self.audit_log.lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock on ingress audit log"))?;
+                if audit_log.len() == MAX_INGRESS_AUDIT_EVENTS {
+                    audit_log.pop_front();
+                }
+                audit_log.push_back(event);
+                
+                Err(e.context("Inbound message dropped"))
+            }
+        }
+    }
+    
+    /// Check the security mode of a message and unwrap it if secured
+    fn open(&self, security: &SecurityService, message: &CommonMessage, interface: &str) -> Result<InboundMessage> {
+        let minimum = self.minimum_mode(interface);
+        
+        if let Some(link) = security.compact_link(interface) {
+            if SecurityService::is_compact_envelope(&message.payload) {
+                let mode = SecurityService::compact_mode(&message.payload)?;
+                check_mode(mode, minimum, interface)?;
+                
+                // Compact envelopes carry only the payload; the metadata comes from the link
+                let mut unwrapped = message.clone();
+                unwrapped.payload = security.extract_compact(&message.payload, link)?;
+                
+                let identity = SecurityService::compact_key(&message.payload, link)
+                    .filter(|_| mode.is_authenticated())
+                    .map(|key| PeerIdentity { sender: link.peer.clone(), key: key.to_string() });
+                
+                return Ok(InboundMessage { message: unwrapped, identity });
+            }
+        }
+        
+        if !SecurityService::is_envelope(&message.payload) {
+            check_mode(SecurityMode::None, minimum, interface)?;
+            return Ok(InboundMessage { message: message.clone(), identity: None });
+        }
+        
+        let secured = security.deserialize(&message.payload)?;
+        
+        // The header mode is authenticated by extract_message, so checking it first is safe
+        check_mode(secured.header.mode, minimum, interface)?;
+        
+        let plaintext = security.extract_message(&secured)?;
+        
+        let header = &secured.header;
+        let identity = header.mode.is_authenticated().then(|| PeerIdentity {
+            sender: header.sender.clone(),
+            key: header.signing_key.as_ref().unwrap_or(&header.key).name.clone(),
+        });
+        
+        let message = bincode::deserialize(&plaintext)
+            .map_err(|e| anyhow!("Failed to decode unwrapped message: {}", e))?;
+        
+        Ok(InboundMessage { message, identity })
+    }
+    
+    /// Dropped messages, oldest first
+    pub fn audit_log(&self) -> Result<Vec<IngressAuditEvent>> {
+        self.audit_log.lock()
+            .map(|log| log.iter().cloned().collect())
+            .map_err(|_| anyhow!("Failed to acquire lock on ingress audit log"))
+    }
+}
+
+/// Reject a security mode weaker than the interface minimum
+fn check_mode(mode: SecurityMode, minimum: SecurityMode, interface: &str) -> Result<()> {
+    if mode.satisfies(minimum) {
+        Ok(())
+    } else {
+        Err(anyhow!(SecurityError::AuthenticationFailed(
+            format!("{:?} message below minimum {:?} for interface {}", mode, minimum, interface)
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::config::TranslationRule;
+    use crate::gateway::router::Router;
+    use crate::protocols::MessageMetadata;
+    use crate::security::key_manager::KeyManager;
+    
+    /// Interface requiring signed messages
+    const PLC: &str = "0.0.0.0:44818";
+    
+    fn create_message(payload: Vec<u8>) -> CommonMessage {
+        CommonMessage {
+            source_protocol: ProtocolType::EthernetIp,
+            target_protocol: Some(ProtocolType::MilStd1553),
+            priority: 3,
+            payload,
+            metadata: MessageMetadata {
+                source_address: "10.0.0.7".to_string(),
+                destination_address: "RT5".to_string(),
+                timestamp: 0,
+                message_id: 42,
+                is_command: true,
+                requires_response: false,
+                subaddress: Some(1),
+                headers: BTreeMap::new(),
+            },
+        }
+    }
+    
+    fn create_service() -> SecurityService {
+        let km = KeyManager::new();
+        km.generate_encryption_key("link", "Link key", None).unwrap();
+        km.generate_keypair("link", "Link keypair", None).unwrap();
+        SecurityService::new(km)
+    }
+    
+    fn wrap(security: &SecurityService, inner: &CommonMessage, mode: SecurityMode) -> CommonMessage {
+        let secured = security.secure_message(&bincode::serialize(inner).unwrap(), mode, "link").unwrap();
+        create_message(security.serialize(&secured).unwrap())
+    }
+    
+    fn create_stage() -> IngressStage {
+        let mut config = Config::default();
+        config.security.ingress_min_security_modes.insert(PLC.to_string(), SecurityMode::Signed);
+        config.security.ingress_min_security_modes.insert("trusted".to_string(), SecurityMode::None);
+        config.security.default_ingress_min_security_mode = SecurityMode::EncryptedAndSigned;
+        IngressStage::new(&config)
+    }
+    
+    #[test]
+    fn test_unwrap_secured_message() {
+        let security = create_service();
+        let stage = create_stage();
+        let inner = create_message(vec![1, 2, 3]);
+        
+        let unwrapped = stage.unwrap(&security, PLC, wrap(&security, &inner, SecurityMode::EncryptedAndSigned)).unwrap();
+        assert_eq!(unwrapped.message.payload, inner.payload);
+        assert_eq!(unwrapped.message.metadata.subaddress, Some(1));
+        assert_eq!(unwrapped.identity.unwrap().key, "link");
+        assert_eq!(unwrapped.message.metadata.header(HEADER_INGRESS_INTERFACE), Some(PLC));
+        
+        // Signed satisfies the interface minimum as well
+        assert!(stage.unwrap(&security, PLC, wrap(&security, &inner, SecurityMode::Signed)).is_ok());
+        
+        // Plaintext passes through on interfaces that allow it
+        let unwrapped = stage.unwrap(&security, "trusted", create_message(vec![9])).unwrap();
+        assert_eq!(unwrapped.message.payload, vec![9]);
+        assert!(unwrapped.identity.is_none());
+        assert!(stage.audit_log().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_drops_are_audited() {
+        let security = create_service();
+        let stage = create_stage();
+        let inner = create_message(vec![1, 2, 3]);
+        
+        // Below the interface minimum
+        assert!(stage.unwrap(&security, PLC, create_message(vec![1, 2, 3])).is_err());
+        assert!(stage.unwrap(&security, PLC, wrap(&security, &inner, SecurityMode::Encrypted)).is_err());
+        
+        // Failed authentication
+        let mut tampered = wrap(&security, &inner, SecurityMode::EncryptedAndSigned);
+        let mut secured = security.deserialize(&tampered.payload).unwrap();
+        secured.payload[0] ^= 0xFF;
+        tampered.payload = security.serialize(&secured).unwrap();
+        assert!(stage.unwrap(&security, PLC, tampered).is_err());
+        
+        // Replay
+        let envelope = wrap(&security, &inner, SecurityMode::Signed);
+        assert!(stage.unwrap(&security, PLC, envelope.clone()).is_ok());
+        assert!(stage.unwrap(&security, PLC, envelope).is_err());
+        
+        // Interfaces without their own minimum use the default
+        assert!(stage.unwrap(&security, "bus-b", create_message(vec![9])).is_err());
+        
+        let log = stage.audit_log().unwrap();
+        assert_eq!(log.len(), 5);
+        assert!(log.iter().all(|event| event.message_id == 42));
+        assert_eq!(log[0].interface, PLC);
+        assert_eq!(log[4].interface, "bus-b");
+    }
+    
+    #[test]
+    fn test_ingress_interface_routable() {
+        let security = create_service();
+        let stage = create_stage();
+        
+        let mut rule = TranslationRule {
+            name: "from-plc".to_string(),
+            source: ProtocolType::EthernetIp,
+            target: ProtocolType::MilStd1553,
+            priority: 1,
+            filter: Default::default(),
+            transform: None,
+            security_mode: SecurityMode::Signed,
+            egress_link: None,
+        };
+        rule.filter.insert("header.ingress-interface".to_string(), PLC.to_string());
+        let router = Router::new(&[rule]);
+        
+        // The header an upstream hop recorded is replaced before routing
+        let mut inner = create_message(vec![1, 2, 3]);
+        inner.metadata.headers.insert(HEADER_INGRESS_INTERFACE.to_string(), "bus-b".to_string());
+        assert!(router.find_rule(&inner).is_err());
+        
+        let unwrapped = stage.unwrap(&security, PLC, wrap(&security, &inner, SecurityMode::Signed)).unwrap();
+        assert_eq!(router.find_rule(&unwrapped.message).unwrap().name, "from-plc");
+    }
+    
+    #[test]
+    fn test_spoofed_interface_header_rejected() {
+        let security = create_service();
+        let stage = create_stage();
+        
+        // Plaintext claiming to come from an interface that allows it
+        let mut spoofed = create_message(vec![9]);
+        spoofed.metadata.headers.insert(HEADER_INGRESS_INTERFACE.to_string(), "trusted".to_string());
+        assert!(stage.unwrap(&security, PLC, spoofed).is_err());
+        
+        let log = stage.audit_log().unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].interface, PLC);
+    }
+}
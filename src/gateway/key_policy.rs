@@ -0,0 +1,217 @@
+//! Outbound key selection
+//!
+//! Chooses the encryption and signing key rings for each translated message
+//! from the configured key policies, falling back to the default keys. Every
+//! key a rule can select is checked against the key store at startup, so a
+//! missing key stops the gateway rather than failing individual messages.
+
+use anyhow::{anyhow, Result};
+
+use crate::config::{KeyPolicyConfig, SecurityConfig, TranslationRule};
+use crate::protocols::{CommonMessage, HEADER_PEER};
+use crate::security::crypto::CipherSuite;
+use crate::security::key_manager::KeyManager;
+
+/// Key rings and cipher suite selected for an outbound message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySelection<'a> {
+    pub encryption: &'a str,
+    pub signing: &'a str,
+    pub cipher_suite: CipherSuite,
+}
+
+/// Key selection policy built from the security configuration
+pub struct KeyPolicy {
+    policies: Vec<KeyPolicyConfig>,
+    default_encryption: String,
+    default_signing: String,
+    default_cipher_suite: CipherSuite,
+}
+
+impl KeyPolicy {
+    /// Create a policy from the security configuration
+    pub fn new(config: &SecurityConfig) -> Self {
+        Self {
+            policies: config.key_policies.clone(),
+            default_encryption: config.default_encryption_key.clone(),
+            default_signing: config.default_signing_key.clone(),
+            default_cipher_suite: config.cipher_suite,
+        }
+    }
+    
+    /// Select the keys for a message translated by `rule`
+    pub fn select(&self, rule: &TranslationRule, message: &CommonMessage) -> KeySelection<'_> {
+        let peer = message.metadata.header(HEADER_PEER);
+        
+        let policy = self.policies.iter().find(|policy| {
+            policy.rule.as_ref().is_none_or(|name| *name == rule.name)
+                && policy.destination.as_ref().is_none_or(|dest| *dest == message.metadata.destination_address)
+                && policy.peer.as_ref().is_none_or(|name| Some(name.as_str()) == peer)
+        });
+        
+        self.resolve(policy)
+    }
+    
+    /// Keys chosen by a policy entry, or the defaults
+    fn resolve<'a>(&'a self, policy: Option<&'a KeyPolicyConfig>) -> KeySelection<'a> {
+        KeySelection {
+            encryption: policy.and_then(|p| p.encryption_key.as_deref()).unwrap_or(&self.default_encryption),
+            signing: policy.and_then(|p| p.signing_key.as_deref()).unwrap_or(&self.default_signing),
+            cipher_suite: policy.and_then(|p| p.cipher_suite).unwrap_or(self.default_cipher_suite),
+        }
+    }
+    
+    /// Every encryption key ring `select` can return, the default first
+    pub fn encryption_rings(&self) -> Vec<&str> {
+        let mut rings = vec![self.default_encryption.as_str()];
+        for ring in self.policies.iter().filter_map(|policy| policy.encryption_key.as_deref()) {
+            if !rings.contains(&ring) {
+                rings.push(ring);
+            }
+        }
+        
+        rings
+    }
+    
+    /// Check that every key selectable for each rule is available for its security mode
+    pub fn validate(&self, key_manager: &KeyManager, rules: &[TranslationRule]) -> Result<()> {
+        for rule in rules {
+            // Destination and peer are only known per message, so any entry not excluded by rule name may apply
+            let candidates = self.policies.iter()
+                .filter(|policy| policy.rule.as_ref().is_none_or(|name| *name == rule.name))
+                .map(Some)
+                .chain(std::iter::once(None));
+            
+            for policy in candidates {
+                let keys = self.resolve(policy);
+                
+                if rule.security_mode.uses_symmetric_key() && key_manager.primary_key(keys.encryption, true, false).is_err() {
+                    return Err(anyhow!("Translation rule '{}' may use encryption key '{}', which is not available",
+                        rule.name, keys.encryption));
+                }
+                
+                if rule.security_mode.is_signed() && key_manager.primary_key(keys.signing, false, true).is_err() {
+                    return Err(anyhow!("Translation rule '{}' may use signing key '{}', which is not available",
+                        rule.name, keys.signing));
+                }
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+    use crate::config::{Config, TransformType};
+    use crate::protocols::{MessageMetadata, ProtocolType};
+    use crate::security::SecurityMode;
+    
+    fn create_rule(name: &str, security_mode: SecurityMode) -> TranslationRule {
+        TranslationRule {
+            name: name.to_string(),
+            source: ProtocolType::MilStd1553,
+            target: ProtocolType::EthernetIp,
+            priority: 5,
+            filter: HashMap::new(),
+            transform: Some(TransformType::Identity),
+            security_mode,
+            egress_link: None,
+        }
+    }
+    
+    fn create_message(destination: &str, peer: Option<&str>) -> CommonMessage {
+        let mut headers = BTreeMap::new();
+        if let Some(peer) = peer {
+            headers.insert(HEADER_PEER.to_string(), peer.to_string());
+        }
+        
+        CommonMessage {
+            source_protocol: ProtocolType::MilStd1553,
+            target_protocol: Some(ProtocolType::EthernetIp),
+            priority: 3,
+            payload: vec![1, 2, 3],
+            metadata: MessageMetadata {
+                source_address: "RT1".to_string(),
+                destination_address: destination.to_string(),
+                timestamp: 0,
+                message_id: 1,
+                is_command: false,
+                requires_response: false,
+                subaddress: None,
+                headers,
+            },
+        }
+    }
+    
+    fn create_policy() -> KeyPolicy {
+        let mut config = Config::default();
+        config.security.key_policies = vec![
+            KeyPolicyConfig {
+                peer: Some("gw-b".to_string()),
+                encryption_key: Some("gw-b-enc".to_string()),
+                signing_key: Some("gw-b-sig".to_string()),
+                cipher_suite: Some(CipherSuite::XChaCha20Poly1305),
+                ..Default::default()
+            },
+            KeyPolicyConfig {
+                rule: Some("telemetry".to_string()),
+                destination: Some("10.0.0.5".to_string()),
+                encryption_key: Some("telemetry-enc".to_string()),
+                ..Default::default()
+            },
+        ];
+        
+        KeyPolicy::new(&config.security)
+    }
+    
+    #[test]
+    fn test_key_selection() {
+        let policy = create_policy();
+        let telemetry = create_rule("telemetry", SecurityMode::EncryptedAndSigned);
+        
+        // First matching entry wins, and unset keys fall back to the defaults
+        assert_eq!(policy.select(&telemetry, &create_message("10.0.0.5", None)),
+            KeySelection { encryption: "telemetry-enc", signing: "default-signing",
+                cipher_suite: CipherSuite::ChaCha20Poly1305 });
+        assert_eq!(policy.select(&telemetry, &create_message("10.0.0.5", Some("gw-b"))),
+            KeySelection { encryption: "gw-b-enc", signing: "gw-b-sig", cipher_suite: CipherSuite::XChaCha20Poly1305 });
+        
+        // No match selects the defaults
+        let commands = create_rule("commands", SecurityMode::EncryptedAndSigned);
+        assert_eq!(policy.select(&commands, &create_message("10.0.0.5", None)),
+            KeySelection { encryption: "default-encryption", signing: "default-signing",
+                cipher_suite: CipherSuite::ChaCha20Poly1305 });
+        
+        assert_eq!(policy.encryption_rings(), vec!["default-encryption", "gw-b-enc", "telemetry-enc"]);
+    }
+    
+    #[test]
+    fn test_validate_keys() {
+        let policy = create_policy();
+        let rules = vec![
+            create_rule("telemetry", SecurityMode::Encrypted),
+            create_rule("status", SecurityMode::Signed),
+        ];
+        
+        let km = KeyManager::new();
+        km.generate_encryption_key("default-encryption", "Default", None).unwrap();
+        km.generate_keypair("default-signing", "Default", None).unwrap();
+        km.generate_encryption_key("gw-b-enc", "Peer", None).unwrap();
+        km.generate_keypair("gw-b-sig", "Peer", None).unwrap();
+        
+        // The telemetry rule may select the missing telemetry key
+        let err = policy.validate(&km, &rules).unwrap_err();
+        assert!(err.to_string().contains("telemetry-enc"));
+        
+        km.generate_encryption_key("telemetry-enc", "Telemetry", None).unwrap();
+        policy.validate(&km, &rules).unwrap();
+        
+        // Signing keys are only required by signed rules
+        km.delete_key("default-signing-signing").unwrap();
+        assert!(policy.validate(&km, &rules).is_err());
+        assert!(policy.validate(&km, &rules[..1]).is_ok());
+    }
+}
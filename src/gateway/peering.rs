@@ -0,0 +1,142 @@
+//! Session handshakes with peer gateways
+//!
+//! Runs the authenticated key agreement of `security::handshake` for the
+//! gateway, identified by its configured name and using the configured
+//! post-quantum policy. The gateway's identity keypair and the peers'
+//! verification keys must be in its key store.
+//!
+//! Handshake state is only dropped once a message signed by the peer
+//! arrives, so forged messages cannot abandon a handshake in progress. A
+//! handshake this gateway started is kept per peer until a response signed
+//! by that peer verifies; starting another with the same peer abandons it.
+//! Handshakes started by a peer are kept side by side, up to
+//! `MAX_PENDING_RESPONSES` per peer with the oldest dropped first, until a
+//! finish message verifies against one of them. With a session ratchet
+//! configured, the agreed keys are ratcheted forward from the moment the
+//! handshake completes.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::security::SecurityError;
+use crate::security::SecurityService;
+use crate::security::handshake::{
+    HandshakeFinish, HandshakeInit, HandshakeResponse, Initiator, KeyExchangePolicy, Responder, SessionKeys,
+};
+use crate::security::ratchet::RatchetSchedule;
+
+/// Unfinished handshakes started by one peer that are kept
+pub const MAX_PENDING_RESPONSES: usize = 4;
+
+/// Handshakes with peer gateways, by peer name
+pub struct PeerHandshakes {
+    local: String,
+    policy: KeyExchangePolicy,
+    ratchet: Option<RatchetSchedule>,
+    initiated: Mutex<HashMap<String, Initiator>>,
+    responded: Mutex<HashMap<String, Vec<Responder>>>,  // Oldest first
+}
+
+impl PeerHandshakes {
+    /// Create the handshake state for the configured gateway name and policy
+    pub fn new(config: &Config) -> Self {
+        Self {
+            local: config.general.name.clone(),
+            policy: KeyExchangePolicy::from_config(config.security.handshake_post_quantum),
+            ratchet: config.security.session_ratchet.as_ref().map(|ratchet| RatchetSchedule {
+                messages_per_key: ratchet.messages_per_key,
+                max_key_age: Duration::from_secs(ratchet.max_key_age_secs),
+                max_skipped_keys: ratchet.max_skipped_keys,
+            }),
+            initiated: Mutex::new(HashMap::new()),
+            responded: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Start a handshake with `peer`, returning the message to send it
+    pub fn start(&self, peer: &str) -> Result<HandshakeInit> {
+        let (initiator, init) = Initiator::start_with_policy(&self.local, &self.policy)?;
+        
+        self.initiated.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on peer handshakes"))?
+            .insert(peer.to_string(), initiator);
+        
+        Ok(init)
+    }
+    
+    /// Answer a handshake started by a peer, returning the message to send back
+    pub fn respond(&self, security: &SecurityService, init: &HandshakeInit) -> Result<HandshakeResponse> {
+        let (responder, response) = Responder::respond_with_policy(security.key_manager(), &self.local, init,
+            &self.policy)?;
+        
+        let mut responded = self.responded.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on peer handshakes"))?;
+        let pending = responded.entry(init.initiator.clone()).or_default();
+        if pending.len() == MAX_PENDING_RESPONSES {
+            pending.remove(0);
+        }
+        pending.push(responder);
+        
+        Ok(response)
+    }
+    
+    /// Complete a handshake this gateway started, installing the session keys
+    ///
+    /// The returned message must be sent to the peer so that it can install
+    /// its keys too.
+    pub fn finish(&self, security: &SecurityService, response: &HandshakeResponse)
+        -> Result<(HandshakeFinish, SessionKeys)> {
+        let initiator = {
+            let mut initiated = self.initiated.lock()
+                .map_err(|_| anyhow!("Failed to acquire lock on peer handshakes"))?;
+            initiated.get(&response.responder)
+                .ok_or_else(|| not_in_progress(&response.responder))?
+                .verify(security.key_manager(), response)?;
+            initiated.remove(&response.responder).ok_or_else(|| not_in_progress(&response.responder))?
+        };
+        
+        let (finish, keys) = initiator.finish(security.key_manager(), response)?;
+        self.start_ratchet(security, &keys)?;
+        
+        Ok((finish, keys))
+    }
+    
+    /// Complete a handshake `peer` started, installing the session keys
+    ///
+    /// The other handshakes `peer` started are dropped once one completes.
+    pub fn complete(&self, security: &SecurityService, peer: &str, finish: &HandshakeFinish) -> Result<SessionKeys> {
+        let responder = {
+            let mut responded = self.responded.lock()
+                .map_err(|_| anyhow!("Failed to acquire lock on peer handshakes"))?;
+            let pending = responded.get_mut(peer).ok_or_else(|| not_in_progress(peer))?;
+            let index = pending.iter()
+                .position(|responder| responder.verify(security.key_manager(), finish).is_ok())
+                .ok_or_else(|| anyhow!(SecurityError::HandshakeFailed(
+                    format!("finish message does not match a handshake in progress with {}", peer)
+                )))?;
+            let responder = pending.swap_remove(index);
+            responded.remove(peer);
+            responder
+        };
+        
+        let keys = responder.finish(security.key_manager(), finish)?;
+        self.start_ratchet(security, &keys)?;
+        
+        Ok(keys)
+    }
+    
+    /// Ratchet newly agreed session keys, if configured
+    fn start_ratchet(&self, security: &SecurityService, keys: &SessionKeys) -> Result<()> {
+        match self.ratchet {
+            Some(schedule) => security.start_session_ratchet(keys, schedule),
+            None => Ok(()),
+        }
+    }
+}
+
+fn not_in_progress(peer: &str) -> anyhow::Error {
+    anyhow!(SecurityError::HandshakeFailed(format!("no handshake in progress with {}", peer)))
+}
@@ -10,6 +10,9 @@ use std::collections::HashMap;
 use crate::config::TranslationRule;
 use crate::protocols::{CommonMessage, ProtocolType};
 
+/// Prefix for filter keys that match on message headers
+const HEADER_FILTER_PREFIX: &str = "header.";
+
 /// Message router that determines how messages should be translated and forwarded
 pub struct Router {
     /// Rules for message translation
@@ -30,14 +33,14 @@ impl Router {
             let key = (rule.source, Some(rule.target));
             
             rule_map.entry(key)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(idx);
                 
             // Also create an entry for wildcard target
             let wildcard_key = (rule.source, None);
             
             rule_map.entry(wildcard_key)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(idx);
         }
         
@@ -113,15 +116,13 @@ impl Router {
         // Check each filter criterion
         for (key, value) in &rule.filter {
             match key.as_str() {
-                "source_address" => {
-                    if !value.is_empty() && message.metadata.source_address != *value {
-                        return false;
-                    }
+                "source_address"
+                    if !value.is_empty() && message.metadata.source_address != *value => {
+                    return false;
                 },
-                "destination_address" => {
-                    if !value.is_empty() && message.metadata.destination_address != *value {
-                        return false;
-                    }
+                "destination_address"
+                    if !value.is_empty() && message.metadata.destination_address != *value => {
+                    return false;
                 },
                 "priority" => {
                     if let Ok(priority) = value.parse::<u8>() {
@@ -144,6 +145,18 @@ impl Router {
                         }
                     }
                 },
+                // Header criteria: "header.<name>" matches a header value,
+                // or just the header's presence if the value is empty. Routing
+                // runs before transform modules, so only headers present at
+                // ingress (including "ingress-interface") can match
+                _ if key.starts_with(HEADER_FILTER_PREFIX) => {
+                    let name = &key[HEADER_FILTER_PREFIX.len()..];
+                    
+                    match message.metadata.header(name) {
+                        Some(header) if value.is_empty() || header == value => {},
+                        _ => return false,
+                    }
+                },
                 // Additional criteria can be added here
                 _ => {
                     // Unknown filter criterion, ignore
@@ -165,12 +178,12 @@ impl Router {
         // Add to the lookup maps
         let key = (rule.source, Some(rule.target));
         self.rule_map.entry(key)
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(idx);
             
         let wildcard_key = (rule.source, None);
         self.rule_map.entry(wildcard_key)
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(idx);
             
         // Re-sort the affected rule lists
@@ -191,7 +204,7 @@ impl Router {
             .ok_or_else(|| anyhow!("Rule not found: {}", name))?;
             
         // Remove the rule
-        let rule = self.rules.remove(idx);
+        self.rules.remove(idx);
         
         // Update the lookup maps
         self.rebuild_rule_map();
@@ -208,13 +221,13 @@ impl Router {
             // Add to exact protocol match map
             let key = (rule.source, Some(rule.target));
             self.rule_map.entry(key)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(idx);
                 
             // Add to wildcard target map
             let wildcard_key = (rule.source, None);
             self.rule_map.entry(wildcard_key)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(idx);
         }
         
@@ -233,7 +246,7 @@ impl Router {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use crate::config::TransformType;
     use crate::protocols::{MessageMetadata, ProtocolType};
     use crate::security::SecurityMode;
@@ -247,6 +260,7 @@ mod tests {
             filter: HashMap::new(),
             transform: Some(TransformType::Identity),
             security_mode: SecurityMode::EncryptedAndSigned,
+            egress_link: None,
         }
     }
     
@@ -263,6 +277,8 @@ mod tests {
                 message_id: 67890,
                 is_command: true,
                 requires_response: true,
+                subaddress: None,
+                headers: BTreeMap::new(),
             },
         }
     }
@@ -318,6 +334,28 @@ mod tests {
         assert!(router.find_rule(&msg2).is_err());
     }
     
+    #[test]
+    fn test_rule_with_header_filter() {
+        let mut rule = create_test_rule("traced", ProtocolType::MilStd1553, ProtocolType::EthernetIp);
+        rule.filter.insert("header.gateway".to_string(), "gw-1".to_string());
+        rule.filter.insert("header.trace-id".to_string(), String::new());
+        
+        let router = Router::new(&[rule]);
+        
+        // Missing headers do not match
+        let mut msg = create_test_message(ProtocolType::MilStd1553, Some(ProtocolType::EthernetIp));
+        assert!(router.find_rule(&msg).is_err());
+        
+        // Matching value and any trace ID
+        msg.metadata.headers.insert("gateway".to_string(), "gw-1".to_string());
+        msg.metadata.headers.insert("trace-id".to_string(), "abc".to_string());
+        assert!(router.find_rule(&msg).is_ok());
+        
+        // Wrong value
+        msg.metadata.headers.insert("gateway".to_string(), "gw-2".to_string());
+        assert!(router.find_rule(&msg).is_err());
+    }
+    
     #[test]
     fn test_rule_priority() {
         // Create two rules with different priorities for the same protocols
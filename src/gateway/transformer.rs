@@ -6,22 +6,86 @@
 use anyhow::{anyhow, Result};
 use log::debug;
 use std::collections::HashMap;
+use std::sync::Mutex;
 
-use crate::config::{TransformType, TranslationRule};
-use crate::protocols::CommonMessage;
+use crate::config::{Config, TransformModuleKind, TransformType, TranslationRule};
+use crate::gateway::aggregation::{AggregateTransform, SplitTransform};
+use crate::gateway::exception::{DeadbandTransform, ReportByExceptionTransform};
+use crate::gateway::ingress::PeerIdentity;
+use crate::protocols::{
+    CommonMessage, HEADER_GATEWAY, HEADER_TRACE_ID,
+};
+use crate::utils::generate_unique_id;
 
 /// Message transformer that applies transformations to messages during protocol translation
 pub struct Transformer {
     // Custom transformation modules could be registered here
     transform_modules: HashMap<String, Box<dyn TransformModule>>,
+    
+    /// Rule each module was last applied under, for messages emitted on tick
+    module_rules: Mutex<HashMap<String, TranslationRule>>,
+}
+
+/// Message emitted by a transform module
+#[derive(Debug, Clone)]
+pub struct TransformOutput {
+    pub message: CommonMessage,
+    
+    /// Identities that authenticated the inbound messages whose contents it
+    /// carries, without repeats; `None` stands for unauthenticated messages
+    pub senders: Vec<Option<PeerIdentity>>,
+}
+
+impl TransformOutput {
+    /// Output carrying the contents of a single message from `sender`
+    pub fn new(message: CommonMessage, sender: Option<&PeerIdentity>) -> Self {
+        Self {
+            message,
+            senders: vec![sender.cloned()],
+        }
+    }
+    
+    /// Output combining the contents of messages from several senders
+    pub fn combined<'a>(message: CommonMessage, senders: impl IntoIterator<Item = Option<&'a PeerIdentity>>) -> Self {
+        let mut output = Self { message, senders: Vec::new() };
+        
+        for sender in senders {
+            if !output.senders.iter().any(|seen| seen.as_ref() == sender) {
+                output.senders.push(sender.cloned());
+            }
+        }
+        
+        output
+    }
 }
 
 /// Trait for custom transform modules
+///
+/// Modules are given the identity that authenticated each message, and
+/// keep it with any state they later emit messages from, so that every
+/// output can be authorized for all of the senders it carries contents of.
 pub trait TransformModule: Send + Sync {
-    fn transform(&self, message: &CommonMessage) -> Result<CommonMessage>;
+    /// Transform a message from `sender` into zero or more messages to forward
+    ///
+    /// Stateful modules may suppress a message (returning nothing), combine
+    /// several messages into one, or split one message into several.
+    fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>) -> Result<Vec<TransformOutput>>;
+    
+    /// Emit messages that are due without a new inbound message, such as
+    /// heartbeats; `now` is in milliseconds since the Unix epoch
+    fn tick(&self, _now: u64) -> Result<Vec<TransformOutput>> {
+        Ok(Vec::new())
+    }
+    
     fn name(&self) -> &str;
 }
 
+impl Default for Transformer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Transformer {
     /// Create a new transformer
     pub fn new() -> Self {
@@ -29,17 +93,65 @@ impl Transformer {
         
         Self {
             transform_modules,
+            module_rules: Mutex::new(HashMap::new()),
         }
     }
     
+    /// Create a transformer with the modules declared in configuration
+    pub fn from_config(config: &Config) -> Self {
+        let mut transformer = Self::new();
+        
+        for module in &config.transform_modules {
+            let boxed: Box<dyn TransformModule> = match &module.kind {
+                TransformModuleKind::ReportByException { heartbeat_ms } => {
+                    Box::new(ReportByExceptionTransform::new(&module.name, *heartbeat_ms))
+                },
+                TransformModuleKind::Deadband { offset, field, deadband, heartbeat_ms } => {
+                    Box::new(DeadbandTransform::new(&module.name, *offset, *field, *deadband, *heartbeat_ms))
+                },
+                TransformModuleKind::Aggregate { members, policy, destination_address } => {
+                    Box::new(AggregateTransform::new(&module.name, members.clone(), *policy, destination_address))
+                },
+                TransformModuleKind::Split { destination_address, subaddresses, max_words } => {
+                    Box::new(SplitTransform::new(&module.name, destination_address, subaddresses.clone(), *max_words))
+                },
+                TransformModuleKind::HeaderEnrichment { fields, gateway_name, trace_id } => {
+                    let mut enrichment = HeaderEnrichmentTransform::new(&module.name);
+                    
+                    for (key, value) in fields {
+                        enrichment.add_field(key, value);
+                    }
+                    
+                    if *gateway_name {
+                        enrichment.add_field(HEADER_GATEWAY, &config.general.name);
+                    }
+                    
+                    if *trace_id {
+                        enrichment.enable_trace_id();
+                    }
+                    
+                    Box::new(enrichment)
+                },
+            };
+            
+            transformer.register_module(boxed);
+        }
+        
+        transformer
+    }
+    
     /// Register a custom transformation module
     pub fn register_module(&mut self, module: Box<dyn TransformModule>) {
         let name = module.name().to_string();
         self.transform_modules.insert(name, module);
     }
     
-    /// Apply a transformation to a message based on a rule
-    pub fn transform(&self, message: &CommonMessage, rule: &TranslationRule) -> Result<CommonMessage> {
+    /// Apply a transformation to a message from `sender` based on a rule
+    ///
+    /// Returns the messages to forward, which may be empty if a stateful
+    /// module suppressed or buffered the message.
+    pub fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>, rule: &TranslationRule)
+        -> Result<Vec<TransformOutput>> {
         // Start with a clone of the original message
         let mut transformed = message.clone();
         
@@ -54,7 +166,18 @@ impl Transformer {
                 },
                 
                 TransformType::Custom(module_name) => {
-                    transformed = self.apply_custom_transform(&transformed, module_name)?;
+                    let mut outputs = self.apply_custom_transform(&transformed, sender, module_name)?;
+                    self.module_rules.lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock on transform module rules"))?
+                        .insert(module_name.clone(), rule.clone());
+                    
+                    // Modules may build new messages, so re-apply the rule's target
+                    for output in &mut outputs {
+                        output.message.target_protocol = Some(rule.target);
+                    }
+                    
+                    debug!("Transform module {} produced {} message(s)", module_name, outputs.len());
+                    return Ok(outputs);
                 },
                 
                 TransformType::Identity => {
@@ -67,7 +190,30 @@ impl Transformer {
         debug!("Message transformed from {} to {}", 
             message.source_protocol, rule.target);
             
-        Ok(transformed)
+        Ok(vec![TransformOutput::new(transformed, sender)])
+    }
+    
+    /// Collect the messages modules emit without a new inbound message
+    ///
+    /// Each message is returned with the rule its module was last applied
+    /// under; modules that have not been applied yet are skipped.
+    pub fn tick(&self, now: u64) -> Result<Vec<(TranslationRule, TransformOutput)>> {
+        let module_rules = self.module_rules.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on transform module rules"))?;
+        
+        let mut outputs = Vec::new();
+        for (module_name, rule) in module_rules.iter() {
+            let Some(module) = self.transform_modules.get(module_name) else {
+                continue;
+            };
+            
+            for mut output in module.tick(now)? {
+                output.message.target_protocol = Some(rule.target);
+                outputs.push((rule.clone(), output));
+            }
+        }
+        
+        Ok(outputs)
     }
     
     /// Apply a field mapping transformation
@@ -88,7 +234,8 @@ impl Transformer {
     }
     
     /// Apply a custom transformation
-    fn apply_custom_transform(&self, message: &CommonMessage, module_name: &str) -> Result<CommonMessage> {
+    fn apply_custom_transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>, module_name: &str)
+        -> Result<Vec<TransformOutput>> {
         debug!("Applying custom transformation: {}", module_name);
         
         // Look up the transform module
@@ -96,14 +243,18 @@ impl Transformer {
             .ok_or_else(|| anyhow!("Transform module not found: {}", module_name))?;
             
         // Apply the transformation
-        module.transform(message)
+        module.transform(message, sender)
     }
 }
 
-/// Example custom transformation module
+/// Custom transformation module that writes headers into message metadata
+///
+/// The module runs after routing, so its headers cannot be matched by rule
+/// filters. The `ingress-interface` header is recorded by the ingress stage.
 pub struct HeaderEnrichmentTransform {
     name: String,
     enrichment_fields: HashMap<String, String>,
+    add_trace_id: bool,
 }
 
 impl HeaderEnrichmentTransform {
@@ -111,26 +262,38 @@ impl HeaderEnrichmentTransform {
         Self {
             name: name.to_string(),
             enrichment_fields: HashMap::new(),
+            add_trace_id: false,
         }
     }
     
+    /// Add a fixed header, overwriting any existing value
     pub fn add_field(&mut self, key: &str, value: &str) {
         self.enrichment_fields.insert(key.to_string(), value.to_string());
     }
+    
+    /// Assign a trace ID to messages that do not already carry one
+    pub fn enable_trace_id(&mut self) {
+        self.add_trace_id = true;
+    }
 }
 
 impl TransformModule for HeaderEnrichmentTransform {
-    fn transform(&self, message: &CommonMessage) -> Result<CommonMessage> {
+    fn transform(&self, message: &CommonMessage, sender: Option<&PeerIdentity>) -> Result<Vec<TransformOutput>> {
         // Create a copy of the message
         let mut transformed = message.clone();
+        let headers = &mut transformed.metadata.headers;
         
-        // For a real implementation, we would add fields to the message's
-        // metadata based on the enrichment fields
+        for (key, value) in &self.enrichment_fields {
+            headers.insert(key.clone(), value.clone());
+        }
         
-        // Just a placeholder for demonstration purposes
-        transformed.priority = message.priority.saturating_add(1);
+        // Keep a trace ID set further upstream
+        if self.add_trace_id {
+            headers.entry(HEADER_TRACE_ID.to_string())
+                .or_insert_with(|| format!("{:016x}", generate_unique_id()));
+        }
         
-        Ok(transformed)
+        Ok(vec![TransformOutput::new(transformed, sender)])
     }
     
     fn name(&self) -> &str {
@@ -141,7 +304,8 @@ impl TransformModule for HeaderEnrichmentTransform {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{TransformType, TranslationRule};
+    use std::collections::BTreeMap;
+    use crate::config::{TransformModuleConfig, TransformType, TranslationRule};
     use crate::protocols::{CommonMessage, MessageMetadata, ProtocolType};
     use crate::security::SecurityMode;
     
@@ -159,6 +323,8 @@ mod tests {
                 message_id: 67890,
                 is_command: true,
                 requires_response: true,
+                subaddress: None,
+                headers: BTreeMap::new(),
             },
         }
     }
@@ -173,6 +339,7 @@ mod tests {
             filter: HashMap::new(),
             transform,
             security_mode: SecurityMode::EncryptedAndSigned,
+            egress_link: None,
         }
     }
     
@@ -182,7 +349,7 @@ mod tests {
         let message = create_test_message();
         let rule = create_test_rule(Some(TransformType::Identity));
         
-        let result = transformer.transform(&message, &rule).unwrap();
+        let result = transformer.transform(&message, None, &rule).unwrap().remove(0).message;
         
         // Identity transform should keep the same payload and metadata
         assert_eq!(result.payload, message.payload);
@@ -204,7 +371,7 @@ mod tests {
         
         let rule = create_test_rule(Some(TransformType::FieldMap(field_map)));
         
-        let result = transformer.transform(&message, &rule).unwrap();
+        let result = transformer.transform(&message, None, &rule).unwrap().remove(0).message;
         
         // Priority should be updated
         assert_eq!(result.priority, 10);
@@ -220,16 +387,67 @@ mod tests {
         // Register a custom transformation module
         let mut enrichment = HeaderEnrichmentTransform::new("test-enrichment");
         enrichment.add_field("source", "enriched-source");
+        enrichment.enable_trace_id();
         
         transformer.register_module(Box::new(enrichment));
         
-        let message = create_test_message();
+        let mut message = create_test_message();
+        message.metadata.headers.insert(HEADER_TRACE_ID.to_string(), "upstream-trace".to_string());
         let rule = create_test_rule(Some(TransformType::Custom("test-enrichment".to_string())));
         
-        let result = transformer.transform(&message, &rule).unwrap();
+        let result = transformer.transform(&message, None, &rule).unwrap().remove(0).message;
+        
+        // Headers should be written by the custom transform
+        assert_eq!(result.metadata.header("source"), Some("enriched-source"));
+        
+        // An existing trace ID is preserved
+        assert_eq!(result.metadata.header(HEADER_TRACE_ID), Some("upstream-trace"));
+        assert_eq!(result.priority, message.priority);
+        
+        // Headers survive serialization
+        let bytes = bincode::serialize(&result).unwrap();
+        let decoded: CommonMessage = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.metadata.headers, result.metadata.headers);
+    }
+    
+    #[test]
+    fn test_configured_report_by_exception() {
+        let mut config = Config::default();
+        config.transform_modules.push(TransformModuleConfig {
+            name: "rbe".to_string(),
+            kind: TransformModuleKind::ReportByException { heartbeat_ms: None },
+        });
+        let transformer = Transformer::from_config(&config);
+        
+        let message = create_test_message();
+        let rule = create_test_rule(Some(TransformType::Custom("rbe".to_string())));
+        
+        // First message passes, an identical repeat is suppressed
+        assert_eq!(transformer.transform(&message, None, &rule).unwrap().len(), 1);
+        assert!(transformer.transform(&message, None, &rule).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_tick_heartbeat() {
+        let mut config = Config::default();
+        config.transform_modules.push(TransformModuleConfig {
+            name: "rbe".to_string(),
+            kind: TransformModuleKind::ReportByException { heartbeat_ms: Some(1000) },
+        });
+        let transformer = Transformer::from_config(&config);
+        
+        // Modules are not ticked before a rule has used them
+        let message = create_test_message();
+        assert!(transformer.tick(message.metadata.timestamp + 1000).unwrap().is_empty());
+        
+        let rule = create_test_rule(Some(TransformType::Custom("rbe".to_string())));
+        assert_eq!(transformer.transform(&message, None, &rule).unwrap().len(), 1);
         
-        // Priority should be incremented by the custom transform
-        assert_eq!(result.priority, message.priority + 1);
+        let outputs = transformer.tick(message.metadata.timestamp + 1000).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].0.name, rule.name);
+        assert_eq!(outputs[0].1.message.payload, message.payload);
+        assert_eq!(outputs[0].1.message.target_protocol, Some(rule.target));
     }
     
     #[test]
@@ -241,6 +459,6 @@ mod tests {
         let rule = create_test_rule(Some(TransformType::Custom("non-existent".to_string())));
         
         // Should fail because the module doesn't exist
-        assert!(transformer.transform(&message, &rule).is_err());
+        assert!(transformer.transform(&message, None, &rule).is_err());
     }
 } 
\ No newline at end of file
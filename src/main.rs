@@ -16,7 +16,7 @@ async fn main() -> Result<()> {
     let config = Config::load()?;
     
     // Create gateway
-    let mut gateway = Gateway::new(config);
+    let mut gateway = Gateway::new(config)?;
     
     // Start the gateway in a separate task
     let gateway_handle = tokio::spawn(async move {
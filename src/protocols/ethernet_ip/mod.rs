@@ -6,14 +6,15 @@
 mod parser;
 
 use anyhow::{Context, Result};
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use log::debug;
+use bytes::{BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::protocols::{CommonMessage, Message, MessageMetadata, ProtocolHandler, ProtocolType};
+use crate::protocols::{CommonMessage, Message, MessageMetadata, ProtocolHandler, ProtocolType,
+    HEADER_CIP_COMMAND};
 use parser::parse_ethernet_ip;
 
 /// EtherNet/IP command types
@@ -79,6 +80,7 @@ pub struct EthernetIpPacket {
 }
 
 impl EthernetIpPacket {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         command: CommandType,
         session_handle: u32,
@@ -180,6 +182,8 @@ impl Message for EthernetIpPacket {
             message_id: self.generate_message_id(),
             is_command,
             requires_response,
+            subaddress: None,
+            headers: BTreeMap::from([(HEADER_CIP_COMMAND.to_string(), self.command.as_u8().to_string())]),
         };
         
         // Create common message
@@ -219,6 +223,7 @@ impl EthernetIpHandler {
     }
     
     // Generate a new session handle
+    #[allow(dead_code)]
     fn next_session(&mut self) -> u32 {
         let session = self.session_counter;
         self.session_counter += 1;
@@ -229,6 +234,12 @@ impl EthernetIpHandler {
     }
 }
 
+impl Default for EthernetIpHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProtocolHandler for EthernetIpHandler {
     fn parse(&self, data: &[u8]) -> Result<Box<dyn Message>> {
         parse_ethernet_ip(data)
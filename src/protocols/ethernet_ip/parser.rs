@@ -3,7 +3,7 @@
 //! This module contains functions for parsing raw bytes into
 //! Ethernet/IP packet structures.
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Result};
 use bytes::{Buf, Bytes};
 use log::debug;
 
@@ -58,6 +58,7 @@ pub fn parse_ethernet_ip(data: &[u8]) -> Result<EthernetIpPacket> {
 }
 
 /// Validate Ethernet/IP packet
+#[allow(dead_code)]
 pub fn validate_ethernet_ip(data: &[u8]) -> bool {
     if data.len() < 24 {
         return false;
@@ -77,7 +78,7 @@ pub fn validate_ethernet_ip(data: &[u8]) -> bool {
     
     // Check length field (should be reasonable)
     let length = bytes.get_u16() as usize;
-    if length < 24 || length > 1500 { // 1500 is typical MTU
+    if !(24..=1500).contains(&length) { // 1500 is typical MTU
         return false;
     }
     
@@ -6,15 +6,20 @@
 mod parser;
 
 use anyhow::{Context, Result};
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use log::{debug, warn};
+use bytes::{BufMut, BytesMut};
+use log::warn;
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::protocols::{CommonMessage, Message, MessageMetadata, ProtocolHandler, ProtocolType};
+use crate::protocols::{CommonMessage, Message, MessageMetadata, ProtocolHandler, ProtocolType,
+    HEADER_MODE_CODE};
 use parser::parse_mil_std_1553;
 
+/// Maximum number of data words in a single MIL-STD-1553 message
+pub const MAX_DATA_WORDS: usize = 32;
+
 /// Word type for MIL-STD-1553
 #[derive(Debug, Clone, Copy)]
 pub struct Word(u16);
@@ -160,6 +165,12 @@ impl Message for Mil1553Message {
             payload.extend_from_slice(&word.value().to_be_bytes());
         }
         
+        // Mode code commands carry the code in the word count field
+        let mut headers = BTreeMap::new();
+        if self.message_type == MessageType::ModeCode {
+            headers.insert(HEADER_MODE_CODE.to_string(), self.word_count.to_string());
+        }
+        
         // Create message metadata
         let metadata = MessageMetadata {
             source_address: source_addr,
@@ -168,6 +179,8 @@ impl Message for Mil1553Message {
             message_id: self.generate_message_id(),
             is_command: matches!(self.message_type, MessageType::BcToRt | MessageType::ModeCode),
             requires_response: self.message_type != MessageType::RtToBc,
+            subaddress: Some(self.subaddress),
+            headers,
         };
         
         // Create common message
@@ -204,6 +217,12 @@ impl Mil1553Handler {
     }
 }
 
+impl Default for Mil1553Handler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProtocolHandler for Mil1553Handler {
     fn parse(&self, data: &[u8]) -> Result<Box<dyn Message>> {
         parse_mil_std_1553(data)
@@ -218,7 +237,9 @@ impl ProtocolHandler for Mil1553Handler {
             .and_then(|s| s.parse::<u8>().ok())
             .unwrap_or(1);  // Default to RT1 if parsing fails
             
-        let subaddress = 1; // Use a default subaddress
+        let subaddress = message.metadata.subaddress
+            .filter(|sa| *sa <= 0x1F)
+            .unwrap_or(1);  // Default to SA1 if not provided
         
         // Create data words from payload
         let mut data_words = Vec::new();
@@ -238,8 +259,15 @@ impl ProtocolHandler for Mil1553Handler {
             data_words.push(Word::new(value));
         }
         
-        // Ensure word count is valid (1-32)
-        let word_count = data_words.len().min(32) as u8;
+        // Ensure word count is valid (1-32); larger payloads must be split upstream
+        if data_words.len() > MAX_DATA_WORDS {
+            warn!("Payload of {} words exceeds MIL-STD-1553 limit, truncating to {}", 
+                data_words.len(), MAX_DATA_WORDS);
+            data_words.truncate(MAX_DATA_WORDS);
+        }
+        
+        // A word count of 32 is encoded as 0
+        let word_count = (data_words.len() as u8) & 0x1F;
         
         // Construct command word: [RT addr(5)][T/R(1)][subaddr(5)][word count(5)]
         // T/R bit: 1 for RT->BC (receive), 0 for BC->RT (transmit)
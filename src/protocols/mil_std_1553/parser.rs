@@ -3,18 +3,11 @@
 //! This module contains functions for parsing raw bytes into
 //! MIL-STD-1553 message structures.
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Result};
 use bytes::{Buf, Bytes};
 use log::debug;
-use nom::{
-    bytes::complete::take,
-    combinator::{map, verify},
-    number::complete::{be_u16, be_u8},
-    sequence::tuple,
-    IResult,
-};
 
-use super::{MessageType, Mil1553Message, Word};
+use super::{MessageType, Mil1553Message, Word, MAX_DATA_WORDS};
 
 /// Parse a MIL-STD-1553 message from raw bytes
 pub fn parse_mil_std_1553(data: &[u8]) -> Result<Mil1553Message> {
@@ -29,10 +22,8 @@ pub fn parse_mil_std_1553(data: &[u8]) -> Result<Mil1553Message> {
     
     // Extract fields from command word
     let cmd = command_word.value();
-    let rt_addr = ((cmd >> 11) & 0x1F) as u8;
     let tr_bit = ((cmd >> 10) & 0x1) as u8;
     let subaddress = ((cmd >> 5) & 0x1F) as u8;
-    let word_count = (cmd & 0x1F) as u8;
     
     // Determine message type based on command word
     let message_type = if subaddress == 0 {
@@ -51,7 +42,7 @@ pub fn parse_mil_std_1553(data: &[u8]) -> Result<Mil1553Message> {
     
     // Read data words
     let mut data_words = Vec::new();
-    while bytes.remaining() >= 2 && data_words.len() < 32 {
+    while bytes.remaining() >= 2 && data_words.len() < MAX_DATA_WORDS {
         data_words.push(Word::new(bytes.get_u16()));
     }
     
@@ -68,6 +59,7 @@ pub fn parse_mil_std_1553(data: &[u8]) -> Result<Mil1553Message> {
 }
 
 /// Validate a command word
+#[allow(dead_code)]
 fn validate_command_word(word: u16) -> bool {
     // RT address should be 0-31
     let rt_addr = (word >> 11) & 0x1F;
@@ -86,14 +78,15 @@ fn validate_command_word(word: u16) -> bool {
 }
 
 /// Validate a status word
+#[allow(dead_code)]
 fn validate_status_word(word: u16) -> bool {
     // RT address should be 0-31
     let rt_addr = (word >> 11) & 0x1F;
     
     // Various status bits
     let message_error = (word >> 9) & 0x1;
-    let instrumentation = (word >> 8) & 0x1;
-    let service_request = (word >> 7) & 0x1;
+    let _instrumentation = (word >> 8) & 0x1;
+    let _service_request = (word >> 7) & 0x1;
     
     // Typically, certain bits should be 0 in normal operation
     rt_addr <= 0x1F && message_error == 0
@@ -104,6 +97,7 @@ mod tests {
     use super::*;
     
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_parse_bc_to_rt() {
         // Command word: RT5, receive, subaddress 2, 3 data words
         // RT5 (5 << 11) | T/R=0 (0 << 10) | SA2 (2 << 5) | WC=3
@@ -9,6 +9,7 @@ pub mod mil_std_1553;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 
 /// Common trait for all protocol messages
@@ -61,6 +62,37 @@ pub struct MessageMetadata {
     pub message_id: u64,
     pub is_command: bool,
     pub requires_response: bool,
+    /// MIL-STD-1553 subaddress, if the message originated on or targets a 1553 bus
+    #[serde(default)]
+    pub subaddress: Option<u8>,
+    /// Extensible key/value headers (ordered so that serialization is deterministic)
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Header carrying the name of the gateway that handled a message
+pub const HEADER_GATEWAY: &str = "gateway";
+
+/// Header carrying the interface a message entered the gateway on
+pub const HEADER_INGRESS_INTERFACE: &str = "ingress-interface";
+
+/// Header carrying an end-to-end trace identifier
+pub const HEADER_TRACE_ID: &str = "trace-id";
+
+/// Header naming the peer gateway a message is bound for
+pub const HEADER_PEER: &str = "peer";
+
+/// Header carrying the MIL-STD-1553 mode code of a mode code command
+pub const HEADER_MODE_CODE: &str = "mode-code";
+
+/// Header carrying the EtherNet/IP encapsulation command code
+pub const HEADER_CIP_COMMAND: &str = "cip-command";
+
+impl MessageMetadata {
+    /// Get a header value by name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
 }
 
 /// A trait for protocol parsers and formatters
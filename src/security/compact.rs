@@ -0,0 +1,345 @@
+//! Compact envelope for small-payload links
+//!
+//! A bincode `SecuredMessage` carries strings, length prefixes, a random
+//! nonce and a full signature, which leaves no room for data in a 64-byte
+//! MIL-STD-1553 message. The compact envelope replaces them with a fixed
+//! 17-byte header and a truncated authentication tag:
+//!
+//! ```text
+//! version | mode | key index | key version (u16) | sequence (u64) | timestamp (u32) | payload | tag
+//! ```
+//!
+//! The envelope is enabled per link in configuration rather than negotiated:
+//! both ends must be configured with the same key ring behind each index,
+//! peer identity and tag length. The nonce is derived from the sender and
+//! sequence number, which must never repeat for a key ring; configure
+//! `sequence_state_path` so that restarts after the clock is set back cannot
+//! reuse one. The timestamp carries the low 32 bits of milliseconds since
+//! the Unix epoch. The first byte is the header version, which never matches
+//! the first byte of a full envelope, so both formats can share a link.
+//! Signatures do not fit and are not supported; `SecurityMode::Authenticated`
+//! appends a MAC truncated to the link's tag length instead.
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use crate::security::{SecurityError, SecurityHeader, SecurityMode, SecurityService};
+use crate::security::crypto::{CipherSuite, NONCE_SIZE, TAG_SIZE};
+use crate::security::key_ring::KeyRef;
+use crate::utils::current_time_millis;
+
+/// Header version identifying a compact envelope
+pub const COMPACT_HEADER_VERSION: u8 = 0x81;
+
+/// Size of the compact header in bytes
+pub const COMPACT_HEADER_LEN: usize = 17;
+
+/// Shortest authentication tag accepted on a compact link
+pub const MIN_COMPACT_TAG_LEN: usize = 8;
+
+/// Default authentication tag length of a compact link
+pub const DEFAULT_COMPACT_TAG_LEN: usize = 8;
+
+/// Settings shared by both ends of a link using the compact envelope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactLink {
+    /// Identity of the gateway at the other end, the sender of inbound messages
+    pub peer: String,
+    
+    /// Key ring names, addressed by their index
+    pub keys: Vec<String>,
+    
+    /// Length of the truncated authentication tag in bytes
+    pub tag_len: usize,
+}
+
+impl CompactLink {
+    pub fn new(peer: &str, keys: Vec<String>, tag_len: usize) -> Result<Self> {
+        if keys.is_empty() || keys.len() > u8::MAX as usize + 1 {
+            return Err(anyhow!(SecurityError::ConfigError("A compact link needs 1 to 256 keys".into())));
+        }
+        
+        if !(MIN_COMPACT_TAG_LEN..=TAG_SIZE).contains(&tag_len) {
+            return Err(anyhow!(SecurityError::ConfigError(format!(
+                "Compact tag length must be between {} and {} bytes", MIN_COMPACT_TAG_LEN, TAG_SIZE
+            ))));
+        }
+        
+        Ok(Self {
+            peer: peer.to_string(),
+            keys,
+            tag_len,
+        })
+    }
+    
+    /// Envelope bytes added to a payload secured with `mode`
+    pub fn overhead(&self, mode: SecurityMode) -> usize {
+        match mode {
+            SecurityMode::None => COMPACT_HEADER_LEN,
+            _ => COMPACT_HEADER_LEN + self.tag_len,
+        }
+    }
+}
+
+/// Nonce for a sender's message: a sender tag followed by the sequence number
+fn compact_nonce(sender: &str, sequence: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..4].copy_from_slice(&Sha256::digest(sender.as_bytes())[..4]);
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+/// Recover a full timestamp from its low 32 bits, taking the value nearest `now`
+fn expand_timestamp(low: u32, now: u64) -> u64 {
+    const SPAN: u64 = 1 << 32;
+    
+    let candidate = (now & !(SPAN - 1)) | low as u64;
+    if candidate > now && candidate - now > SPAN / 2 {
+        candidate.saturating_sub(SPAN)
+    } else if now > candidate && now - candidate > SPAN / 2 {
+        candidate + SPAN
+    } else {
+        candidate
+    }
+}
+
+impl SecurityService {
+    /// Check whether bytes look like a compact envelope
+    pub fn is_compact_envelope(data: &[u8]) -> bool {
+        data.len() >= COMPACT_HEADER_LEN && data[0] == COMPACT_HEADER_VERSION
+    }
+    
+    /// Security mode claimed by a compact envelope, before authentication
+    pub fn compact_mode(data: &[u8]) -> Result<SecurityMode> {
+        if !Self::is_compact_envelope(data) {
+            return Err(anyhow!(SecurityError::AuthenticationFailed("Not a compact envelope".into())));
+        }
+        
+        SecurityMode::from_u8(data[1])
+            .ok_or_else(|| anyhow!(SecurityError::AuthenticationFailed(format!("Unknown security mode: {}", data[1]))))
+    }
+    
+    /// Key ring named by a compact envelope's key index, before authentication
+    pub fn compact_key<'a>(data: &[u8], link: &'a CompactLink) -> Option<&'a str> {
+        if !Self::is_compact_envelope(data) {
+            return None;
+        }
+        
+        link.keys.get(data[2] as usize).map(String::as_str)
+    }
+    
+    /// Secure a payload in a compact envelope for `link`
+    ///
+    /// The primary version of the `key_name` key ring is used; the ring must
+    /// be one of the link's keys and `mode` must not require a signature.
+    pub fn secure_compact(&self, data: &[u8], mode: SecurityMode, key_name: &str,
+                          link: &CompactLink) -> Result<Vec<u8>> {
+        if mode.is_signed() {
+            return Err(anyhow!(SecurityError::ConfigError(
+                format!("{:?} messages do not fit the compact envelope", mode)
+            )));
+        }
+        
+        let index = link.keys.iter().position(|name| name == key_name)
+            .ok_or_else(|| anyhow!(SecurityError::KeyError(
+                format!("Key ring {} is not configured for compact link to {}", key_name, link.peer)
+            )))?;
+        
+        let key = if mode.uses_symmetric_key() {
+            self.key_manager.primary_key(key_name, true, false)?
+        } else {
+            KeyRef::new(key_name, 0)
+        };
+        let version = u16::try_from(key.version)
+            .map_err(|_| anyhow!(SecurityError::KeyError(format!("{} does not fit the compact envelope", key))))?;
+        
+        let header = SecurityHeader {
+            version: COMPACT_HEADER_VERSION,
+            mode,
+            suite: CipherSuite::ChaCha20Poly1305,
+            sequence: self.sequences.next(key_name)?,
+            key,
+            signing_key: None,
+            sender: self.sender_id.clone(),
+            timestamp: current_time_millis(),
+            nonce: vec![],
+            signature: None,
+        };
+        
+        let mut envelope = Vec::with_capacity(link.overhead(mode) + data.len());
+        envelope.push(COMPACT_HEADER_VERSION);
+        envelope.push(mode.as_u8());
+        envelope.push(index as u8);
+        envelope.extend_from_slice(&version.to_be_bytes());
+        envelope.extend_from_slice(&header.sequence.to_be_bytes());
+        envelope.extend_from_slice(&(header.timestamp as u32).to_be_bytes());
+        
+        match mode {
+            SecurityMode::Encrypted => {
+                let nonce = compact_nonce(&header.sender, header.sequence);
+                envelope.extend_from_slice(&self.key_manager.seal(
+                    &header.key.encryption_id(),
+                    &nonce,
+                    data,
+                    &header.authenticated_data(),
+                    link.tag_len,
+                )?);
+            },
+            SecurityMode::Authenticated => {
+                envelope.extend_from_slice(data);
+                envelope.extend_from_slice(&self.key_manager.mac(
+                    &header.key.encryption_id(),
+                    &header.mac_content(link.tag_len, data),
+                    link.tag_len,
+                )?);
+            },
+            _ => envelope.extend_from_slice(data),
+        }
+        
+        Ok(envelope)
+    }
+    
+    /// Extract the payload of a compact envelope received over `link`
+    ///
+    /// Replay protection applies as for full envelopes, with the link's peer
+    /// as the sender.
+    pub fn extract_compact(&self, data: &[u8], link: &CompactLink) -> Result<Vec<u8>> {
+        let mode = Self::compact_mode(data)?;
+        
+        let key_name = link.keys.get(data[2] as usize)
+            .ok_or_else(|| anyhow!(SecurityError::KeyError(format!("Unknown compact key index: {}", data[2]))))?;
+        let version = u16::from_be_bytes([data[3], data[4]]) as u32;
+        let sequence = u64::from_be_bytes(data[5..13].try_into().expect("slice of 8 bytes"));
+        let timestamp_low = u32::from_be_bytes(data[13..17].try_into().expect("slice of 4 bytes"));
+        
+        let header = SecurityHeader {
+            version: COMPACT_HEADER_VERSION,
+            mode,
+            suite: CipherSuite::ChaCha20Poly1305,
+            key: KeyRef::new(key_name, version),
+            signing_key: None,
+            sender: link.peer.clone(),
+            sequence,
+            timestamp: expand_timestamp(timestamp_low, current_time_millis()),
+            nonce: vec![],
+            signature: None,
+        };
+        
+        // Reject obvious replays before doing any cryptographic work
+        self.replay_guard.check(&header)?;
+        let body = &data[COMPACT_HEADER_LEN..];
+        
+        let plaintext = match mode {
+            SecurityMode::None => body.to_vec(),
+            
+            SecurityMode::Encrypted => self.key_manager.open(
+                &header.key.encryption_id(),
+                &compact_nonce(&header.sender, header.sequence),
+                body,
+                &header.authenticated_data(),
+                link.tag_len,
+            )?,
+            
+            SecurityMode::Authenticated => {
+                if body.len() < link.tag_len {
+                    return Err(anyhow!(SecurityError::AuthenticationFailed("Missing MAC".into())));
+                }
+                
+                let (payload, tag) = body.split_at(body.len() - link.tag_len);
+                self.key_manager.verify_mac(
+                    &header.key.encryption_id(),
+                    &header.mac_content(link.tag_len, payload),
+                    tag,
+                )?;
+                payload.to_vec()
+            },
+            
+            SecurityMode::Signed | SecurityMode::EncryptedAndSigned => {
+                return Err(anyhow!(SecurityError::AuthenticationFailed(
+                    format!("{:?} is not supported in compact envelopes", mode)
+                )));
+            },
+        };
+        
+        // Only record the sequence number once the message is authenticated
+        self.replay_guard.accept(&header)?;
+        
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::mil_std_1553::MAX_DATA_WORDS;
+    use crate::security::key_manager::{KeyManager, KeyUsage};
+    use crate::security::key_ring::RingMaterial;
+    
+    fn create_pair() -> (SecurityService, SecurityService, CompactLink, CompactLink) {
+        let km_a = KeyManager::new();
+        km_a.add_ring_version("bus-a", RingMaterial::Encryption, "Bus A").unwrap();
+        let key = km_a.get_encryption_key("bus-a/v1").unwrap();
+        
+        let km_b = KeyManager::new();
+        km_b.install_session_key("bus-a/v1", &key, "Bus A").unwrap();
+        
+        let mut a = SecurityService::new(km_a);
+        a.set_sender_id("gw-a");
+        let mut b = SecurityService::new(km_b);
+        b.set_sender_id("gw-b");
+        
+        let keys = vec!["spare".to_string(), "bus-a".to_string()];
+        (a, b, CompactLink::new("gw-b", keys.clone(), 8).unwrap(), CompactLink::new("gw-a", keys, 8).unwrap())
+    }
+    
+    #[test]
+    fn test_compact_roundtrip() {
+        let (a, b, to_b, from_a) = create_pair();
+        
+        // 38 bytes of data plus 25 bytes of overhead fit in one 64-byte 1553 message
+        let payload = [0x5Au8; 38];
+        let envelope = a.secure_compact(&payload, SecurityMode::Encrypted, "bus-a", &to_b).unwrap();
+        assert_eq!(envelope.len(), payload.len() + to_b.overhead(SecurityMode::Encrypted));
+        assert!(envelope.len() <= MAX_DATA_WORDS * 2);
+        assert_eq!(envelope[2], 1);
+        assert!(SecurityService::is_compact_envelope(&envelope));
+        assert!(!SecurityService::is_envelope(&envelope));
+        
+        assert_eq!(b.extract_compact(&envelope, &from_a).unwrap(), payload);
+        
+        // Replays, tampering and a wrong peer are rejected
+        assert!(b.extract_compact(&envelope, &from_a).is_err());
+        
+        let mut tampered = a.secure_compact(&payload, SecurityMode::Encrypted, "bus-a", &to_b).unwrap();
+        tampered[20] ^= 1;
+        assert!(b.extract_compact(&tampered, &from_a).is_err());
+        
+        let fresh = a.secure_compact(&payload, SecurityMode::Encrypted, "bus-a", &to_b).unwrap();
+        let wrong_peer = CompactLink::new("gw-c", from_a.keys.clone(), 8).unwrap();
+        assert!(b.extract_compact(&fresh, &wrong_peer).is_err());
+        assert!(b.extract_compact(&fresh, &from_a).is_ok());
+        
+        // MAC-only telemetry leaves the payload readable
+        let telemetry = a.secure_compact(b"alt=1200", SecurityMode::Authenticated, "bus-a", &to_b).unwrap();
+        assert_eq!(&telemetry[COMPACT_HEADER_LEN..COMPACT_HEADER_LEN + 8], b"alt=1200");
+        assert_eq!(b.extract_compact(&telemetry, &from_a).unwrap(), b"alt=1200");
+        
+        // Encrypted envelopes count against the key's usage limits
+        let usage = a.key_manager().key_usage("bus-a/v1").unwrap();
+        assert_eq!(usage, KeyUsage { encryptions: 3, bytes: 3 * payload.len() as u64 });
+    }
+    
+    #[test]
+    fn test_compact_restrictions() {
+        let (a, _, to_b, _) = create_pair();
+        
+        assert!(a.secure_compact(b"x", SecurityMode::Signed, "bus-a", &to_b).is_err());
+        assert!(a.secure_compact(b"x", SecurityMode::Encrypted, "other", &to_b).is_err());
+        assert!(CompactLink::new("gw-b", vec!["bus-a".to_string()], 4).is_err());
+        assert!(CompactLink::new("gw-b", vec![], 8).is_err());
+        
+        assert_eq!(to_b.overhead(SecurityMode::Encrypted), 25);
+        assert_eq!(expand_timestamp(0xFFFF_FFF0, 0x1_0000_0010), 0xFFFF_FFF0);
+        assert_eq!(expand_timestamp(0x10, 0x1_FFFF_FFF0), 0x2_0000_0010);
+    }
+}
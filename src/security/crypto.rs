@@ -3,17 +3,24 @@
 //! This module provides encryption, decryption, signature generation
 //! and verification functionality.
 
-use anyhow::{anyhow, Context, Result};
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Result};
 use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit},
-    ChaCha20Poly1305, Nonce,
+    aead::{self, Aead, AeadInPlace, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce, XChaCha20Poly1305,
 };
 use ed25519_dalek::{
-    Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey,
+    Signature, Signer, SigningKey, Verifier, VerifyingKey,
 };
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroize;
 
 use crate::security::SecurityError;
+use crate::security::secret::SecretBytes;
 
 /// ChaCha20Poly1305 key size in bytes
 pub const CHACHA_KEY_SIZE: usize = 32;
@@ -21,6 +28,18 @@ pub const CHACHA_KEY_SIZE: usize = 32;
 /// Nonce size for ChaCha20Poly1305
 pub const NONCE_SIZE: usize = 12;
 
+/// Full ChaCha20Poly1305 authentication tag size
+pub const TAG_SIZE: usize = 16;
+
+/// Full HMAC-SHA256 tag size
+pub const MAC_SIZE: usize = 32;
+
+/// Shortest MAC tag accepted
+pub const MIN_MAC_TAG_LEN: usize = 8;
+
+/// HKDF info deriving the MAC key from a symmetric key
+const MAC_KEY_INFO: &[u8] = b"secure-gateway message mac";
+
 /// Ed25519 signature size
 pub const SIGNATURE_SIZE: usize = 64;
 
@@ -30,62 +49,256 @@ pub const ED25519_PUBLIC_KEY_SIZE: usize = 32;
 /// Ed25519
 pub const ED25519_PRIVATE_KEY_SIZE: usize = 32;
 
+/// XChaCha20Poly1305 nonce size
+pub const XNONCE_SIZE: usize = 24;
+
+/// AEAD cipher suite protecting a message payload
+///
+/// The identifier is carried in the secured message header. Every suite
+/// uses a 256-bit key, so keys are independent of the suite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum CipherSuite {
+    /// ChaCha20Poly1305 with a random 96-bit nonce
+    #[default]
+    ChaCha20Poly1305,
+    
+    /// XChaCha20Poly1305 with a random 192-bit nonce, safe for any message count
+    XChaCha20Poly1305,
+    
+    /// AES-256-GCM with a random 96-bit nonce, for hardware with AES acceleration
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    /// Suites that can be used for encryption and decryption
+    pub const AVAILABLE: &'static [CipherSuite] = &[
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::XChaCha20Poly1305,
+        CipherSuite::Aes256Gcm,
+    ];
+    
+    /// Stable numeric identifier used in the authenticated header encoding
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => 1,
+            CipherSuite::XChaCha20Poly1305 => 2,
+            CipherSuite::Aes256Gcm => 3,
+        }
+    }
+    
+    /// Whether this build implements the suite
+    pub fn is_available(&self) -> bool {
+        Self::AVAILABLE.contains(self)
+    }
+    
+    /// Nonce size in bytes
+    pub fn nonce_size(&self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => XNONCE_SIZE,
+            CipherSuite::ChaCha20Poly1305 | CipherSuite::Aes256Gcm => NONCE_SIZE,
+        }
+    }
+    
+    /// Whether random nonces are short enough to limit the number of messages per key
+    pub fn has_short_nonce(&self) -> bool {
+        self.nonce_size() < XNONCE_SIZE
+    }
+}
+
 /// Encrypt a message using ChaCha20Poly1305
-pub fn encrypt_message(plaintext: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+///
+/// The associated data is authenticated but not encrypted; the same bytes
+/// must be supplied to `decrypt_message`.
+pub fn encrypt_message(plaintext: &[u8], key: &SecretBytes, associated_data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    encrypt_with_suite(CipherSuite::ChaCha20Poly1305, plaintext, key, associated_data)
+}
+
+/// Decrypt a message using ChaCha20Poly1305
+pub fn decrypt_message(ciphertext: &[u8], nonce_bytes: &[u8], key: &SecretBytes, associated_data: &[u8]) -> Result<Vec<u8>> {
+    decrypt_with_suite(CipherSuite::ChaCha20Poly1305, ciphertext, nonce_bytes, key, associated_data)
+}
+
+/// Encrypt a message with a random nonce, returning the ciphertext and nonce
+pub fn encrypt_with_suite(suite: CipherSuite, plaintext: &[u8], key: &SecretBytes,
+                          associated_data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    // Generate random nonce
+    let mut nonce_bytes = vec![0u8; suite.nonce_size()];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    
+    let ciphertext = encrypt_with_nonce(suite, plaintext, &nonce_bytes, key, associated_data)?;
+    Ok((ciphertext, nonce_bytes))
+}
+
+/// Encrypt a message with a caller-chosen nonce
+///
+/// The caller must never reuse a nonce with the same key.
+pub fn encrypt_with_nonce(suite: CipherSuite, plaintext: &[u8], nonce_bytes: &[u8], key: &SecretBytes,
+                          associated_data: &[u8]) -> Result<Vec<u8>> {
     if key.len() != CHACHA_KEY_SIZE {
         return Err(anyhow!(SecurityError::EncryptionFailed(
             format!("Invalid key size: {} (expected {})", key.len(), CHACHA_KEY_SIZE)
         )));
     }
     
-    // Create cipher instance
-    let cipher = ChaCha20Poly1305::new_from_slice(key)
-        .map_err(|e| SecurityError::EncryptionFailed(e.to_string()))?;
-    
-    // Generate random nonce
-    let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    if nonce_bytes.len() != suite.nonce_size() {
+        return Err(anyhow!(SecurityError::EncryptionFailed(
+            format!("Invalid nonce size: {} (expected {})", nonce_bytes.len(), suite.nonce_size())
+        )));
+    }
     
-    // Encrypt
-    let ciphertext = cipher
-        .encrypt(nonce, plaintext)
-        .map_err(|e| SecurityError::EncryptionFailed(e.to_string()))?;
+    let payload = Payload { msg: plaintext, aad: associated_data };
+    let ciphertext = match suite {
+        CipherSuite::ChaCha20Poly1305 => aead_encrypt::<ChaCha20Poly1305>(key, nonce_bytes, payload),
+        CipherSuite::XChaCha20Poly1305 => aead_encrypt::<XChaCha20Poly1305>(key, nonce_bytes, payload),
+        CipherSuite::Aes256Gcm => aead_encrypt::<Aes256Gcm>(key, nonce_bytes, payload),
+    }.map_err(SecurityError::EncryptionFailed)?;
     
-    Ok((ciphertext, nonce_bytes.to_vec()))
+    Ok(ciphertext)
 }
 
-/// Decrypt a message using ChaCha20Poly1305
-pub fn decrypt_message(ciphertext: &[u8], nonce_bytes: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+/// Decrypt a message encrypted with `encrypt_with_suite`
+pub fn decrypt_with_suite(suite: CipherSuite, ciphertext: &[u8], nonce_bytes: &[u8], key: &SecretBytes,
+                          associated_data: &[u8]) -> Result<Vec<u8>> {
     if key.len() != CHACHA_KEY_SIZE {
         return Err(anyhow!(SecurityError::DecryptionFailed(
             format!("Invalid key size: {} (expected {})", key.len(), CHACHA_KEY_SIZE)
         )));
     }
     
-    if nonce_bytes.len() != NONCE_SIZE {
+    if nonce_bytes.len() != suite.nonce_size() {
         return Err(anyhow!(SecurityError::DecryptionFailed(
-            format!("Invalid nonce size: {} (expected {})", nonce_bytes.len(), NONCE_SIZE)
+            format!("Invalid nonce size: {} (expected {})", nonce_bytes.len(), suite.nonce_size())
         )));
     }
     
-    // Create cipher instance
-    let cipher = ChaCha20Poly1305::new_from_slice(key)
-        .map_err(|e| SecurityError::DecryptionFailed(e.to_string()))?;
+    let payload = Payload { msg: ciphertext, aad: associated_data };
+    let plaintext = match suite {
+        CipherSuite::ChaCha20Poly1305 => aead_decrypt::<ChaCha20Poly1305>(key, nonce_bytes, payload),
+        CipherSuite::XChaCha20Poly1305 => aead_decrypt::<XChaCha20Poly1305>(key, nonce_bytes, payload),
+        CipherSuite::Aes256Gcm => aead_decrypt::<Aes256Gcm>(key, nonce_bytes, payload),
+    }.map_err(SecurityError::DecryptionFailed)?;
+    
+    Ok(plaintext)
+}
+
+fn aead_encrypt<C: KeyInit + Aead>(key: &SecretBytes, nonce_bytes: &[u8], payload: Payload) -> Result<Vec<u8>, String> {
+    C::new_from_slice(key.expose())
+        .map_err(|e| e.to_string())?
+        .encrypt(aead::Nonce::<C>::from_slice(nonce_bytes), payload)
+        .map_err(|e| e.to_string())
+}
+
+fn aead_decrypt<C: KeyInit + Aead>(key: &SecretBytes, nonce_bytes: &[u8], payload: Payload) -> Result<Vec<u8>, String> {
+    C::new_from_slice(key.expose())
+        .map_err(|e| e.to_string())?
+        .decrypt(aead::Nonce::<C>::from_slice(nonce_bytes), payload)
+        .map_err(|e| e.to_string())
+}
+
+/// Encrypt with a caller-chosen nonce, keeping the first `tag_len` bytes of the tag
+///
+/// Returns the ciphertext followed by the truncated tag. The caller must
+/// never reuse a nonce with the same key.
+pub fn encrypt_truncated(plaintext: &[u8], nonce_bytes: &[u8; NONCE_SIZE], key: &SecretBytes,
+                         associated_data: &[u8], tag_len: usize) -> Result<Vec<u8>> {
+    if tag_len == 0 || tag_len > TAG_SIZE {
+        return Err(anyhow!(SecurityError::EncryptionFailed(format!("Invalid tag length: {}", tag_len))));
+    }
+    
+    let cipher = ChaCha20Poly1305::new_from_slice(key.expose())
+        .map_err(|e| SecurityError::EncryptionFailed(e.to_string()))?;
+    
+    let mut data = plaintext.to_vec();
+    let tag = cipher
+        .encrypt_in_place_detached(Nonce::from_slice(nonce_bytes), associated_data, &mut data)
+        .map_err(|e| SecurityError::EncryptionFailed(e.to_string()))?;
+    
+    data.extend_from_slice(&tag[..tag_len]);
+    Ok(data)
+}
+
+/// Decrypt the output of `encrypt_truncated`
+pub fn decrypt_truncated(data: &[u8], nonce_bytes: &[u8; NONCE_SIZE], key: &SecretBytes,
+                         associated_data: &[u8], tag_len: usize) -> Result<Vec<u8>> {
+    if tag_len == 0 || tag_len > TAG_SIZE || data.len() < tag_len {
+        return Err(anyhow!(SecurityError::DecryptionFailed("Invalid tag length".into())));
+    }
     
-    // Create nonce
+    let cipher = ChaCha20Poly1305::new_from_slice(key.expose())
+        .map_err(|e| SecurityError::DecryptionFailed(e.to_string()))?;
     let nonce = Nonce::from_slice(nonce_bytes);
+    let (ciphertext, received_tag) = data.split_at(data.len() - tag_len);
     
-    // Decrypt
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext)
+    // The AEAD API only verifies full tags, so apply the keystream (an XOR) by
+    // encrypting, then recompute the tag over the recovered plaintext
+    let mut plaintext = ciphertext.to_vec();
+    cipher.encrypt_in_place_detached(nonce, associated_data, &mut plaintext)
         .map_err(|e| SecurityError::DecryptionFailed(e.to_string()))?;
     
+    let mut recomputed = plaintext.clone();
+    let tag = cipher.encrypt_in_place_detached(nonce, associated_data, &mut recomputed)
+        .map_err(|e| SecurityError::DecryptionFailed(e.to_string()))?;
+    
+    if !constant_time_eq(&tag[..tag_len], received_tag) {
+        plaintext.zeroize();
+        return Err(anyhow!(SecurityError::DecryptionFailed("Authentication tag mismatch".into())));
+    }
+    
     Ok(plaintext)
 }
 
+/// Derive `len` bytes of key material from a symmetric key with HKDF-SHA256
+pub fn derive_key(key: &SecretBytes, salt: Option<&[u8]>, info: &[u8], len: usize) -> Result<SecretBytes> {
+    let mut derived = SecretBytes::new(vec![0u8; len]);
+    Hkdf::<Sha256>::new(salt, key.expose())
+        .expand(info, derived.expose_mut())
+        .map_err(|e| anyhow!(SecurityError::KeyError(format!("Key derivation failed: {}", e))))?;
+    Ok(derived)
+}
+
+/// Derive the MAC key from a symmetric key, so one key never serves both AEAD and HMAC
+fn mac_key(key: &SecretBytes) -> Result<SecretBytes> {
+    let mut mac_key = SecretBytes::new(vec![0u8; MAC_SIZE]);
+    Hkdf::<Sha256>::new(None, key.expose())
+        .expand(MAC_KEY_INFO, mac_key.expose_mut())
+        .map_err(|e| SecurityError::KeyError(e.to_string()))?;
+    Ok(mac_key)
+}
+
+/// Compute an HMAC-SHA256 tag truncated to `tag_len` bytes
+pub fn compute_mac(message: &[u8], key: &SecretBytes, tag_len: usize) -> Result<Vec<u8>> {
+    if !(MIN_MAC_TAG_LEN..=MAC_SIZE).contains(&tag_len) {
+        return Err(anyhow!(SecurityError::AuthenticationFailed(format!("Invalid MAC length: {}", tag_len))));
+    }
+    
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key(key)?.expose())
+        .map_err(|e| SecurityError::AuthenticationFailed(e.to_string()))?;
+    mac.update(message);
+    
+    Ok(mac.finalize().into_bytes()[..tag_len].to_vec())
+}
+
+/// Verify a tag produced by `compute_mac`, of whatever length it has
+pub fn verify_mac(message: &[u8], tag: &[u8], key: &SecretBytes) -> Result<()> {
+    if !(MIN_MAC_TAG_LEN..=MAC_SIZE).contains(&tag.len()) {
+        return Err(anyhow!(SecurityError::AuthenticationFailed(format!("Invalid MAC length: {}", tag.len()))));
+    }
+    
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key(key)?.expose())
+        .map_err(|e| SecurityError::AuthenticationFailed(e.to_string()))?;
+    mac.update(message);
+    
+    mac.verify_truncated_left(tag)
+        .map_err(|_| anyhow!(SecurityError::AuthenticationFailed("MAC mismatch".into())))
+}
+
+/// Compare two byte strings in time independent of their contents
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Sign a message using Ed25519
-pub fn sign_message(message: &[u8], private_key: &[u8]) -> Result<Vec<u8>> {
+pub fn sign_message(message: &[u8], private_key: &SecretBytes) -> Result<Vec<u8>> {
     if private_key.len() != ED25519_PRIVATE_KEY_SIZE {
         return Err(anyhow!(SecurityError::AuthenticationFailed(
             format!("Invalid signing key size: {} (expected {})", 
@@ -94,10 +307,8 @@ pub fn sign_message(message: &[u8], private_key: &[u8]) -> Result<Vec<u8>> {
     }
     
     // Create signing key
-    let signing_key = SigningKey::from_bytes(
-        &private_key.try_into()
-            .map_err(|_| SecurityError::AuthenticationFailed("Invalid key format".into()))?
-    );
+    let signing_key = SigningKey::try_from(private_key.expose())
+        .map_err(|_| SecurityError::AuthenticationFailed("Invalid key format".into()))?;
     
     // Sign the message
     let signature = signing_key.sign(message);
@@ -140,34 +351,32 @@ pub fn verify_signature(message: &[u8], signature_bytes: &[u8], public_key: &[u8
 }
 
 /// Generate a random encryption key
-pub fn generate_encryption_key() -> [u8; CHACHA_KEY_SIZE] {
-    let mut key = [0u8; CHACHA_KEY_SIZE];
-    OsRng.fill_bytes(&mut key);
+pub fn generate_encryption_key() -> SecretBytes {
+    let mut key = SecretBytes::new(vec![0u8; CHACHA_KEY_SIZE]);
+    OsRng.fill_bytes(key.expose_mut());
     key
 }
 
-/// Generate a new Ed25519 keypair
-pub fn generate_signing_keypair() -> Result<(Vec<u8>, Vec<u8>)> {
+/// Generate a new Ed25519 keypair as (private, public)
+pub fn generate_signing_keypair() -> Result<(SecretBytes, Vec<u8>)> {
     // Generate random bytes for private key
-    let mut private_key_bytes = [0u8; ED25519_PRIVATE_KEY_SIZE];
-    OsRng.fill_bytes(&mut private_key_bytes);
+    let mut private_key = SecretBytes::new(vec![0u8; ED25519_PRIVATE_KEY_SIZE]);
+    OsRng.fill_bytes(private_key.expose_mut());
     
-    // Create signing key from random bytes
-    let signing_key = SigningKey::from_bytes(&private_key_bytes);
+    // Create signing key from random bytes (wiped when dropped)
+    let signing_key = SigningKey::try_from(private_key.expose())
+        .map_err(|e| SecurityError::KeyError(e.to_string()))?;
     
     // Get the verification key
     let verifying_key = VerifyingKey::from(&signing_key);
     
-    // Return the keypair as bytes
-    Ok((
-        signing_key.to_bytes().to_vec(),
-        verifying_key.to_bytes().to_vec(),
-    ))
+    Ok((private_key, verifying_key.to_bytes().to_vec()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::hex_to_bytes;
     
     #[test]
     fn test_encrypt_decrypt() {
@@ -177,22 +386,106 @@ mod tests {
         // Test message
         let message = b"This is a secret message for testing";
         
+        let aad = b"header";
+        
         // Encrypt
-        let (ciphertext, nonce) = encrypt_message(message, &key).unwrap();
+        let (ciphertext, nonce) = encrypt_message(message, &key, aad).unwrap();
         
         // Make sure ciphertext is different from plaintext
         assert_ne!(ciphertext, message);
         
         // Decrypt
-        let decrypted = decrypt_message(&ciphertext, &nonce, &key).unwrap();
+        let decrypted = decrypt_message(&ciphertext, &nonce, &key, aad).unwrap();
         
         // Verify
         assert_eq!(decrypted, message);
         
         // Try with wrong key
         let wrong_key = generate_encryption_key();
-        let result = decrypt_message(&ciphertext, &nonce, &wrong_key);
+        let result = decrypt_message(&ciphertext, &nonce, &wrong_key, aad);
         assert!(result.is_err());
+        
+        // Try with different associated data
+        let result = decrypt_message(&ciphertext, &nonce, &key, b"tampered");
+        assert!(result.is_err());
+    }
+    
+    #[test]
+    fn test_every_suite() {
+        let key = generate_encryption_key();
+        let message = b"This is a secret message for testing";
+        
+        for &suite in CipherSuite::AVAILABLE {
+            let (ciphertext, nonce) = encrypt_with_suite(suite, message, &key, b"header").unwrap();
+            assert_eq!(nonce.len(), suite.nonce_size());
+            assert_eq!(ciphertext.len(), message.len() + TAG_SIZE);
+            assert_eq!(decrypt_with_suite(suite, &ciphertext, &nonce, &key, b"header").unwrap(), message);
+            
+            // Tampering with the ciphertext, tag, nonce or associated data is detected
+            for index in [0, ciphertext.len() - 1] {
+                let mut tampered = ciphertext.clone();
+                tampered[index] ^= 1;
+                assert!(decrypt_with_suite(suite, &tampered, &nonce, &key, b"header").is_err());
+            }
+            let mut wrong_nonce = nonce.clone();
+            wrong_nonce[0] ^= 1;
+            assert!(decrypt_with_suite(suite, &ciphertext, &wrong_nonce, &key, b"header").is_err());
+            assert!(decrypt_with_suite(suite, &ciphertext, &nonce, &key, b"tampered").is_err());
+            assert!(decrypt_with_suite(suite, &ciphertext, &nonce, &generate_encryption_key(), b"header").is_err());
+        }
+        
+        // Suites are not interchangeable under the same key and nonce
+        let nonce = [9u8; NONCE_SIZE];
+        let aes = encrypt_with_nonce(CipherSuite::Aes256Gcm, message, &nonce, &key, b"").unwrap();
+        assert!(decrypt_with_suite(CipherSuite::ChaCha20Poly1305, &aes, &nonce, &key, b"").is_err());
+    }
+    
+    #[test]
+    fn test_aes_gcm_known_answer() {
+        // Test Case 15 of the GCM specification: ciphertext followed by the tag
+        let key = SecretBytes::new(hex_to_bytes("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308").unwrap());
+        let nonce = hex_to_bytes("cafebabefacedbaddecaf888").unwrap();
+        let plaintext = hex_to_bytes("d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255").unwrap();
+        let expected = hex_to_bytes("522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015adb094dac5d93471bdec1a502270e3cc6c").unwrap();
+        
+        assert_eq!(encrypt_with_nonce(CipherSuite::Aes256Gcm, &plaintext, &nonce, &key, b"").unwrap(), expected);
+        assert_eq!(decrypt_with_suite(CipherSuite::Aes256Gcm, &expected, &nonce, &key, b"").unwrap(), plaintext);
+    }
+    
+    #[test]
+    fn test_truncated_tag() {
+        let key = generate_encryption_key();
+        let nonce = [3u8; NONCE_SIZE];
+        let message = b"RT5 SA1 status";
+        
+        let sealed = encrypt_truncated(message, &nonce, &key, b"header", 8).unwrap();
+        assert_eq!(sealed.len(), message.len() + 8);
+        
+        // The truncated tag is a prefix of the full tag
+        let cipher = ChaCha20Poly1305::new_from_slice(key.expose()).unwrap();
+        let full = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: message, aad: b"header" }).unwrap();
+        assert_eq!(sealed[..], full[..message.len() + 8]);
+        
+        assert_eq!(decrypt_truncated(&sealed, &nonce, &key, b"header", 8).unwrap(), message);
+        assert!(decrypt_truncated(&sealed, &nonce, &key, b"tampered", 8).is_err());
+        
+        let mut flipped = sealed.clone();
+        flipped[0] ^= 1;
+        assert!(decrypt_truncated(&flipped, &nonce, &key, b"header", 8).is_err());
+        assert!(decrypt_truncated(&sealed, &nonce, &key, b"header", 12).is_err());
+    }
+    
+    #[test]
+    fn test_mac() {
+        let key = generate_encryption_key();
+        let tag = compute_mac(b"telemetry", &key, 12).unwrap();
+        assert_eq!(tag.len(), 12);
+        
+        assert!(verify_mac(b"telemetry", &tag, &key).is_ok());
+        assert!(verify_mac(b"telemetrx", &tag, &key).is_err());
+        assert!(verify_mac(b"telemetry", &tag, &generate_encryption_key()).is_err());
+        assert!(verify_mac(b"telemetry", &tag[..4], &key).is_err());
+        assert!(compute_mac(b"telemetry", &key, 33).is_err());
     }
     
     #[test]
@@ -0,0 +1,771 @@
+//! Authenticated key agreement between gateway peers
+//!
+//! Two gateways agree on fresh session keys with ephemeral X25519 and
+//! authenticate each other by signing the handshake transcript with their
+//! Ed25519 identity keys. The exchange takes three messages:
+//!
+//! 1. The initiator sends its name, an ephemeral public key and a nonce.
+//! 2. The responder replies with the same, plus a signature over the transcript.
+//! 3. The initiator verifies it and answers with its own transcript signature.
+//!
+//! A gateway's identity keypair is the one generated under its name, so it
+//! signs with `<name>-signing` and peers verify it with `<name>-verify`. Only
+//! peers whose verification key is present in the `KeyManager` can complete
+//! a handshake.
+//!
+//! Each side derives one encryption key per direction with HKDF-SHA256 and
+//! installs both as session-scoped keys, named by `session_key_id`.
+//!
+//! The key exchange is negotiated explicitly. The initiator offers the
+//! exchanges its `KeyExchangePolicy` allows, in order of preference, and the
+//! responder picks the first one its own policy accepts. The hybrid exchange
+//! adds an ML-KEM-768 encapsulation to the ephemeral X25519 agreement and
+//! hashes both shared secrets into the session keys, so they stay secret
+//! unless both are broken. The offer and choice are part of the signed
+//! transcript, so a man in the middle cannot downgrade the exchange. A
+//! policy requiring post-quantum protection refuses classical-only peers.
+//!
+//! ML-KEM-768 is provided by `MlKem768`, backed by the RustCrypto `ml-kem`
+//! crate; other implementations, such as one backed by a hardware module,
+//! can be supplied through the `Kem` trait.
+
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use ml_kem::{Decapsulate as _, Encapsulate as _, KeyExport as _};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroize;
+
+use crate::security::key_manager::KeyManager;
+use crate::security::trust::PeerRole;
+use crate::security::{crypto, SecurityError};
+use crate::security::secret::SecretBytes;
+
+/// Domain separation label for the handshake transcript
+const PROTOCOL_LABEL: &[u8] = b"secure-gateway handshake v2";
+
+/// Size of the random nonce each side contributes
+pub const HANDSHAKE_NONCE_SIZE: usize = 32;
+
+/// Key exchange used by a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyExchange {
+    /// Ephemeral X25519 only
+    X25519,
+    
+    /// Ephemeral X25519 combined with an ML-KEM-768 encapsulation
+    X25519MlKem768,
+}
+
+impl KeyExchange {
+    /// Stable numeric identifier used in the transcript encoding
+    fn as_u8(&self) -> u8 {
+        match self {
+            KeyExchange::X25519 => 1,
+            KeyExchange::X25519MlKem768 => 2,
+        }
+    }
+    
+    /// Whether the exchange resists a quantum adversary
+    pub fn is_post_quantum(&self) -> bool {
+        *self == KeyExchange::X25519MlKem768
+    }
+}
+
+/// Whether handshakes use the hybrid post-quantum key exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PostQuantumPolicy {
+    /// Classical X25519 only
+    #[default]
+    Disabled,
+    
+    /// Hybrid when the peer supports it, classical otherwise
+    Preferred,
+    
+    /// Hybrid only; classical-only peers are refused
+    Required,
+}
+
+/// Key encapsulation mechanism used by the hybrid key exchange
+pub trait Kem: Send + Sync {
+    /// Generate a keypair, returning the decapsulation key and the encapsulation key
+    fn generate(&self) -> Result<(SecretBytes, Vec<u8>)>;
+    
+    /// Encapsulate a fresh shared secret to an encapsulation key, returning the ciphertext and secret
+    fn encapsulate(&self, encapsulation_key: &[u8]) -> Result<(Vec<u8>, SecretBytes)>;
+    
+    /// Recover the shared secret from a ciphertext
+    fn decapsulate(&self, decapsulation_key: &SecretBytes, ciphertext: &[u8]) -> Result<SecretBytes>;
+}
+
+/// ML-KEM-768 (FIPS 203) as the handshake KEM
+///
+/// Decapsulation keys are kept as their 64-byte seed `d | z`, the form
+/// FIPS 203 recommends storing; encapsulation keys and ciphertexts use the
+/// standard encodings. Encapsulation keys that fail the input checks of the
+/// standard are refused.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MlKem768;
+
+impl MlKem768 {
+    /// Encapsulation key size
+    pub const ENCAPSULATION_KEY_SIZE: usize = 1184;
+    
+    /// Ciphertext size
+    pub const CIPHERTEXT_SIZE: usize = 1088;
+    
+    /// Decapsulation key (seed) size
+    pub const SEED_SIZE: usize = 64;
+    
+    fn decapsulation_key(seed: &SecretBytes) -> Result<ml_kem::ml_kem_768::DecapsulationKey> {
+        let seed = ml_kem::Seed::try_from(seed.expose()).map_err(|_| anyhow!(SecurityError::KeyError(
+            format!("Invalid ML-KEM seed size: {} (expected {})", seed.len(), Self::SEED_SIZE)
+        )))?;
+        
+        Ok(ml_kem::ml_kem_768::DecapsulationKey::from_seed(seed))
+    }
+}
+
+impl Kem for MlKem768 {
+    fn generate(&self) -> Result<(SecretBytes, Vec<u8>)> {
+        let mut bytes = [0u8; Self::SEED_SIZE];
+        OsRng.fill_bytes(&mut bytes);
+        let seed = SecretBytes::new(bytes.to_vec());
+        bytes.zeroize();
+        
+        let encapsulation_key = Self::decapsulation_key(&seed)?.encapsulation_key().to_bytes().to_vec();
+        Ok((seed, encapsulation_key))
+    }
+    
+    fn encapsulate(&self, encapsulation_key: &[u8]) -> Result<(Vec<u8>, SecretBytes)> {
+        let encoded = ml_kem::Key::<ml_kem::ml_kem_768::EncapsulationKey>::try_from(encapsulation_key)
+            .map_err(|_| anyhow!(SecurityError::KeyError(format!(
+                "Invalid ML-KEM encapsulation key size: {} (expected {})",
+                encapsulation_key.len(), Self::ENCAPSULATION_KEY_SIZE
+            ))))?;
+        let key = ml_kem::ml_kem_768::EncapsulationKey::new(&encoded)
+            .map_err(|_| anyhow!(SecurityError::KeyError("Invalid ML-KEM encapsulation key".into())))?;
+        
+        let (ciphertext, mut shared) = key.encapsulate();
+        let secret = SecretBytes::new(shared.to_vec());
+        shared.zeroize();
+        
+        Ok((ciphertext.to_vec(), secret))
+    }
+    
+    fn decapsulate(&self, decapsulation_key: &SecretBytes, ciphertext: &[u8]) -> Result<SecretBytes> {
+        let key = Self::decapsulation_key(decapsulation_key)?;
+        let mut shared = key.decapsulate_slice(ciphertext).map_err(|_| anyhow!(SecurityError::DecryptionFailed(
+            format!("Invalid ML-KEM ciphertext size: {} (expected {})", ciphertext.len(), Self::CIPHERTEXT_SIZE)
+        )))?;
+        let secret = SecretBytes::new(shared.to_vec());
+        shared.zeroize();
+        
+        Ok(secret)
+    }
+}
+
+/// Key exchanges a gateway offers and accepts
+#[derive(Clone, Default)]
+pub struct KeyExchangePolicy {
+    post_quantum: PostQuantumPolicy,
+    kem: Option<Arc<dyn Kem>>,
+}
+
+impl KeyExchangePolicy {
+    /// Classical X25519 only
+    pub fn classical() -> Self {
+        Self::default()
+    }
+    
+    /// Use the hybrid exchange with ML-KEM-768 provided by `kem`
+    pub fn hybrid(post_quantum: PostQuantumPolicy, kem: Arc<dyn Kem>) -> Self {
+        Self { post_quantum, kem: Some(kem) }
+    }
+    
+    /// Policy for the configured setting, using the ML-KEM implementation built in
+    pub fn from_config(post_quantum: PostQuantumPolicy) -> Self {
+        match post_quantum {
+            PostQuantumPolicy::Disabled => Self::classical(),
+            PostQuantumPolicy::Preferred | PostQuantumPolicy::Required => Self::hybrid(post_quantum, Arc::new(MlKem768)),
+        }
+    }
+    
+    /// Exchanges to offer, most preferred first
+    fn offer(&self) -> Vec<KeyExchange> {
+        match (self.post_quantum, &self.kem) {
+            (PostQuantumPolicy::Required, _) => vec![KeyExchange::X25519MlKem768],
+            (PostQuantumPolicy::Preferred, Some(_)) => vec![KeyExchange::X25519MlKem768, KeyExchange::X25519],
+            _ => vec![KeyExchange::X25519],
+        }
+    }
+    
+    /// The KEM, if the hybrid exchange is used
+    fn kem(&self, exchange: KeyExchange) -> Result<Option<&dyn Kem>> {
+        if !exchange.is_post_quantum() {
+            return Ok(None);
+        }
+        
+        self.kem.as_deref()
+            .map(Some)
+            .ok_or_else(|| handshake_error("no ML-KEM implementation is available"))
+    }
+}
+
+
+/// First handshake message, sent by the initiator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeInit {
+    pub initiator: String,
+    pub ephemeral_public: [u8; 32],
+    pub nonce: [u8; HANDSHAKE_NONCE_SIZE],
+    pub key_exchanges: Vec<KeyExchange>,  // Offered, most preferred first
+    pub kem_public: Vec<u8>,  // ML-KEM encapsulation key, if the hybrid exchange is offered
+}
+
+/// Second handshake message, sent by the responder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    pub responder: String,
+    pub ephemeral_public: [u8; 32],
+    pub nonce: [u8; HANDSHAKE_NONCE_SIZE],
+    pub key_exchange: KeyExchange,  // Chosen from the initiator's offer
+    pub kem_ciphertext: Vec<u8>,  // ML-KEM encapsulation, in the hybrid exchange
+    pub signature: Vec<u8>,  // Responder's signature over the transcript
+}
+
+/// Final handshake message, sent by the initiator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandshakeFinish {
+    pub signature: Vec<u8>,  // Initiator's signature over the transcript
+}
+
+/// Key IDs installed by a completed handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    /// Key for messages this gateway sends to the peer
+    pub send_key_id: String,
+    
+    /// Key for messages this gateway receives from the peer
+    pub receive_key_id: String,
+}
+
+/// ID of the session key protecting traffic from one gateway to another
+pub fn session_key_id(from: &str, to: &str) -> String {
+    format!("session/{}/{}", from, to)
+}
+
+/// Initiating side of a handshake
+pub struct Initiator {
+    local: String,
+    ephemeral: EphemeralSecret,
+    policy: KeyExchangePolicy,
+    kem_secret: Option<SecretBytes>,
+    init: HandshakeInit,
+}
+
+impl Initiator {
+    /// Start a classical handshake; send the returned message to the peer
+    pub fn start(local: &str) -> Result<(Self, HandshakeInit)> {
+        Self::start_with_policy(local, &KeyExchangePolicy::classical())
+    }
+    
+    /// Start a handshake offering the key exchanges allowed by `policy`
+    pub fn start_with_policy(local: &str, policy: &KeyExchangePolicy) -> Result<(Self, HandshakeInit)> {
+        check_name(local)?;
+        
+        let key_exchanges = policy.offer();
+        let (kem_secret, kem_public) = match policy.kem(key_exchanges[0])? {
+            Some(kem) => {
+                let (secret, public) = kem.generate()?;
+                (Some(secret), public)
+            },
+            None => (None, vec![]),
+        };
+        
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let init = HandshakeInit {
+            initiator: local.to_string(),
+            ephemeral_public: PublicKey::from(&ephemeral).to_bytes(),
+            nonce: random_nonce(),
+            key_exchanges,
+            kem_public,
+        };
+        
+        let initiator = Self {
+            local: local.to_string(),
+            ephemeral,
+            policy: policy.clone(),
+            kem_secret,
+            init: init.clone(),
+        };
+        
+        Ok((initiator, init))
+    }
+    
+    /// Verify the responder and install the session keys
+    ///
+    /// The returned message must be sent to the responder so that it can
+    /// authenticate this side and install its keys.
+    pub fn finish(self, key_manager: &KeyManager, response: &HandshakeResponse)
+        -> Result<(HandshakeFinish, SessionKeys)> {
+        // Authenticate the responder before using anything it sent
+        self.verify(key_manager, response)?;
+        let transcript = transcript(&self.init, response);
+        
+        let kem_shared = match (self.policy.kem(response.key_exchange)?, &self.kem_secret) {
+            (Some(kem), Some(secret)) => Some(kem.decapsulate(secret, &response.kem_ciphertext)
+                .map_err(|e| handshake_error(&format!("KEM decapsulation failed: {}", e)))?),
+            (Some(_), None) => return Err(handshake_error("no KEM keypair for the hybrid exchange")),
+            (None, _) => None,
+        };
+        
+        let (initiator_to_responder, responder_to_initiator) = derive_keys(
+            self.ephemeral, &response.ephemeral_public, kem_shared.as_ref(), &self.init, response, &transcript)?;
+        
+        let signature = key_manager.sign(
+            &format!("{}-signing", self.local),
+            &signed_content(b"initiator", &transcript),
+        )?;
+        
+        let keys = install_keys(key_manager, &self.local, &response.responder,
+            &initiator_to_responder, &responder_to_initiator)?;
+        
+        Ok((HandshakeFinish { signature }, keys))
+    }
+    
+    /// Check that `response` answers this handshake and is signed by the responder it names
+    pub fn verify(&self, key_manager: &KeyManager, response: &HandshakeResponse) -> Result<()> {
+        check_name(&response.responder)?;
+        if response.responder == self.local {
+            return Err(handshake_error("peer uses our own name"));
+        }
+        
+        crypto::verify_signature(
+            &signed_content(b"responder", &transcript(&self.init, response)),
+            &response.signature,
+            &key_manager.get_peer_verification_key(&format!("{}-verify", response.responder),
+                &response.responder, PeerRole::Handshake)?,
+        ).map_err(|e| handshake_error(&format!("responder signature invalid: {}", e)))?;
+        
+        // The signed transcript covers our offer, so the choice cannot be downgraded in transit
+        if !self.init.key_exchanges.contains(&response.key_exchange) {
+            return Err(handshake_error(&format!("responder chose {:?}, which was not offered",
+                response.key_exchange)));
+        }
+        
+        Ok(())
+    }
+}
+
+/// Responding side of a handshake
+pub struct Responder {
+    local: String,
+    peer: String,
+    transcript: Vec<u8>,
+    send_key: SecretBytes,
+    receive_key: SecretBytes,
+}
+
+impl Responder {
+    /// Answer a handshake from a known peer with a classical key exchange
+    pub fn respond(key_manager: &KeyManager, local: &str, init: &HandshakeInit)
+        -> Result<(Self, HandshakeResponse)> {
+        Self::respond_with_policy(key_manager, local, init, &KeyExchangePolicy::classical())
+    }
+    
+    /// Answer a handshake from a known peer; send the returned message back
+    ///
+    /// The first key exchange offered by the initiator that `policy` allows
+    /// is used. Fails if there is none, e.g. when post-quantum protection is
+    /// required but the initiator only offers a classical exchange.
+    pub fn respond_with_policy(key_manager: &KeyManager, local: &str, init: &HandshakeInit,
+                               policy: &KeyExchangePolicy) -> Result<(Self, HandshakeResponse)> {
+        check_name(local)?;
+        check_name(&init.initiator)?;
+        if init.initiator == local {
+            return Err(handshake_error("peer uses our own name"));
+        }
+        
+        // Refuse unknown peers before doing any work for them
+        let peer_verify_id = format!("{}-verify", init.initiator);
+        key_manager.get_peer_verification_key(&peer_verify_id, &init.initiator, PeerRole::Handshake)
+            .map_err(|_| handshake_error(&format!("unknown peer {}", init.initiator)))?;
+        
+        let allowed = policy.offer();
+        let key_exchange = *init.key_exchanges.iter()
+            .find(|exchange| allowed.contains(exchange))
+            .ok_or_else(|| handshake_error(&format!("no acceptable key exchange offered by {} (offered {:?})",
+                init.initiator, init.key_exchanges)))?;
+        
+        let (kem_ciphertext, kem_shared) = match policy.kem(key_exchange)? {
+            Some(kem) => {
+                let (ciphertext, shared) = kem.encapsulate(&init.kem_public)
+                    .map_err(|e| handshake_error(&format!("KEM encapsulation failed: {}", e)))?;
+                (ciphertext, Some(shared))
+            },
+            None => (vec![], None),
+        };
+        
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let mut response = HandshakeResponse {
+            responder: local.to_string(),
+            ephemeral_public: PublicKey::from(&ephemeral).to_bytes(),
+            nonce: random_nonce(),
+            key_exchange,
+            kem_ciphertext,
+            signature: vec![],
+        };
+        
+        let transcript = transcript(init, &response);
+        response.signature = key_manager.sign(
+            &format!("{}-signing", local),
+            &signed_content(b"responder", &transcript),
+        )?;
+        
+        let (initiator_to_responder, responder_to_initiator) = derive_keys(
+            ephemeral, &init.ephemeral_public, kem_shared.as_ref(), init, &response, &transcript)?;
+        
+        let responder = Self {
+            local: local.to_string(),
+            peer: init.initiator.clone(),
+            transcript,
+            send_key: responder_to_initiator,
+            receive_key: initiator_to_responder,
+        };
+        
+        Ok((responder, response))
+    }
+    
+    /// Verify the initiator and install the session keys
+    pub fn finish(self, key_manager: &KeyManager, finish: &HandshakeFinish) -> Result<SessionKeys> {
+        self.verify(key_manager, finish)?;
+        install_keys(key_manager, &self.local, &self.peer, &self.send_key, &self.receive_key)
+    }
+    
+    /// Check that `finish` is the initiator's signature over this handshake
+    pub fn verify(&self, key_manager: &KeyManager, finish: &HandshakeFinish) -> Result<()> {
+        crypto::verify_signature(
+            &signed_content(b"initiator", &self.transcript),
+            &finish.signature,
+            &key_manager.get_peer_verification_key(&format!("{}-verify", self.peer),
+                &self.peer, PeerRole::Handshake)?,
+        ).map_err(|e| handshake_error(&format!("initiator signature invalid: {}", e)))
+    }
+}
+
+fn handshake_error(reason: &str) -> anyhow::Error {
+    anyhow!(SecurityError::HandshakeFailed(reason.to_string()))
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > u16::MAX as usize {
+        return Err(handshake_error("gateway name must be 1 to 65535 bytes"));
+    }
+    
+    Ok(())
+}
+
+fn random_nonce() -> [u8; HANDSHAKE_NONCE_SIZE] {
+    let mut nonce = [0u8; HANDSHAKE_NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Canonical encoding of everything exchanged before the signatures
+fn transcript(init: &HandshakeInit, response: &HandshakeResponse) -> Vec<u8> {
+    let mut transcript = PROTOCOL_LABEL.to_vec();
+    
+    for (name, public, nonce) in [
+        (&init.initiator, &init.ephemeral_public, &init.nonce),
+        (&response.responder, &response.ephemeral_public, &response.nonce),
+    ] {
+        transcript.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        transcript.extend_from_slice(name.as_bytes());
+        transcript.extend_from_slice(public);
+        transcript.extend_from_slice(nonce);
+    }
+    
+    // Key exchange negotiation: the offer, the KEM public key, the choice and the encapsulation
+    transcript.push(init.key_exchanges.len() as u8);
+    transcript.extend(init.key_exchanges.iter().map(KeyExchange::as_u8));
+    transcript.extend_from_slice(&(init.kem_public.len() as u32).to_be_bytes());
+    transcript.extend_from_slice(&init.kem_public);
+    transcript.push(response.key_exchange.as_u8());
+    transcript.extend_from_slice(&(response.kem_ciphertext.len() as u32).to_be_bytes());
+    transcript.extend_from_slice(&response.kem_ciphertext);
+    
+    transcript
+}
+
+/// Prefix the transcript with the signer's role so signatures cannot be reflected
+fn signed_content(role: &[u8], transcript: &[u8]) -> Vec<u8> {
+    let mut content = role.to_vec();
+    content.extend_from_slice(transcript);
+    content
+}
+
+/// Derive the (initiator to responder, responder to initiator) keys
+///
+/// In the hybrid exchange the KEM shared secret is appended to the X25519
+/// shared secret, so the keys depend on both.
+fn derive_keys(ephemeral: EphemeralSecret, peer_public: &[u8; 32], kem_shared: Option<&SecretBytes>,
+               init: &HandshakeInit, response: &HandshakeResponse, transcript: &[u8])
+    -> Result<(SecretBytes, SecretBytes)> {
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*peer_public));
+    
+    // Low-order peer points yield a predictable shared secret
+    if !shared.was_contributory() {
+        return Err(handshake_error("peer ephemeral key is not contributory"));
+    }
+    
+    let mut salt = init.nonce.to_vec();
+    salt.extend_from_slice(&response.nonce);
+    let mut input = SecretBytes::from_slice(shared.as_bytes());
+    if let Some(kem_shared) = kem_shared {
+        input = SecretBytes::new([input.expose(), kem_shared.expose()].concat());
+    }
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), input.expose());
+    
+    let expand = |direction: &[u8]| -> Result<SecretBytes> {
+        let mut info = direction.to_vec();
+        info.extend_from_slice(transcript);
+        
+        let mut key = SecretBytes::new(vec![0u8; crypto::CHACHA_KEY_SIZE]);
+        hkdf.expand(&info, key.expose_mut())
+            .map_err(|e| handshake_error(&format!("key derivation failed: {}", e)))?;
+        Ok(key)
+    };
+    
+    Ok((expand(b"initiator->responder")?, expand(b"responder->initiator")?))
+}
+
+fn install_keys(key_manager: &KeyManager, local: &str, peer: &str,
+                send_key: &SecretBytes, receive_key: &SecretBytes) -> Result<SessionKeys> {
+    let keys = SessionKeys {
+        send_key_id: session_key_id(local, peer),
+        receive_key_id: session_key_id(peer, local),
+    };
+    
+    key_manager.install_session_key(&keys.send_key_id, send_key,
+        &format!("Session key {} to {}", local, peer))?;
+    key_manager.install_session_key(&keys.receive_key_id, receive_key,
+        &format!("Session key {} to {}", peer, local))?;
+    
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::key_manager::KeyType;
+    use crate::utils::hex_to_bytes;
+    #[allow(deprecated)]
+    use ml_kem::ExpandedKeyEncoding as _;
+    use serde_json::Value;
+    
+    /// Create key managers for two gateways that know each other's identity
+    fn create_peers() -> (KeyManager, KeyManager) {
+        let km_a = KeyManager::new();
+        let km_b = KeyManager::new();
+        km_a.generate_keypair("gw-a", "Identity of gw-a", None).unwrap();
+        km_b.generate_keypair("gw-b", "Identity of gw-b", None).unwrap();
+        
+        km_b.import_key("gw-a-verify", KeyType::Verification,
+            &km_a.get_verification_key("gw-a-verify").unwrap(), "Peer gw-a", None).unwrap();
+        km_a.import_key("gw-b-verify", KeyType::Verification,
+            &km_b.get_verification_key("gw-b-verify").unwrap(), "Peer gw-b", None).unwrap();
+        
+        (km_a, km_b)
+    }
+    
+    #[test]
+    fn test_handshake() {
+        let (km_a, km_b) = create_peers();
+        
+        let (initiator, init) = Initiator::start("gw-a").unwrap();
+        let (responder, response) = Responder::respond(&km_b, "gw-b", &init).unwrap();
+        let (finish, keys_a) = initiator.finish(&km_a, &response).unwrap();
+        let keys_b = responder.finish(&km_b, &finish).unwrap();
+        
+        assert_eq!(keys_a.send_key_id, keys_b.receive_key_id);
+        assert_eq!(keys_a.receive_key_id, keys_b.send_key_id);
+        assert!(km_a.is_session_key(&keys_a.send_key_id).unwrap());
+        
+        // Each direction gets its own key, agreed by both sides
+        let a_to_b = km_a.get_encryption_key(&keys_a.send_key_id).unwrap();
+        assert_eq!(a_to_b, km_b.get_encryption_key(&keys_b.receive_key_id).unwrap());
+        assert_eq!(km_a.get_encryption_key(&keys_a.receive_key_id).unwrap(),
+            km_b.get_encryption_key(&keys_b.send_key_id).unwrap());
+        assert_ne!(a_to_b, km_a.get_encryption_key(&keys_a.receive_key_id).unwrap());
+    }
+    
+    #[test]
+    fn test_handshake_rejects_impostors() {
+        let (km_a, km_b) = create_peers();
+        
+        // Unknown initiators are refused
+        let (_, init) = Initiator::start("gw-c").unwrap();
+        let err = Responder::respond(&km_b, "gw-b", &init).err().unwrap();
+        assert!(matches!(err.downcast_ref::<SecurityError>(), Some(SecurityError::HandshakeFailed(_))));
+        
+        // A substituted responder ephemeral key breaks the responder signature
+        let (initiator, init) = Initiator::start("gw-a").unwrap();
+        let (_, mut response) = Responder::respond(&km_b, "gw-b", &init).unwrap();
+        response.ephemeral_public = PublicKey::from(&EphemeralSecret::random_from_rng(OsRng)).to_bytes();
+        assert!(initiator.finish(&km_a, &response).is_err());
+        
+        // The responder's signature cannot be reflected back as the initiator's
+        let (_, init) = Initiator::start("gw-a").unwrap();
+        let (responder, response) = Responder::respond(&km_b, "gw-b", &init).unwrap();
+        let reflected = HandshakeFinish { signature: response.signature.clone() };
+        assert!(responder.finish(&km_b, &reflected).is_err());
+        assert!(km_b.get_encryption_key(&session_key_id("gw-a", "gw-b")).is_err());
+    }
+    
+    fn run(km_a: &KeyManager, km_b: &KeyManager, policy_a: &KeyExchangePolicy, policy_b: &KeyExchangePolicy)
+        -> Result<KeyExchange> {
+        let (initiator, init) = Initiator::start_with_policy("gw-a", policy_a)?;
+        let (responder, response) = Responder::respond_with_policy(km_b, "gw-b", &init, policy_b)?;
+        let (finish, keys_a) = initiator.finish(km_a, &response)?;
+        let keys_b = responder.finish(km_b, &finish)?;
+        
+        assert_eq!(km_a.get_encryption_key(&keys_a.send_key_id)?, km_b.get_encryption_key(&keys_b.receive_key_id)?);
+        Ok(response.key_exchange)
+    }
+    
+    #[test]
+    fn test_hybrid_negotiation() {
+        let (km_a, km_b) = create_peers();
+        let classical = KeyExchangePolicy::classical();
+        let preferred = KeyExchangePolicy::from_config(PostQuantumPolicy::Preferred);
+        let required = KeyExchangePolicy::from_config(PostQuantumPolicy::Required);
+        
+        assert_eq!(run(&km_a, &km_b, &preferred, &preferred).unwrap(), KeyExchange::X25519MlKem768);
+        assert_eq!(run(&km_a, &km_b, &required, &preferred).unwrap(), KeyExchange::X25519MlKem768);
+        assert_eq!(run(&km_a, &km_b, &preferred, &classical).unwrap(), KeyExchange::X25519);
+        
+        // Either side requiring post-quantum protection refuses classical-only peers
+        assert!(run(&km_a, &km_b, &classical, &required).is_err());
+        assert!(run(&km_a, &km_b, &required, &classical).is_err());
+        
+        // Stripping the hybrid offer in transit breaks the responder signature
+        let (initiator, init) = Initiator::start_with_policy("gw-a", &preferred).unwrap();
+        let mut stripped = init.clone();
+        stripped.key_exchanges = vec![KeyExchange::X25519];
+        stripped.kem_public.clear();
+        let (_, response) = Responder::respond_with_policy(&km_b, "gw-b", &stripped, &preferred).unwrap();
+        assert_eq!(response.key_exchange, KeyExchange::X25519);
+        assert!(initiator.finish(&km_a, &response).is_err());
+    }
+    
+    #[test]
+    fn test_hybrid_with_ml_kem() {
+        let (km_a, km_b) = create_peers();
+        let required = KeyExchangePolicy::from_config(PostQuantumPolicy::Required);
+        
+        let (initiator, init) = Initiator::start_with_policy("gw-a", &required).unwrap();
+        assert_eq!(init.kem_public.len(), MlKem768::ENCAPSULATION_KEY_SIZE);
+        let (responder, response) = Responder::respond_with_policy(&km_b, "gw-b", &init, &required).unwrap();
+        assert_eq!(response.key_exchange, KeyExchange::X25519MlKem768);
+        assert_eq!(response.kem_ciphertext.len(), MlKem768::CIPHERTEXT_SIZE);
+        let (finish, keys_a) = initiator.finish(&km_a, &response).unwrap();
+        let keys_b = responder.finish(&km_b, &finish).unwrap();
+        assert_eq!(km_a.get_encryption_key(&keys_a.send_key_id).unwrap(),
+            km_b.get_encryption_key(&keys_b.receive_key_id).unwrap());
+        
+        // A substituted encapsulation is signed over, so the initiator refuses it
+        let (initiator, init) = Initiator::start_with_policy("gw-a", &required).unwrap();
+        let (_, mut response) = Responder::respond_with_policy(&km_b, "gw-b", &init, &required).unwrap();
+        response.kem_ciphertext = MlKem768.encapsulate(&init.kem_public).unwrap().0;
+        assert!(initiator.finish(&km_a, &response).is_err());
+    }
+    
+    /// ML-KEM-768 test groups of a NIST ACVP internal projection
+    ///
+    /// The files in `testdata` hold the ML-KEM-768 groups of the FIPS 203
+    /// keyGen and encapDecap vector sets from the usnistgov/ACVP-Server
+    /// repository, `gen-val/json-files/ML-KEM-*-FIPS203/internalProjection.json`.
+    fn acvp_groups(vectors: &str) -> Vec<Value> {
+        let vectors: Value = serde_json::from_str(vectors).unwrap();
+        vectors["testGroups"].as_array().unwrap().clone()
+    }
+    
+    fn hex_field(value: &Value, name: &str) -> Vec<u8> {
+        hex_to_bytes(value[name].as_str().unwrap()).unwrap()
+    }
+    
+    #[test]
+    fn test_ml_kem_acvp_key_generation() {
+        let mut count = 0;
+        for group in acvp_groups(include_str!("testdata/ml_kem_768_keygen.json")) {
+            for test in group["tests"].as_array().unwrap() {
+                let seed = SecretBytes::new([hex_field(test, "d"), hex_field(test, "z")].concat());
+                let key = MlKem768::decapsulation_key(&seed).unwrap();
+                assert_eq!(key.encapsulation_key().to_bytes().as_slice(), hex_field(test, "ek"));
+                #[allow(deprecated)]
+                let expanded = key.to_expanded_bytes();
+                assert_eq!(expanded.as_slice(), hex_field(test, "dk"));
+                count += 1;
+            }
+        }
+        assert_eq!(count, 25);
+    }
+    
+    #[test]
+    #[allow(deprecated)]
+    fn test_ml_kem_acvp_encapsulation_and_decapsulation() {
+        let mut count = 0;
+        for group in acvp_groups(include_str!("testdata/ml_kem_768_encap_decap.json")) {
+            for test in group["tests"].as_array().unwrap() {
+                // Decapsulation tests share the group's key
+                let key_source = if group["function"] == "decapsulation" { &group } else { test };
+                let decapsulation_key = ml_kem::ExpandedDecapsulationKey::<ml_kem::MlKem768>::try_from(
+                    hex_field(key_source, "dk").as_slice()).unwrap();
+                let decapsulation_key = ml_kem::ml_kem_768::DecapsulationKey::from_expanded(&decapsulation_key).unwrap();
+                let ciphertext = hex_field(test, "c");
+                let shared = hex_field(test, "k");
+                
+                if group["function"] == "encapsulation" {
+                    let encapsulation_key = ml_kem::ml_kem_768::EncapsulationKey::new(
+                        &hex_field(test, "ek").as_slice().try_into().unwrap()).unwrap();
+                    let message = hex_field(test, "m").as_slice().try_into().unwrap();
+                    let (encapsulated, agreed) = encapsulation_key.encapsulate_deterministic(&message);
+                    assert_eq!(encapsulated.as_slice(), ciphertext);
+                    assert_eq!(agreed.as_slice(), shared);
+                }
+                
+                // Modified ciphertexts yield the implicit rejection secret
+                assert_eq!(decapsulation_key.decapsulate_slice(&ciphertext).unwrap().as_slice(), shared);
+                count += 1;
+            }
+        }
+        assert_eq!(count, 35);
+    }
+    
+    #[test]
+    fn test_ml_kem_invalid_inputs() {
+        let (decapsulation_key, encapsulation_key) = MlKem768.generate().unwrap();
+        assert_eq!(decapsulation_key.len(), MlKem768::SEED_SIZE);
+        assert_eq!(encapsulation_key.len(), MlKem768::ENCAPSULATION_KEY_SIZE);
+        
+        let (ciphertext, shared) = MlKem768.encapsulate(&encapsulation_key).unwrap();
+        assert_eq!(ciphertext.len(), MlKem768::CIPHERTEXT_SIZE);
+        assert_eq!(MlKem768.decapsulate(&decapsulation_key, &ciphertext).unwrap(), shared);
+        
+        // Encapsulation keys with unreduced coefficients or the wrong size are refused
+        let mut unreduced = encapsulation_key.clone();
+        unreduced[0] = 0xff;
+        unreduced[1] |= 0x0f;
+        assert!(MlKem768.encapsulate(&unreduced).is_err());
+        assert!(MlKem768.encapsulate(&encapsulation_key[1..]).is_err());
+        
+        // So are truncated ciphertexts and seeds
+        assert!(MlKem768.decapsulate(&decapsulation_key, &ciphertext[1..]).is_err());
+        let truncated = SecretBytes::new(decapsulation_key.expose()[1..].to_vec());
+        assert!(MlKem768.decapsulate(&truncated, &ciphertext).is_err());
+    }
+}
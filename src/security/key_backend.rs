@@ -0,0 +1,632 @@
+//! Key storage backends
+//!
+//! `KeyManager` decides which keys may be used and leaves storing them to a
+//! `KeyStore`. Software backends keep key material in memory, optionally
+//! mirrored to a plain or sealed file. Token backends keep secret and
+//! private keys inside the device and sign, encrypt, decrypt, MAC and derive
+//! there, so the material never leaves it; see `token`.
+
+use anyhow::{anyhow, Context, Result};
+use bincode::Options;
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::config::{KeyStoreBackend, SecurityConfig};
+use crate::security::{crypto, SecurityError};
+use crate::security::crypto::{CipherSuite, NONCE_SIZE};
+use crate::security::key_manager::{KeyEntry, KeyMetadata, KeyType, KeyUsage};
+use crate::security::key_store::{self, KeyStoreSecret, SealingKey};
+use crate::security::secret::SecretBytes;
+use crate::security::token::SoftwareToken;
+
+/// Storage for long-term keys
+///
+/// Implementations only store keys and perform operations with them;
+/// key types and expiry are checked by `KeyManager` before calling in.
+pub trait KeyStore: Send + Sync {
+    /// Short description of the backend for logs
+    fn describe(&self) -> String;
+    
+    /// Metadata of a key, if present
+    fn metadata(&self, id: &str) -> Result<Option<KeyMetadata>>;
+    
+    /// Metadata of all keys
+    fn list(&self) -> Result<Vec<KeyMetadata>>;
+    
+    /// Store keys created outside the backend, replacing keys with the same IDs
+    fn insert(&self, entries: Vec<KeyEntry>) -> Result<()>;
+    
+    /// Create an encryption key inside the backend
+    fn generate_key(&self, metadata: KeyMetadata) -> Result<()> {
+        self.insert(vec![KeyEntry { metadata, key_data: crypto::generate_encryption_key() }])
+    }
+    
+    /// Create a signing keypair inside the backend
+    fn generate_keypair(&self, signing: KeyMetadata, verification: KeyMetadata) -> Result<()> {
+        let (private_key, public_key) = crypto::generate_signing_keypair()?;
+        
+        self.insert(vec![
+            KeyEntry { metadata: signing, key_data: private_key },
+            KeyEntry { metadata: verification, key_data: public_key.into() },
+        ])
+    }
+    
+    /// Set when a key expires (seconds since the Unix epoch, None = never)
+    fn set_expiry(&self, id: &str, expires_at: Option<u64>) -> Result<()>;
+    
+    /// Record the usage of an encryption key with its entry
+    fn set_usage(&self, id: &str, usage: KeyUsage) -> Result<()>;
+    
+    /// Remove a key, returning whether it was present
+    fn remove(&self, id: &str) -> Result<bool>;
+    
+    /// Key material, for keys the backend allows to leave it
+    fn export(&self, id: &str) -> Result<SecretBytes>;
+    
+    /// Sign a message with a signing key
+    fn sign(&self, id: &str, message: &[u8]) -> Result<Vec<u8>> {
+        crypto::sign_message(message, &self.export(id)?)
+    }
+    
+    /// Encrypt with an encryption key, returning the ciphertext and nonce
+    fn encrypt(&self, id: &str, suite: CipherSuite, plaintext: &[u8], associated_data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        crypto::encrypt_with_suite(suite, plaintext, &self.export(id)?, associated_data)
+    }
+    
+    /// Decrypt with an encryption key
+    fn decrypt(&self, id: &str, suite: CipherSuite, ciphertext: &[u8], nonce: &[u8],
+               associated_data: &[u8]) -> Result<Vec<u8>> {
+        crypto::decrypt_with_suite(suite, ciphertext, nonce, &self.export(id)?, associated_data)
+    }
+    
+    /// Encrypt with an encryption key under a caller-chosen nonce, keeping
+    /// `tag_len` bytes of the ChaCha20-Poly1305 tag
+    ///
+    /// The caller must never reuse a nonce with the same key.
+    fn seal(&self, id: &str, nonce: &[u8; NONCE_SIZE], plaintext: &[u8], associated_data: &[u8],
+            tag_len: usize) -> Result<Vec<u8>> {
+        crypto::encrypt_truncated(plaintext, nonce, &self.export(id)?, associated_data, tag_len)
+    }
+    
+    /// Decrypt the output of `seal`
+    fn open(&self, id: &str, nonce: &[u8; NONCE_SIZE], data: &[u8], associated_data: &[u8],
+            tag_len: usize) -> Result<Vec<u8>> {
+        crypto::decrypt_truncated(data, nonce, &self.export(id)?, associated_data, tag_len)
+    }
+    
+    /// Compute a MAC truncated to `tag_len` bytes with an encryption key
+    fn mac(&self, id: &str, message: &[u8], tag_len: usize) -> Result<Vec<u8>> {
+        crypto::compute_mac(message, &self.export(id)?, tag_len)
+    }
+    
+    /// Verify a tag produced by `mac`
+    fn verify_mac(&self, id: &str, message: &[u8], tag: &[u8]) -> Result<()> {
+        crypto::verify_mac(message, tag, &self.export(id)?)
+    }
+    
+    /// Derive key material from an encryption key with HKDF-SHA256
+    ///
+    /// The derived material is returned to the caller, like a PKCS#11
+    /// derived key created extractable.
+    fn derive(&self, id: &str, salt: Option<&[u8]>, info: &[u8], len: usize) -> Result<SecretBytes> {
+        crypto::derive_key(&self.export(id)?, salt, info, len)
+    }
+    
+    /// Re-protect the stored keys under a new secret
+    fn change_secret(&self, _secret: &KeyStoreSecret) -> Result<()> {
+        Err(anyhow!(SecurityError::KeyError(
+            format!("Key store {} is not sealed with a secret", self.describe())
+        )))
+    }
+}
+
+/// Open the key store selected by the security configuration
+pub fn open_key_store(config: &SecurityConfig) -> Result<Box<dyn KeyStore>> {
+    match (&config.key_store_backend, &config.key_storage_path) {
+        (KeyStoreBackend::EncryptedFile, Some(path)) => {
+            let source = config.key_store_key.as_ref()
+                .ok_or_else(|| anyhow!("No key store key source configured"))?;
+            let secret = KeyStoreSecret::from_source(source)?;
+            
+            Ok(Box::new(EncryptedFileKeyStore::open(path, &secret, config.key_store_backups)
+                .with_context(|| format!("Failed to open persistent key store {}", path))?))
+        },
+        
+        (KeyStoreBackend::EncryptedFile, None) | (KeyStoreBackend::Memory, _) => Ok(Box::new(MemoryKeyStore::new())),
+        
+        (KeyStoreBackend::PlainFile, Some(path)) => {
+            warn!("Key store {} is not encrypted", path);
+            Ok(Box::new(PlainFileKeyStore::open(path, config.key_store_backups)
+                .with_context(|| format!("Failed to open plain key store {}", path))?))
+        },
+        
+        (KeyStoreBackend::PlainFile, None) => Err(anyhow!("The plain file key store needs a key storage path")),
+        
+        (KeyStoreBackend::SoftwareToken { label, pin_env }, _) => {
+            let pin = SecretBytes::new(std::env::var(pin_env)
+                .with_context(|| format!("Token PIN variable {} is not set", pin_env))?
+                .into_bytes());
+            
+            let token = SoftwareToken::new(label, pin.expose());
+            token.login(pin.expose())?;
+            Ok(Box::new(token))
+        },
+    }
+}
+
+/// Keys held in memory only
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    keys: RwLock<HashMap<String, KeyEntry>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    fn from_keys(keys: HashMap<String, KeyEntry>) -> Self {
+        Self {
+            keys: RwLock::new(keys),
+        }
+    }
+    
+    /// Serialize all keys in the key store file format
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let keys = self.keys.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
+        
+        bincode::serialize(&*keys)
+            .context("Failed to serialize key store")
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn describe(&self) -> String {
+        "in memory".to_string()
+    }
+    
+    fn metadata(&self, id: &str) -> Result<Option<KeyMetadata>> {
+        let keys = self.keys.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
+        
+        Ok(keys.get(id).map(|entry| entry.metadata.clone()))
+    }
+    
+    fn list(&self) -> Result<Vec<KeyMetadata>> {
+        let keys = self.keys.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
+        
+        Ok(keys.values().map(|entry| entry.metadata.clone()).collect())
+    }
+    
+    fn insert(&self, entries: Vec<KeyEntry>) -> Result<()> {
+        let mut keys = self.keys.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
+        
+        for entry in entries {
+            keys.insert(entry.metadata.id.clone(), entry);
+        }
+        
+        Ok(())
+    }
+    
+    fn set_expiry(&self, id: &str, expires_at: Option<u64>) -> Result<()> {
+        let mut keys = self.keys.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
+        
+        let entry = keys.get_mut(id)
+            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
+        entry.metadata.expires_at = expires_at;
+        
+        Ok(())
+    }
+    
+    fn set_usage(&self, id: &str, usage: KeyUsage) -> Result<()> {
+        let mut keys = self.keys.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
+        
+        let entry = keys.get_mut(id)
+            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
+        entry.metadata.usage = usage;
+        
+        Ok(())
+    }
+    
+    fn remove(&self, id: &str) -> Result<bool> {
+        let mut keys = self.keys.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
+        
+        Ok(keys.remove(id).is_some())
+    }
+    
+    fn export(&self, id: &str) -> Result<SecretBytes> {
+        let keys = self.keys.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
+        
+        keys.get(id)
+            .map(|entry| entry.key_data.clone())
+            .ok_or_else(|| anyhow!(SecurityError::KeyError(format!("Key not found: {}", id))))
+    }
+}
+
+/// How a file-backed key store encodes its contents on disk
+pub trait FileFormat: Send + Sync {
+    /// Encode serialized keys for writing
+    fn encode(&self, plaintext: &[u8]) -> Result<Vec<u8>>;
+    
+    /// Switch to a new secret for the write made by `save`, keeping the old one if it fails
+    fn change_secret(&self, _secret: &KeyStoreSecret, _save: &dyn Fn() -> Result<()>) -> Result<()> {
+        Err(anyhow!(SecurityError::KeyError("Key store file is not sealed with a secret".into())))
+    }
+}
+
+/// Serialized keys written as they are
+pub struct PlainFile;
+
+impl FileFormat for PlainFile {
+    fn encode(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// Serialized keys sealed under a key-encryption key, see `key_store`
+pub struct SealedFile {
+    sealing_key: RwLock<SealingKey>,
+}
+
+impl FileFormat for SealedFile {
+    fn encode(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let sealing_key = self.sealing_key.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on sealing key"))?;
+        
+        key_store::seal(plaintext, &sealing_key)
+    }
+    
+    fn change_secret(&self, secret: &KeyStoreSecret, save: &dyn Fn() -> Result<()>) -> Result<()> {
+        let new_key = SealingKey::derive(secret)?;
+        let previous = std::mem::replace(&mut *self.sealing_key.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on sealing key"))?, new_key);
+        
+        if let Err(e) = save() {
+            *self.sealing_key.write()
+                .map_err(|_| anyhow!("Failed to acquire write lock on sealing key"))? = previous;
+            return Err(e);
+        }
+        
+        Ok(())
+    }
+}
+
+/// Keys held in memory and written to a file after every change
+///
+/// The file is replaced atomically, keeping previous generations as
+/// backups. If it cannot be read on open, the newest readable backup is
+/// used instead and the file is re-written from it.
+pub struct FileKeyStore<F: FileFormat> {
+    keys: MemoryKeyStore,
+    path: PathBuf,
+    backups: usize,
+    format: F,
+}
+
+/// Unencrypted key store file, as written by older versions
+pub type PlainFileKeyStore = FileKeyStore<PlainFile>;
+
+/// Key store file sealed under a passphrase or key
+pub type EncryptedFileKeyStore = FileKeyStore<SealedFile>;
+
+impl PlainFileKeyStore {
+    /// Open or create a plain key store file keeping `backups` previous generations
+    pub fn open<P: AsRef<Path>>(path: P, backups: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        
+        let loaded = recover(&path, backups, |candidate| {
+            let data = fs::read(candidate)
+                .context("Failed to read key store file")?;
+            
+            if key_store::is_sealed(&data) {
+                return Err(anyhow!(SecurityError::KeyError("Key store file is sealed".into())));
+            }
+            
+            deserialize_keys(&data)
+        })?;
+        
+        let (keys, rewrite) = loaded.unwrap_or_default();
+        Self::create(path, backups, keys, PlainFile, rewrite)
+    }
+}
+
+impl EncryptedFileKeyStore {
+    /// Open or create a sealed key store file keeping `backups` previous generations
+    ///
+    /// A store written by an older version in plaintext is loaded and
+    /// immediately re-written sealed. Fails if a store or backup exists but
+    /// none of them can be opened.
+    pub fn open<P: AsRef<Path>>(path: P, secret: &KeyStoreSecret, backups: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        
+        let loaded = recover(&path, backups, |candidate| Self::load(candidate, secret))?;
+        
+        let ((keys, sealing_key, migrate), recovered) = match loaded {
+            Some(loaded) => loaded,
+            None => ((HashMap::new(), SealingKey::derive(secret)?, false), false),
+        };
+        
+        let format = SealedFile {
+            sealing_key: RwLock::new(sealing_key),
+        };
+        Self::create(path, backups, keys, format, migrate || recovered)
+    }
+    
+    /// Load one store file, returning its keys, sealing key and whether it needs sealing
+    fn load(path: &Path, secret: &KeyStoreSecret) -> Result<(HashMap<String, KeyEntry>, SealingKey, bool)> {
+        let data = fs::read(path)
+            .context("Failed to read key store file")?;
+        
+        if key_store::is_sealed(&data) {
+            let (plaintext, sealing_key) = key_store::open(&data, secret)?;
+            let keys = deserialize_keys(&plaintext)?;
+            return Ok((keys, sealing_key, false));
+        }
+        
+        let keys = deserialize_keys(&data)?;
+        warn!("Key store {} is not encrypted, sealing it now", path.display());
+        
+        Ok((keys, SealingKey::derive(secret)?, true))
+    }
+}
+
+impl<F: FileFormat> FileKeyStore<F> {
+    fn create(path: PathBuf, backups: usize, keys: HashMap<String, KeyEntry>, format: F, rewrite: bool) -> Result<Self> {
+        let store = Self {
+            keys: MemoryKeyStore::from_keys(keys),
+            path,
+            backups,
+            format,
+        };
+        
+        if rewrite {
+            store.save()?;
+        }
+        
+        Ok(store)
+    }
+    
+    /// Write all keys to the file
+    fn save(&self) -> Result<()> {
+        let data = self.format.encode(&self.keys.serialize()?)?;
+        
+        // Create parent directory if it doesn't exist
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .context("Failed to create key store directory")?;
+        }
+        
+        // Replace the file atomically, keeping previous generations
+        key_store::write_atomic(&self.path, &data, self.backups)
+            .context("Failed to write key store file")
+    }
+}
+
+impl<F: FileFormat> KeyStore for FileKeyStore<F> {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+    
+    fn metadata(&self, id: &str) -> Result<Option<KeyMetadata>> {
+        self.keys.metadata(id)
+    }
+    
+    fn list(&self) -> Result<Vec<KeyMetadata>> {
+        self.keys.list()
+    }
+    
+    fn insert(&self, entries: Vec<KeyEntry>) -> Result<()> {
+        self.keys.insert(entries)?;
+        self.save()
+    }
+    
+    fn set_expiry(&self, id: &str, expires_at: Option<u64>) -> Result<()> {
+        self.keys.set_expiry(id, expires_at)?;
+        self.save()
+    }
+    
+    fn set_usage(&self, id: &str, usage: KeyUsage) -> Result<()> {
+        self.keys.set_usage(id, usage)?;
+        self.save()
+    }
+    
+    fn remove(&self, id: &str) -> Result<bool> {
+        if !self.keys.remove(id)? {
+            return Ok(false);
+        }
+        
+        self.save()?;
+        Ok(true)
+    }
+    
+    fn export(&self, id: &str) -> Result<SecretBytes> {
+        self.keys.export(id)
+    }
+    
+    /// Re-seal the file under a new secret
+    ///
+    /// Backups sealed under the old secret are deleted, since they would
+    /// otherwise keep it usable.
+    fn change_secret(&self, secret: &KeyStoreSecret) -> Result<()> {
+        self.format.change_secret(secret, &|| self.save())?;
+        
+        for generation in 1..=self.backups {
+            let backup = key_store::backup_path(&self.path, generation);
+            if backup.exists() {
+                fs::remove_file(&backup)
+                    .with_context(|| format!("Failed to remove old backup {}", backup.display()))?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+/// Key entry as written before key usage was recorded with it
+#[derive(Deserialize)]
+struct LegacyKeyEntry {
+    metadata: LegacyKeyMetadata,
+    key_data: SecretBytes,
+}
+
+/// Key metadata as written before key usage was recorded with it
+#[derive(Deserialize)]
+struct LegacyKeyMetadata {
+    id: String,
+    key_type: KeyType,
+    created_at: u64,
+    expires_at: Option<u64>,
+    description: String,
+}
+
+/// Deserialize the keys of a store file
+///
+/// Files written before key usage was recorded are read with zero usage.
+fn deserialize_keys(data: &[u8]) -> Result<HashMap<String, KeyEntry>> {
+    let options = bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes();
+    if let Ok(keys) = options.deserialize(data) {
+        return Ok(keys);
+    }
+    
+    let legacy: HashMap<String, LegacyKeyEntry> = options.deserialize(data)
+        .context("Failed to deserialize key store")?;
+    
+    Ok(legacy.into_iter().map(|(id, entry)| (id, KeyEntry {
+        metadata: KeyMetadata {
+            id: entry.metadata.id,
+            key_type: entry.metadata.key_type,
+            created_at: entry.metadata.created_at,
+            expires_at: entry.metadata.expires_at,
+            description: entry.metadata.description,
+            usage: KeyUsage::default(),
+        },
+        key_data: entry.key_data,
+    })).collect())
+}
+
+/// Load the store at `path`, falling back to its backups
+///
+/// Returns the loaded contents and whether they came from a backup, or None
+/// if neither the store nor any backup exists. An unreadable store is set
+/// aside so that it does not displace a good backup on the next write.
+fn recover<T>(path: &Path, backups: usize, load: impl Fn(&Path) -> Result<T>) -> Result<Option<(T, bool)>> {
+    let candidates: Vec<PathBuf> = std::iter::once(path.to_path_buf())
+        .chain((1..=backups).map(|generation| key_store::backup_path(path, generation)))
+        .filter(|candidate| candidate.exists())
+        .collect();
+    
+    let mut first_error = None;
+    
+    for candidate in &candidates {
+        match load(candidate) {
+            Ok(loaded) => {
+                let recovered = candidate != path;
+                if recovered {
+                    warn!("Recovered key store from backup {}", candidate.display());
+                    
+                    if path.exists() {
+                        let mut corrupt = path.as_os_str().to_owned();
+                        corrupt.push(".corrupt");
+                        fs::rename(path, PathBuf::from(corrupt))
+                            .context("Failed to set aside unreadable key store")?;
+                    }
+                }
+                return Ok(Some((loaded, recovered)));
+            },
+            Err(e) => {
+                warn!("Failed to load key store {}: {:#}", candidate.display(), e);
+                first_error.get_or_insert(e);
+            },
+        }
+    }
+    
+    match first_error {
+        Some(e) => Err(e.context(format!(
+            "Failed to load key store {} or any of its backups", path.display()
+        ))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    
+    fn create_metadata(id: &str, key_type: KeyType) -> KeyMetadata {
+        KeyMetadata {
+            id: id.to_string(),
+            key_type,
+            created_at: 0,
+            expires_at: None,
+            description: String::new(),
+            usage: KeyUsage::default(),
+        }
+    }
+    
+    #[test]
+    fn test_plain_file_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+        
+        {
+            let store = PlainFileKeyStore::open(&path, 1).unwrap();
+            store.generate_key(create_metadata("enc", KeyType::Encryption)).unwrap();
+            store.generate_keypair(create_metadata("pair-signing", KeyType::Signing),
+                create_metadata("pair-verify", KeyType::Verification)).unwrap();
+            assert!(store.remove("enc").unwrap());
+            assert!(!store.remove("enc").unwrap());
+        }
+        
+        let store = PlainFileKeyStore::open(&path, 1).unwrap();
+        assert_eq!(store.list().unwrap().len(), 2);
+        assert!(store.metadata("enc").unwrap().is_none());
+        assert!(store.change_secret(&KeyStoreSecret::passphrase("secret")).is_err());
+        
+        // Software backends sign with the stored key
+        let signature = store.sign("pair-signing", b"message").unwrap();
+        crypto::verify_signature(b"message", &signature, store.export("pair-verify").unwrap().expose()).unwrap();
+        
+        // A sealed store is never mistaken for a plain one
+        let sealed = dir.path().join("sealed.bin");
+        drop(EncryptedFileKeyStore::open(&sealed, &KeyStoreSecret::passphrase("secret"), 0).unwrap().insert(vec![]));
+        assert!(PlainFileKeyStore::open(&sealed, 0).is_err());
+    }
+    
+    #[test]
+    fn test_store_without_usage_loaded() {
+        #[derive(serde::Serialize)]
+        struct OldMetadata { id: String, key_type: KeyType, created_at: u64, expires_at: Option<u64>, description: String }
+        #[derive(serde::Serialize)]
+        struct OldEntry { metadata: OldMetadata, key_data: SecretBytes }
+        
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+        let old: HashMap<String, OldEntry> = ["a", "b"].iter().map(|id| (id.to_string(), OldEntry {
+            metadata: OldMetadata { id: id.to_string(), key_type: KeyType::Encryption, created_at: 1, expires_at: None,
+                                    description: "old".into() },
+            key_data: crypto::generate_encryption_key(),
+        })).collect();
+        fs::write(&path, bincode::serialize(&old).unwrap()).unwrap();
+        
+        let store = PlainFileKeyStore::open(&path, 0).unwrap();
+        assert_eq!(store.list().unwrap().len(), 2);
+        assert_eq!(store.metadata("a").unwrap().unwrap().usage, KeyUsage::default());
+        
+        // The usage is written with the entry from then on
+        let usage = KeyUsage { encryptions: 3, bytes: 30 };
+        store.set_usage("a", usage).unwrap();
+        assert_eq!(PlainFileKeyStore::open(&path, 0).unwrap().metadata("a").unwrap().unwrap().usage, usage);
+    }
+}
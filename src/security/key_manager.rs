@@ -1,17 +1,26 @@
 //! Key management system
 //!
-//! This module provides secure storage and management for cryptographic keys.
+//! This module decides which keys may be used for what: key types, expiry,
+//! session keys and peer trust. Storing the keys is left to a `KeyStore`
+//! backend, see `key_backend`; persistent stores are sealed on disk, see
+//! `key_store`.
 
-use anyhow::{anyhow, Context, Result};
-use rand::{rngs::OsRng, RngCore};
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::config::KeyUsageLimits;
 use crate::security::{SecurityError, crypto};
+use crate::security::crypto::{CipherSuite, NONCE_SIZE};
+use crate::security::key_backend::{EncryptedFileKeyStore, KeyStore, MemoryKeyStore};
+use crate::security::key_format::{self, KeyFormat, KeyUnwrapping, KeyWrapping};
+use crate::security::key_store::{self, KeyStoreSecret};
+use crate::security::secret::SecretBytes;
+use crate::security::trust::{Endorsement, PeerRole, TrustStore};
 
 /// Key metadata
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -21,6 +30,10 @@ pub struct KeyMetadata {
     pub created_at: u64,
     pub expires_at: Option<u64>,
     pub description: String,
+    
+    /// Encryptions performed with the key, as last persisted by its store
+    #[serde(default)]
+    pub usage: KeyUsage,
 }
 
 /// Types of keys supported by the system
@@ -36,167 +49,183 @@ pub enum KeyType {
     Verification,
 }
 
+impl KeyType {
+    pub(crate) fn describe(&self) -> &'static str {
+        match self {
+            KeyType::Encryption => "an encryption key",
+            KeyType::Signing => "a signing key",
+            KeyType::Verification => "a verification key",
+        }
+    }
+}
+
 /// Key entry in the key store
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct KeyEntry {
+pub struct KeyEntry {
     pub metadata: KeyMetadata,
-    pub key_data: Vec<u8>,
+    pub key_data: SecretBytes,
+}
+
+/// Encryptions performed with a key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyUsage {
+    /// Messages encrypted with a suite using short random nonces
+    pub encryptions: u64,
+    
+    /// Plaintext bytes encrypted
+    pub bytes: u64,
+}
+
+impl KeyUsage {
+    /// Largest fraction of a limit used, in percent
+    fn percent_of(&self, limits: &KeyUsageLimits) -> u64 {
+        let percent = |used: u64, limit: Option<u64>| limit
+            .map_or(0, |limit| (used as u128 * 100 / limit as u128) as u64);
+        percent(self.encryptions, limits.max_encryptions).max(percent(self.bytes, limits.max_bytes))
+    }
+}
+
+/// Fraction of a usage limit reserved in the key store at a time
+///
+/// Usage is persisted ahead of the count, so the store is re-written about
+/// this many times over a key's lifetime and a restart can only overstate
+/// the usage, by at most this fraction of the limit.
+const USAGE_RESERVATION_DIVISOR: u64 = 64;
+
+/// Encryptions reserved at a time for keys without a message limit
+const UNLIMITED_ENCRYPTION_RESERVATION: u64 = 1 << 16;
+
+/// Bytes reserved at a time for keys without a byte limit
+const UNLIMITED_BYTE_RESERVATION: u64 = 1 << 26;
+
+/// Usage of a key counted in memory and reserved in its store entry
+#[derive(Clone, Copy, Debug, Default)]
+struct UsageRecord {
+    used: KeyUsage,
+    reserved: KeyUsage,
+}
+
+/// Next persisted value for a count, capped at its limit
+fn reserve(used: u64, limit: Option<u64>, unlimited: u64) -> u64 {
+    match limit {
+        Some(limit) => used.saturating_add((limit / USAGE_RESERVATION_DIVISOR).max(1)).min(limit.max(used)),
+        None => used.saturating_add(unlimited),
+    }
 }
 
 /// Manages cryptographic keys for the system
 pub struct KeyManager {
-    /// Storage for keys
-    keys: Arc<RwLock<HashMap<String, KeyEntry>>>,
+    /// Backend holding long-term keys
+    store: Box<dyn KeyStore>,
     
-    /// Path to key storage file
-    storage_path: Option<PathBuf>,
+    /// Session-scoped keys, which never reach the backend
+    sessions: MemoryKeyStore,
     
-    /// Whether changes should be persisted to disk
-    persistent: bool,
+    /// Root authority for peer verification keys, if provenance is enforced
+    trust_store: Option<TrustStore>,
+    
+    /// Limits on encryptions per key
+    usage_limits: KeyUsageLimits,
+    
+    /// Encryptions per key ID, loaded from the key's store entry on first use
+    usage: Mutex<HashMap<String, UsageRecord>>,
+}
+
+impl Default for KeyManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 impl KeyManager {
     /// Create a new in-memory key manager (non-persistent)
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryKeyStore::new()))
+    }
+    
+    /// Create a key manager on top of a key storage backend
+    pub fn with_store(store: Box<dyn KeyStore>) -> Self {
         Self {
-            keys: Arc::new(RwLock::new(HashMap::new())),
-            storage_path: None,
-            persistent: false,
+            store,
+            sessions: MemoryKeyStore::new(),
+            trust_store: None,
+            usage_limits: KeyUsageLimits::default(),
+            usage: Mutex::new(HashMap::new()),
         }
     }
     
-    /// Create a persistent key manager that stores keys on disk
-    pub fn new_persistent<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let storage_path = path.as_ref().to_path_buf();
-        let keys = if storage_path.exists() {
-            // Load existing keys from file
-            let data = fs::read(&storage_path)
-                .context("Failed to read key store file")?;
-                
-            // Deserialize the key store
-            bincode::deserialize(&data)
-                .context("Failed to deserialize key store")?
-        } else {
-            // Start with empty key store
-            HashMap::new()
-        };
-        
-        Ok(Self {
-            keys: Arc::new(RwLock::new(keys)),
-            storage_path: Some(storage_path),
-            persistent: true,
-        })
+    /// Create a persistent key manager that stores keys on disk, sealed under `secret`
+    ///
+    /// A key store written by an older version in plaintext is loaded and
+    /// immediately re-written sealed.
+    pub fn new_persistent<P: AsRef<Path>>(path: P, secret: &KeyStoreSecret) -> Result<Self> {
+        Self::new_persistent_with_backups(path, secret, key_store::DEFAULT_BACKUP_GENERATIONS)
     }
     
-    /// Save the key store to disk (if persistent)
-    fn save(&self) -> Result<()> {
-        if !self.persistent {
-            return Ok(());
-        }
-        
-        if let Some(path) = &self.storage_path {
-            let keys = self.keys.read()
-                .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
-                
-            // Serialize the key store
-            let data = bincode::serialize(&*keys)
-                .context("Failed to serialize key store")?;
-                
-            // Create parent directory if it doesn't exist
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)
-                    .context("Failed to create key store directory")?;
-            }
-            
-            // Write to file
-            fs::write(path, data)
-                .context("Failed to write key store file")?;
-        }
-        
-        Ok(())
+    /// Create a persistent key manager keeping `backups` previous generations of the store
+    ///
+    /// If the store cannot be read, the newest readable backup is used
+    /// instead and the store is re-written from it. Fails if a store or
+    /// backup exists but none of them can be opened.
+    pub fn new_persistent_with_backups<P: AsRef<Path>>(path: P, secret: &KeyStoreSecret, 
+                                                      backups: usize) -> Result<Self> {
+        Ok(Self::with_store(Box::new(EncryptedFileKeyStore::open(path, secret, backups)?)))
+    }
+    
+    /// Re-seal the key store under a new passphrase or key
+    ///
+    /// Backups sealed under the old secret are deleted, since they would
+    /// otherwise keep it usable.
+    pub fn change_passphrase(&self, new_secret: &KeyStoreSecret) -> Result<()> {
+        self.store.change_secret(new_secret)
     }
     
     /// Generate a new encryption key
     pub fn generate_encryption_key(&self, id: &str, description: &str, ttl_days: Option<u64>) -> Result<()> {
-        // Generate random key
-        let key_data = crypto::generate_encryption_key().to_vec();
-        
-        // Calculate expiration time
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-            
-        let expires_at = ttl_days.map(|days| {
-            now + days * 24 * 60 * 60
-        });
+        let now = now_secs();
         
-        // Create metadata
-        let metadata = KeyMetadata {
+        self.store.generate_key(KeyMetadata {
             id: id.to_string(),
             key_type: KeyType::Encryption,
             created_at: now,
-            expires_at,
+            expires_at: ttl_days.map(|days| now + days * 24 * 60 * 60),
             description: description.to_string(),
-        };
-        
-        // Store the key
-        let mut keys = self.keys.write()
-            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
-            
-        keys.insert(id.to_string(), KeyEntry { metadata, key_data });
-        
-        // Save changes
-        drop(keys);
-        self.save()
+            usage: KeyUsage::default(),
+        })?;
+        self.reset_usage(id)
     }
     
     /// Generate a new signing/verification key pair
     pub fn generate_keypair(&self, id: &str, description: &str, ttl_days: Option<u64>) -> Result<()> {
-        // Generate Ed25519 keypair
-        let (private_key, public_key) = crypto::generate_signing_keypair()?;
-        
-        // Calculate expiration time
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-            
-        let expires_at = ttl_days.map(|days| {
-            now + days * 24 * 60 * 60
-        });
+        let now = now_secs();
+        let expires_at = ttl_days.map(|days| now + days * 24 * 60 * 60);
         
-        // Create metadata for signing key
         let signing_metadata = KeyMetadata {
             id: format!("{}-signing", id),
             key_type: KeyType::Signing,
             created_at: now,
             expires_at,
             description: format!("{} (signing)", description),
+            usage: KeyUsage::default(),
         };
         
-        // Create metadata for verification key
         let verify_metadata = KeyMetadata {
             id: format!("{}-verify", id),
             key_type: KeyType::Verification,
             created_at: now,
             expires_at,
             description: format!("{} (verification)", description),
+            usage: KeyUsage::default(),
         };
         
-        // Store the keys
-        let mut keys = self.keys.write()
-            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
-            
-        keys.insert(signing_metadata.id.clone(), 
-            KeyEntry { metadata: signing_metadata, key_data: private_key });
-            
-        keys.insert(verify_metadata.id.clone(),
-            KeyEntry { metadata: verify_metadata, key_data: public_key });
-        
-        // Save changes
-        drop(keys);
-        self.save()
+        self.store.generate_keypair(signing_metadata, verify_metadata)
     }
     
     /// Import an existing key
@@ -221,6 +250,12 @@ impl KeyManager {
                 }
             },
             KeyType::Verification => {
+                if self.trust_store.is_some() {
+                    return Err(anyhow!(SecurityError::KeyError(
+                        format!("Verification key {} must be imported with an endorsement", id)
+                    )));
+                }
+                
                 if key_data.len() != crypto::ED25519_PUBLIC_KEY_SIZE {
                     return Err(anyhow!(SecurityError::KeyError(
                         format!("Invalid verification key size: {} (expected {})",
@@ -230,158 +265,383 @@ impl KeyManager {
             },
         }
         
-        // Calculate expiration time
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-            
-        let expires_at = ttl_days.map(|days| {
-            now + days * 24 * 60 * 60
-        });
-        
-        // Create metadata
+        let now = now_secs();
         let metadata = KeyMetadata {
             id: id.to_string(),
             key_type,
             created_at: now,
-            expires_at,
+            expires_at: ttl_days.map(|days| now + days * 24 * 60 * 60),
             description: description.to_string(),
+            usage: KeyUsage::default(),
         };
         
-        // Store the key
-        let mut keys = self.keys.write()
-            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
-            
-        keys.insert(id.to_string(), KeyEntry { 
+        self.store.insert(vec![KeyEntry { 
             metadata, 
-            key_data: key_data.to_vec(),
-        });
+            key_data: SecretBytes::from_slice(key_data),
+        }])?;
+        self.reset_usage(id)
+    }
+    
+    /// Import a key given in a standard format
+    ///
+    /// Wrapped keys, as written by `export_key`, are unwrapped first and
+    /// need `unwrapping`; the format is that of the key inside.
+    #[allow(clippy::too_many_arguments)]
+    pub fn import_encoded_key(&self, id: &str, key_type: KeyType, data: &[u8], format: KeyFormat,
+                              unwrapping: Option<&KeyUnwrapping>, description: &str, ttl_days: Option<u64>) -> Result<()> {
+        let unwrapped = if key_format::is_wrapped(data) {
+            let unwrapping = unwrapping.ok_or_else(|| anyhow!(SecurityError::KeyError(
+                format!("Key {} is wrapped; a password or recipient key is needed", id)
+            )))?;
+            Some(key_format::unwrap_key(data, unwrapping)?)
+        } else {
+            None
+        };
         
-        // Save changes
-        drop(keys);
-        self.save()
+        let key_data = key_format::decode_key(key_type, unwrapped.as_ref().map_or(data, |key| key.expose()), format)?;
+        self.import_key(id, key_type, key_data.expose(), description, ttl_days)
     }
     
-    /// Get an encryption key by ID
-    pub fn get_encryption_key(&self, id: &str) -> Result<Vec<u8>> {
-        let keys = self.keys.read()
-            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
-            
-        let entry = keys.get(id)
-            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
-            
-        if entry.metadata.key_type != KeyType::Encryption {
+    /// Export a key in a standard format
+    ///
+    /// Signing and encryption keys are only exported wrapped; verification
+    /// keys are public and exported as-is unless wrapping is given. Session
+    /// keys and keys held by a backend that does not allow export cannot be
+    /// exported.
+    pub fn export_key(&self, id: &str, format: KeyFormat, wrapping: Option<&KeyWrapping>) -> Result<Vec<u8>> {
+        if self.is_session_key(id)? {
             return Err(anyhow!(SecurityError::KeyError(
-                format!("Key {} is not an encryption key", id)
+                format!("Session key {} cannot be exported", id)
             )));
         }
         
-        // Check if key is expired
-        if let Some(expires_at) = entry.metadata.expires_at {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-                
-            if now > expires_at {
-                return Err(anyhow!(SecurityError::KeyError(
-                    format!("Key {} has expired", id)
-                )));
-            }
+        let metadata = self.get_metadata(id)?;
+        if metadata.key_type != KeyType::Verification && wrapping.is_none() {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Key {} is secret and must be exported wrapped", id)
+            )));
+        }
+        
+        let encoded = key_format::encode_key(metadata.key_type, &self.store.export(id)?, format)?;
+        match wrapping {
+            Some(wrapping) => key_format::wrap_key(&encoded, wrapping),
+            None => Ok(encoded.expose().to_vec()),
+        }
+    }
+    
+    /// Install a session-scoped encryption key
+    ///
+    /// Session keys are usable like any other encryption key but are never
+    /// persisted, so they disappear when the gateway restarts. Installing a
+    /// key under an existing session ID replaces it.
+    pub fn install_session_key(&self, id: &str, key_data: &SecretBytes, description: &str) -> Result<()> {
+        if key_data.len() != crypto::CHACHA_KEY_SIZE {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Invalid session key size: {} (expected {})",
+                    key_data.len(), crypto::CHACHA_KEY_SIZE)
+            )));
+        }
+        
+        let metadata = KeyMetadata {
+            id: id.to_string(),
+            key_type: KeyType::Encryption,
+            created_at: now_secs(),
+            expires_at: None,
+            description: description.to_string(),
+            usage: KeyUsage::default(),
+        };
+        
+        self.sessions.insert(vec![KeyEntry { 
+            metadata, 
+            key_data: key_data.clone(),
+        }])?;
+        self.reset_usage(id)
+    }
+    
+    /// Check whether a key is session-scoped
+    pub fn is_session_key(&self, id: &str) -> Result<bool> {
+        Ok(self.sessions.metadata(id)?.is_some())
+    }
+    
+    /// Find the store holding a key, session keys first
+    fn locate(&self, id: &str) -> Result<(&dyn KeyStore, KeyMetadata)> {
+        if let Some(metadata) = self.sessions.metadata(id)? {
+            return Ok((&self.sessions, metadata));
+        }
+        
+        match self.store.metadata(id)? {
+            Some(metadata) => Ok((self.store.as_ref(), metadata)),
+            None => Err(anyhow!(SecurityError::KeyError(format!("Key not found: {}", id)))),
+        }
+    }
+    
+    /// Find the store holding a key of the given type, failing if it has expired
+    fn usable(&self, id: &str, key_type: KeyType) -> Result<&dyn KeyStore> {
+        let (store, metadata) = self.locate(id)?;
+        
+        if metadata.key_type != key_type {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Key {} is not {}", id, key_type.describe())
+            )));
+        }
+        
+        if metadata.expires_at.is_some_and(|expires_at| now_secs() > expires_at) {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Key {} has expired", id)
+            )));
         }
         
-        Ok(entry.key_data.clone())
+        Ok(store)
+    }
+    
+    /// Get an encryption key by ID
+    ///
+    /// Fails for keys held by a backend that does not allow export; prefer
+    /// the operations that run inside the backend, such as `encrypt`, `seal`,
+    /// `mac` and `derive`.
+    pub fn get_encryption_key(&self, id: &str) -> Result<SecretBytes> {
+        self.usable(id, KeyType::Encryption)?.export(id)
     }
     
     /// Get a signing key by ID
-    pub fn get_signing_key(&self, id: &str) -> Result<Vec<u8>> {
-        let keys = self.keys.read()
-            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
-            
-        let entry = keys.get(id)
-            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
-            
-        if entry.metadata.key_type != KeyType::Signing {
+    ///
+    /// Fails for keys held by a backend that does not allow export; prefer `sign`.
+    pub fn get_signing_key(&self, id: &str) -> Result<SecretBytes> {
+        self.usable(id, KeyType::Signing)?.export(id)
+    }
+    
+    /// Sign a message with a signing key, inside its backend
+    pub fn sign(&self, id: &str, message: &[u8]) -> Result<Vec<u8>> {
+        self.usable(id, KeyType::Signing)?.sign(id, message)
+    }
+    
+    /// Encrypt with an encryption key inside its backend, returning the ciphertext and nonce
+    ///
+    /// Fails once the key has reached its usage limits.
+    pub fn encrypt(&self, id: &str, suite: CipherSuite, plaintext: &[u8], associated_data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let store = self.usable(id, KeyType::Encryption)?;
+        self.record_usage(id, u64::from(suite.has_short_nonce()), plaintext.len() as u64)?;
+        store.encrypt(id, suite, plaintext, associated_data)
+    }
+    
+    /// Count encryptions against a key, refusing them if a limit would be exceeded
+    ///
+    /// Only messages under suites with short random nonces count against the
+    /// message limit. The usage is reserved ahead in the key's store entry,
+    /// so that it survives a restart.
+    pub(crate) fn record_usage(&self, id: &str, encryptions: u64, bytes: u64) -> Result<()> {
+        let mut usage = self.usage.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on key usage"))?;
+        let record = self.usage_record(&mut usage, id)?;
+        
+        let encryptions = record.used.encryptions.saturating_add(encryptions);
+        let bytes = record.used.bytes.saturating_add(bytes);
+        if self.usage_limits.max_encryptions.is_some_and(|max| encryptions > max)
+            || self.usage_limits.max_bytes.is_some_and(|max| bytes > max) {
             return Err(anyhow!(SecurityError::KeyError(
-                format!("Key {} is not a signing key", id)
+                format!("Key {} has reached its usage limit and must be rotated", id)
             )));
         }
         
-        // Check if key is expired
-        if let Some(expires_at) = entry.metadata.expires_at {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
+        if encryptions > record.reserved.encryptions || bytes > record.reserved.bytes {
+            let reserved = KeyUsage {
+                encryptions: reserve(encryptions, self.usage_limits.max_encryptions, UNLIMITED_ENCRYPTION_RESERVATION),
+                bytes: reserve(bytes, self.usage_limits.max_bytes, UNLIMITED_BYTE_RESERVATION),
+            };
+            self.locate(id)?.0.set_usage(id, reserved)?;
+            record.reserved = reserved;
+        }
+        
+        record.used = KeyUsage { encryptions, bytes };
+        Ok(())
+    }
+    
+    /// Usage record of a key, loaded from its store entry on first use
+    fn usage_record<'a>(&self, usage: &'a mut HashMap<String, UsageRecord>, id: &str) -> Result<&'a mut UsageRecord> {
+        match usage.entry(id.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let metadata = match self.sessions.metadata(id)? {
+                    Some(metadata) => Some(metadata),
+                    None => self.store.metadata(id)?,
+                };
+                let stored = metadata.map(|metadata| metadata.usage).unwrap_or_default();
                 
-            if now > expires_at {
-                return Err(anyhow!(SecurityError::KeyError(
-                    format!("Key {} has expired", id)
-                )));
-            }
+                Ok(entry.insert(UsageRecord { used: stored, reserved: stored }))
+            },
         }
+    }
+    
+    /// Forget the usage of a key ID whose key has been replaced or removed
+    fn reset_usage(&self, id: &str) -> Result<()> {
+        self.usage.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on key usage"))?
+            .remove(id);
+        Ok(())
+    }
+    
+    /// Set the usage limits of encryption keys
+    pub fn set_usage_limits(&mut self, limits: KeyUsageLimits) {
+        self.usage_limits = limits;
+    }
+    
+    /// Encryptions performed with a key
+    ///
+    /// After a restart this includes the usage reserved before it, see
+    /// `USAGE_RESERVATION_DIVISOR`.
+    pub fn key_usage(&self, id: &str) -> Result<KeyUsage> {
+        let mut usage = self.usage.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on key usage"))?;
+        Ok(self.usage_record(&mut usage, id)?.used)
+    }
+    
+    /// Check whether a key has used enough of its limits to be rotated
+    pub fn usage_rotation_due(&self, id: &str) -> Result<bool> {
+        let Some(threshold) = self.usage_limits.rotate_at_percent else {
+            return Ok(false);
+        };
         
-        Ok(entry.key_data.clone())
+        Ok(self.key_usage(id)?.percent_of(&self.usage_limits) >= threshold as u64)
+    }
+    
+    /// Decrypt with an encryption key, inside its backend
+    pub fn decrypt(&self, id: &str, suite: CipherSuite, ciphertext: &[u8], nonce: &[u8],
+                   associated_data: &[u8]) -> Result<Vec<u8>> {
+        self.usable(id, KeyType::Encryption)?.decrypt(id, suite, ciphertext, nonce, associated_data)
+    }
+    
+    /// Encrypt under a caller-chosen nonce with a truncated tag, inside the key's backend
+    ///
+    /// Counts against the key's usage limits like `encrypt`.
+    pub fn seal(&self, id: &str, nonce: &[u8; NONCE_SIZE], plaintext: &[u8], associated_data: &[u8],
+                tag_len: usize) -> Result<Vec<u8>> {
+        let store = self.usable(id, KeyType::Encryption)?;
+        self.record_usage(id, 1, plaintext.len() as u64)?;
+        store.seal(id, nonce, plaintext, associated_data, tag_len)
+    }
+    
+    /// Decrypt the output of `seal`, inside the key's backend
+    pub fn open(&self, id: &str, nonce: &[u8; NONCE_SIZE], data: &[u8], associated_data: &[u8],
+                tag_len: usize) -> Result<Vec<u8>> {
+        self.usable(id, KeyType::Encryption)?.open(id, nonce, data, associated_data, tag_len)
+    }
+    
+    /// Compute a truncated MAC with an encryption key, inside its backend
+    pub fn mac(&self, id: &str, message: &[u8], tag_len: usize) -> Result<Vec<u8>> {
+        self.usable(id, KeyType::Encryption)?.mac(id, message, tag_len)
+    }
+    
+    /// Verify a MAC with an encryption key, inside its backend
+    pub fn verify_mac(&self, id: &str, message: &[u8], tag: &[u8]) -> Result<()> {
+        self.usable(id, KeyType::Encryption)?.verify_mac(id, message, tag)
+    }
+    
+    /// Derive key material from an encryption key with HKDF-SHA256, inside its backend
+    pub fn derive(&self, id: &str, salt: Option<&[u8]>, info: &[u8], len: usize) -> Result<SecretBytes> {
+        self.usable(id, KeyType::Encryption)?.derive(id, salt, info, len)
     }
     
     /// Get a verification key by ID
+    ///
+    /// With a trust store set, only keys endorsed by the root authority and
+    /// the verification halves of locally held key pairs are served, and
+    /// revoked keys are refused.
     pub fn get_verification_key(&self, id: &str) -> Result<Vec<u8>> {
-        let keys = self.keys.read()
-            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
-            
-        let entry = keys.get(id)
-            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
-            
-        if entry.metadata.key_type != KeyType::Verification {
+        self.trusted_verification_key(id, None, PeerRole::Messaging)
+    }
+    
+    /// Get a verification key by ID that is trusted for `peer` in `role`
+    ///
+    /// Without a trust store this is the same as `get_verification_key`.
+    pub fn get_peer_verification_key(&self, id: &str, peer: &str, role: PeerRole) -> Result<Vec<u8>> {
+        self.trusted_verification_key(id, Some(peer), role)
+    }
+    
+    fn trusted_verification_key(&self, id: &str, peer: Option<&str>, role: PeerRole) -> Result<Vec<u8>> {
+        let Some(trust_store) = &self.trust_store else {
+            return self.stored_verification_key(id);
+        };
+        
+        if let Some(key) = trust_store.endorsed_key(id, peer, role)? {
+            return Ok(key);
+        }
+        
+        if !self.has_local_signing_key(id)? {
             return Err(anyhow!(SecurityError::KeyError(
-                format!("Key {} is not a verification key", id)
+                format!("Key {} is not endorsed by the root authority", id)
             )));
         }
         
-        // Check if key is expired
-        if let Some(expires_at) = entry.metadata.expires_at {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-                
-            if now > expires_at {
-                return Err(anyhow!(SecurityError::KeyError(
-                    format!("Key {} has expired", id)
-                )));
-            }
-        }
+        let key = self.stored_verification_key(id)?;
+        trust_store.check_not_revoked(&key)?;
         
-        Ok(entry.key_data.clone())
+        Ok(key)
+    }
+    
+    /// Whether `id` is the verification half of a key pair held locally
+    fn has_local_signing_key(&self, id: &str) -> Result<bool> {
+        let Some(base) = id.strip_suffix("-verify") else {
+            return Ok(false);
+        };
+        
+        Ok(self.locate(&format!("{}-signing", base))
+            .is_ok_and(|(_, metadata)| metadata.key_type == KeyType::Signing))
+    }
+    
+    fn stored_verification_key(&self, id: &str) -> Result<Vec<u8>> {
+        Ok(self.usable(id, KeyType::Verification)?.export(id)?.expose().to_vec())
+    }
+    
+    /// Require root authority endorsements for peer verification keys
+    ///
+    /// Verification keys imported without an endorsement are no longer
+    /// served once a trust store is set.
+    pub fn set_trust_store(&mut self, trust_store: TrustStore) {
+        self.trust_store = Some(trust_store);
+    }
+    
+    /// Trust store in use, if any
+    pub fn trust_store(&self) -> Option<&TrustStore> {
+        self.trust_store.as_ref()
+    }
+    
+    /// Import a peer verification key endorsed by the root authority
+    ///
+    /// Endorsements are held in memory only and must be re-imported on start.
+    pub fn import_endorsed_key(&self, id: &str, endorsement: Endorsement) -> Result<()> {
+        let trust_store = self.trust_store.as_ref()
+            .ok_or_else(|| anyhow!(SecurityError::ConfigError("No trust store configured".into())))?;
+        
+        trust_store.add_endorsement(id, endorsement)
+    }
+    
+    /// Get the metadata of a key by ID
+    pub fn get_metadata(&self, id: &str) -> Result<KeyMetadata> {
+        self.locate(id).map(|(_, metadata)| metadata)
+    }
+    
+    /// Set when a key expires (seconds since the Unix epoch, None = never)
+    pub fn set_expiry(&self, id: &str, expires_at: Option<u64>) -> Result<()> {
+        self.locate(id)?.0.set_expiry(id, expires_at)
     }
     
     /// List all keys
     pub fn list_keys(&self) -> Result<Vec<KeyMetadata>> {
-        let keys = self.keys.read()
-            .map_err(|_| anyhow!("Failed to acquire read lock on key store"))?;
-            
-        let metadata: Vec<KeyMetadata> = keys.values()
-            .map(|entry| entry.metadata.clone())
-            .collect();
-            
+        let mut metadata = self.store.list()?;
+        metadata.extend(self.sessions.list()?);
+        
         Ok(metadata)
     }
     
     /// Delete a key by ID
     pub fn delete_key(&self, id: &str) -> Result<()> {
-        let mut keys = self.keys.write()
-            .map_err(|_| anyhow!("Failed to acquire write lock on key store"))?;
-            
-        if keys.remove(id).is_none() {
-            return Err(anyhow!(SecurityError::KeyError(
-                format!("Key not found: {}", id)
-            )));
+        if self.sessions.remove(id)? || self.store.remove(id)? {
+            return self.reset_usage(id);
         }
         
-        // Save changes
-        drop(keys);
-        self.save()
+        Err(anyhow!(SecurityError::KeyError(
+            format!("Key not found: {}", id)
+        )))
     }
     
     /// Rotate an encryption key (generate new key and optionally delete old one)
@@ -423,7 +683,13 @@ impl KeyManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
     use tempfile::tempdir;
+    use crate::security::key_backend::PlainFileKeyStore;
+    
+    fn create_secret() -> KeyStoreSecret {
+        KeyStoreSecret::Key(SecretBytes::from_slice(&[7; crypto::CHACHA_KEY_SIZE]))
+    }
     
     #[test]
     fn test_key_generation() {
@@ -493,14 +759,14 @@ mod tests {
         
         // Create key manager and add some keys
         {
-            let km = KeyManager::new_persistent(&path).unwrap();
+            let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
             km.generate_encryption_key("test-enc", "Test encryption key", None).unwrap();
             km.generate_keypair("test-pair", "Test keypair", None).unwrap();
         }
         
         // Create new instance and verify keys were loaded
         {
-            let km = KeyManager::new_persistent(&path).unwrap();
+            let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
             
             let enc_key = km.get_encryption_key("test-enc").unwrap();
             assert_eq!(enc_key.len(), crypto::CHACHA_KEY_SIZE);
@@ -511,34 +777,142 @@ mod tests {
     }
     
     #[test]
-    fn test_key_expiration() {
-        let km = KeyManager::new();
+    fn test_usage_persisted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+        let limits = KeyUsageLimits { max_encryptions: Some(128), max_bytes: None, rotate_at_percent: None };
         
-        // Generate key that expired in the past
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-            
-        // Create metadata with past expiration
-        let metadata = KeyMetadata {
-            id: "expired-key".to_string(),
-            key_type: KeyType::Encryption,
-            created_at: now - 100,
-            expires_at: Some(now - 10),
-            description: "Expired key".to_string(),
-        };
+        {
+            let mut km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+            km.set_usage_limits(limits.clone());
+            km.generate_encryption_key("test-enc", "Test encryption key", None).unwrap();
+            for _ in 0..5 {
+                km.encrypt("test-enc", CipherSuite::ChaCha20Poly1305, b"data", b"").unwrap();
+            }
+            km.seal("test-enc", &[0; NONCE_SIZE], b"data", b"", 8).unwrap();
+            assert_eq!(km.key_usage("test-enc").unwrap(), KeyUsage { encryptions: 6, bytes: 24 });
+        }
+        
+        // After a restart the usage is reloaded, overstated by at most one reservation
+        let mut km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+        km.set_usage_limits(limits);
+        let usage = km.key_usage("test-enc").unwrap();
+        assert!((6..=6 + 128 / USAGE_RESERVATION_DIVISOR).contains(&usage.encryptions));
+        assert!(usage.bytes >= 24);
+        
+        // A replaced key starts from zero
+        km.generate_encryption_key("test-enc", "Replacement key", None).unwrap();
+        assert_eq!(km.key_usage("test-enc").unwrap(), KeyUsage::default());
+    }
+    
+    #[test]
+    fn test_store_sealed_on_disk() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+        let passphrase = KeyStoreSecret::passphrase("initial passphrase");
+        
+        let km = KeyManager::new_persistent(&path, &passphrase).unwrap();
+        km.import_key("known", KeyType::Encryption, &[0x5A; crypto::CHACHA_KEY_SIZE], "Known key", None).unwrap();
+        
+        // Key material never appears in the file
+        let data = fs::read(&path).unwrap();
+        assert!(key_store::is_sealed(&data));
+        assert!(!data.windows(crypto::CHACHA_KEY_SIZE).any(|w| w == [0x5A; crypto::CHACHA_KEY_SIZE]));
+        
+        // Changing the passphrase locks out the old one
+        let changed = KeyStoreSecret::passphrase("changed passphrase");
+        km.change_passphrase(&changed).unwrap();
+        drop(km);
+        
+        assert!(KeyManager::new_persistent(&path, &passphrase).is_err());
+        let km = KeyManager::new_persistent(&path, &changed).unwrap();
+        assert_eq!(km.get_encryption_key("known").unwrap().expose(), [0x5A; crypto::CHACHA_KEY_SIZE]);
+    }
+    
+    #[test]
+    fn test_recovery_from_backup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
         
-        // Create random key data
-        let mut key_data = vec![0u8; crypto::CHACHA_KEY_SIZE];
-        OsRng.fill_bytes(&mut key_data);
+        {
+            let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+            km.generate_encryption_key("first", "First key", None).unwrap();
+            km.generate_encryption_key("second", "Second key", None).unwrap();
+        }
+        
+        // Simulate a torn write of the primary store
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+        
+        // The newest backup predates the second key
+        let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+        assert!(km.get_encryption_key("first").is_ok());
+        assert!(km.get_encryption_key("second").is_err());
+        drop(km);
+        
+        // Recovery re-wrote a readable primary store and kept the good backup
+        assert!(KeyManager::new_persistent_with_backups(&path, &create_secret(), 0).is_ok());
+        assert!(KeyManager::new_persistent_with_backups(key_store::backup_path(&path, 1), &create_secret(), 0).is_ok());
+        assert!(dir.path().join("keys.bin.corrupt").exists());
+        
+        // Failing loudly rather than starting empty when nothing is readable
+        for generation in 0..=key_store::DEFAULT_BACKUP_GENERATIONS {
+            let file = if generation == 0 { path.clone() } else { key_store::backup_path(&path, generation) };
+            if file.exists() {
+                fs::write(&file, b"garbage").unwrap();
+            }
+        }
+        assert!(KeyManager::new_persistent(&path, &create_secret()).is_err());
+    }
+    
+    #[test]
+    fn test_plaintext_store_migrated() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+        
+        // Write a store the way older versions did
+        let legacy = KeyManager::with_store(Box::new(PlainFileKeyStore::open(&path, 0).unwrap()));
+        legacy.generate_encryption_key("legacy", "Legacy key", None).unwrap();
+        drop(legacy);
+        
+        let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+        assert!(km.get_encryption_key("legacy").is_ok());
+        assert!(key_store::is_sealed(&fs::read(&path).unwrap()));
+    }
+    
+    #[test]
+    fn test_session_keys_not_persisted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
         
-        // Add expired key
         {
-            let mut keys = km.keys.write().unwrap();
-            keys.insert("expired-key".to_string(), KeyEntry { metadata, key_data });
+            let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+            km.generate_encryption_key("long-term", "Long-term key", None).unwrap();
+            km.install_session_key("session", &crypto::generate_encryption_key(), "Session key").unwrap();
+            
+            assert!(km.is_session_key("session").unwrap());
+            assert!(!km.is_session_key("long-term").unwrap());
+            assert!(km.get_encryption_key("session").is_ok());
+            
+            // Saving for any reason must still leave the session key out
+            km.generate_encryption_key("another", "Another key", None).unwrap();
         }
         
+        let km = KeyManager::new_persistent(&path, &create_secret()).unwrap();
+        assert!(km.get_encryption_key("long-term").is_ok());
+        assert!(km.get_encryption_key("another").is_ok());
+        assert!(km.get_encryption_key("session").is_err());
+    }
+    
+    #[test]
+    fn test_key_expiration() {
+        let km = KeyManager::new();
+        
+        // Import a key that expired in the past
+        let now = now_secs();
+        km.import_key("expired-key", KeyType::Encryption, &[1; crypto::CHACHA_KEY_SIZE], "Expired key", None).unwrap();
+        km.set_expiry("expired-key", Some(now - 10)).unwrap();
+        
         // Attempt to retrieve the expired key should fail
         let result = km.get_encryption_key("expired-key");
         assert!(result.is_err());
@@ -550,4 +924,31 @@ mod tests {
         let key = km.get_encryption_key("non-expiring");
         assert!(key.is_ok());
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_export_import() {
+        let source = KeyManager::new();
+        source.generate_keypair("ops", "Operations", None).unwrap();
+        
+        // Private keys only leave wrapped, public keys in the clear
+        assert!(source.export_key("ops-signing", KeyFormat::Pem, None).is_err());
+        let public = source.export_key("ops-verify", KeyFormat::Pem, None).unwrap();
+        assert!(public.starts_with(b"-----BEGIN PUBLIC KEY-----"));
+        
+        let password = SecretBytes::from_slice(b"transfer");
+        let wrapped = source.export_key("ops-signing", KeyFormat::Pem,
+            Some(&KeyWrapping::Password(password.clone()))).unwrap();
+        
+        let target = KeyManager::new();
+        assert!(target.import_encoded_key("ops-signing", KeyType::Signing, &wrapped, KeyFormat::Pem,
+            None, "Operations", None).is_err());
+        target.import_encoded_key("ops-signing", KeyType::Signing, &wrapped, KeyFormat::Pem,
+            Some(&KeyUnwrapping::Password(password)), "Operations", None).unwrap();
+        target.import_encoded_key("ops-verify", KeyType::Verification, &public, KeyFormat::Pem,
+            None, "Operations", None).unwrap();
+        
+        let signature = target.sign("ops-signing", b"command").unwrap();
+        let verification_key = source.get_verification_key("ops-verify").unwrap();
+        assert!(crypto::verify_signature(b"command", &signature, &verification_key).is_ok());
+    }
+}
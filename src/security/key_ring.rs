@@ -0,0 +1,225 @@
+//! Versioned key rings
+//!
+//! A key ring is a logical key name with numbered versions. Each version
+//! holds an encryption key, a signing keypair, or both, stored in the
+//! `KeyManager` under `<name>/v<version>` (with the usual `-signing` and
+//! `-verify` suffixes for keypairs). The primary version, used for
+//! encrypting and signing, is the newest unexpired version holding the
+//! material needed; older versions stay available for decryption and
+//! verification until they expire.
+//!
+//! Version 0 refers to the unversioned key stored under the bare name, so
+//! keys created outside a ring (such as handshake session keys) can be
+//! used wherever a ring is expected.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::security::SecurityError;
+use crate::security::key_manager::{KeyManager, KeyMetadata, KeyType};
+use crate::utils::current_time_millis;
+
+/// Reference to one version of a key ring
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyRef {
+    pub name: String,
+    pub version: u32,
+}
+
+impl KeyRef {
+    pub fn new(name: &str, version: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            version,
+        }
+    }
+    
+    /// Base key ID of this version in the `KeyManager`
+    pub fn key_id(&self) -> String {
+        if self.version == 0 {
+            self.name.clone()
+        } else {
+            format!("{}/v{}", self.name, self.version)
+        }
+    }
+    
+    /// ID of the encryption key
+    pub fn encryption_id(&self) -> String {
+        self.key_id()
+    }
+    
+    /// ID of the private signing key
+    pub fn signing_id(&self) -> String {
+        format!("{}-signing", self.key_id())
+    }
+    
+    /// ID of the public verification key
+    pub fn verification_id(&self) -> String {
+        format!("{}-verify", self.key_id())
+    }
+}
+
+impl fmt::Display for KeyRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{}", self.name, self.version)
+    }
+}
+
+/// Material held by a key ring version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingMaterial {
+    /// ChaCha20Poly1305 encryption key
+    Encryption,
+    
+    /// Ed25519 signing keypair
+    Signing,
+    
+    /// Both an encryption key and a signing keypair
+    Both,
+}
+
+impl RingMaterial {
+    fn has_encryption(&self) -> bool {
+        matches!(self, RingMaterial::Encryption | RingMaterial::Both)
+    }
+    
+    fn has_signing(&self) -> bool {
+        matches!(self, RingMaterial::Signing | RingMaterial::Both)
+    }
+}
+
+/// Parse the ring version from a key ID, given the ring name
+fn parse_version(id: &str, name: &str) -> Option<u32> {
+    let base = id.strip_suffix("-signing")
+        .or_else(|| id.strip_suffix("-verify"))
+        .unwrap_or(id);
+    
+    if base == name {
+        return Some(0);
+    }
+    
+    base.strip_prefix(name)?
+        .strip_prefix("/v")?
+        .parse()
+        .ok()
+}
+
+fn is_unexpired(meta: &KeyMetadata, now: u64) -> bool {
+    meta.expires_at.is_none_or(|expires_at| now <= expires_at)
+}
+
+impl KeyManager {
+    /// Versions present in a key ring, oldest first (including expired ones)
+    pub fn ring_versions(&self, name: &str) -> Result<Vec<u32>> {
+        let versions: BTreeSet<u32> = self.list_keys()?
+            .iter()
+            .filter_map(|meta| parse_version(&meta.id, name))
+            .collect();
+        
+        Ok(versions.into_iter().collect())
+    }
+    
+    /// Add a new version to a key ring, creating the ring if needed
+    pub fn add_ring_version(&self, name: &str, material: RingMaterial, description: &str) -> Result<KeyRef> {
+        let version = self.ring_versions(name)?
+            .last()
+            .map_or(1, |latest| latest + 1);
+        let key = KeyRef::new(name, version);
+        
+        if material.has_encryption() {
+            self.generate_encryption_key(&key.encryption_id(), description, None)?;
+        }
+        
+        if material.has_signing() {
+            self.generate_keypair(&key.key_id(), description, None)?;
+        }
+        
+        Ok(key)
+    }
+    
+    /// Newest unexpired version holding the requested material
+    pub fn primary_key(&self, name: &str, encryption: bool, signing: bool) -> Result<KeyRef> {
+        let now = current_time_millis() / 1000;
+        let keys = self.list_keys()?;
+        
+        let usable = |id: String, key_type: KeyType| {
+            keys.iter().any(|meta| meta.id == id && meta.key_type == key_type && is_unexpired(meta, now))
+        };
+        
+        self.ring_versions(name)?
+            .into_iter()
+            .rev()
+            .map(|version| KeyRef::new(name, version))
+            .find(|key| {
+                (!encryption || usable(key.encryption_id(), KeyType::Encryption))
+                    && (!signing || usable(key.signing_id(), KeyType::Signing))
+            })
+            .ok_or_else(|| anyhow!(SecurityError::KeyError(
+                format!("Key ring {} has no usable version", name)
+            )))
+    }
+    
+    /// Metadata of the material held by a ring version
+    pub fn ring_version_metadata(&self, key: &KeyRef) -> Result<Vec<KeyMetadata>> {
+        let ids = [key.encryption_id(), key.signing_id(), key.verification_id()];
+        
+        let metadata: Vec<KeyMetadata> = self.list_keys()?
+            .into_iter()
+            .filter(|meta| ids.contains(&meta.id))
+            .collect();
+        
+        if metadata.is_empty() {
+            return Err(anyhow!(SecurityError::KeyError(format!("Key ring version not found: {}", key))));
+        }
+        
+        Ok(metadata)
+    }
+    
+    /// Expire all material of a ring version at `expires_at` (seconds since the Unix epoch)
+    ///
+    /// An earlier existing expiry is kept.
+    pub fn retire_ring_version(&self, key: &KeyRef, expires_at: u64) -> Result<()> {
+        for meta in self.ring_version_metadata(key)? {
+            let expires_at = meta.expires_at.map_or(expires_at, |current| current.min(expires_at));
+            self.set_expiry(&meta.id, Some(expires_at))?;
+        }
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_key_ring_versions() {
+        let km = KeyManager::new();
+        
+        // An unversioned key is version 0 of the ring with its name
+        km.generate_encryption_key("link", "Legacy link key", None).unwrap();
+        assert_eq!(km.primary_key("link", true, false).unwrap(), KeyRef::new("link", 0));
+        
+        let v1 = km.add_ring_version("link", RingMaterial::Both, "Link key").unwrap();
+        let v2 = km.add_ring_version("link", RingMaterial::Encryption, "Link key").unwrap();
+        assert_eq!((v1.version, v2.version), (1, 2));
+        assert_eq!(km.ring_versions("link").unwrap(), vec![0, 1, 2]);
+        
+        // The primary is the newest version with the needed material
+        assert_eq!(km.primary_key("link", true, false).unwrap(), v2);
+        assert_eq!(km.primary_key("link", true, true).unwrap(), v1);
+        assert!(km.get_verification_key(&v1.verification_id()).is_ok());
+        
+        // Retired versions drop out of primary selection once expired
+        km.retire_ring_version(&v2, 1).unwrap();
+        assert_eq!(km.primary_key("link", true, false).unwrap(), v1);
+        assert!(km.get_encryption_key(&v2.encryption_id()).is_err());
+        
+        // Rings with similar names are kept apart
+        km.add_ring_version("link-backup", RingMaterial::Encryption, "Other").unwrap();
+        assert_eq!(km.ring_versions("link").unwrap(), vec![0, 1, 2]);
+        assert!(km.primary_key("missing", true, false).is_err());
+    }
+}
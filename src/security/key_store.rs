@@ -0,0 +1,353 @@
+//! Encrypted-at-rest key store format
+//!
+//! The serialized key store is sealed with ChaCha20Poly1305 under a
+//! key-encryption key (KEK). The KEK is either derived from a passphrase
+//! with Argon2id or supplied directly as 32 raw bytes.
+//!
+//! File layout (integers big-endian):
+//!
+//! ```text
+//! magic "SGKS" | version u8 | kdf u8 | m_cost u32 | t_cost u32 | p_cost u32
+//!   | salt [16] | nonce [12] | ciphertext + tag
+//! ```
+//!
+//! The header before the nonce is authenticated as associated data, so
+//! tampering with the header or the contents fails to open the store.
+//!
+//! Stores are replaced atomically: the new contents are written and synced
+//! to a temporary file which is then renamed over the old one. Previous
+//! generations are kept as `<path>.1` (newest) to `<path>.N` for recovery.
+
+use anyhow::{anyhow, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{rngs::OsRng, RngCore};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::config::KeyStoreKeySource;
+use crate::security::{crypto, SecurityError};
+use crate::security::secret::SecretBytes;
+use crate::utils::hex_to_bytes;
+
+/// Magic bytes identifying a sealed key store
+pub const KEY_STORE_MAGIC: &[u8; 4] = b"SGKS";
+
+/// Current key store format version
+pub const KEY_STORE_VERSION: u8 = 1;
+
+/// Size of the Argon2id salt
+const SALT_SIZE: usize = 16;
+
+/// Size of the header preceding the ciphertext
+const HEADER_SIZE: usize = 4 + 1 + 1 + 12 + SALT_SIZE + crypto::NONCE_SIZE;
+
+/// KDF identifier for a raw key-encryption key
+const KDF_RAW: u8 = 0;
+
+/// KDF identifier for an Argon2id passphrase
+const KDF_ARGON2ID: u8 = 1;
+
+/// Number of backup generations kept by default
+pub const DEFAULT_BACKUP_GENERATIONS: usize = 3;
+
+/// Secret protecting the key store
+#[derive(Clone, Debug)]
+pub enum KeyStoreSecret {
+    /// Passphrase (UTF-8 bytes) stretched into the KEK with Argon2id
+    Passphrase(SecretBytes),
+    
+    /// Raw 32-byte key-encryption key
+    Key(SecretBytes),
+}
+
+impl KeyStoreSecret {
+    /// Create a passphrase secret
+    pub fn passphrase(passphrase: &str) -> Self {
+        KeyStoreSecret::Passphrase(SecretBytes::from_slice(passphrase.as_bytes()))
+    }
+    
+
+    /// Load the secret from its configured source
+    ///
+    /// Raw keys are given as 64 hex characters, or as exactly 32 bytes in a key file.
+    pub fn from_source(source: &KeyStoreKeySource) -> Result<Self> {
+        match source {
+            KeyStoreKeySource::PassphraseEnv { var } => {
+                let passphrase = std::env::var(var)
+                    .with_context(|| format!("Key store passphrase variable {} is not set", var))?;
+                Ok(KeyStoreSecret::Passphrase(SecretBytes::new(passphrase.into_bytes())))
+            },
+            
+            KeyStoreKeySource::PassphraseFile { path } => {
+                let contents = SecretBytes::new(fs::read(path)
+                    .with_context(|| format!("Failed to read key store passphrase file {}", path))?);
+                
+                // Editors commonly leave a trailing newline
+                let passphrase = contents.expose();
+                let len = passphrase.len() - passphrase.iter().rev().take_while(|b| **b == b'\n' || **b == b'\r').count();
+                Ok(KeyStoreSecret::Passphrase(SecretBytes::from_slice(&passphrase[..len])))
+            },
+            
+            KeyStoreKeySource::KeyEnv { var } => {
+                let hex = SecretBytes::new(std::env::var(var)
+                    .with_context(|| format!("Key store key variable {} is not set", var))?
+                    .into_bytes());
+                Self::hex_key(&hex)
+            },
+            
+            KeyStoreKeySource::KeyFile { path } => {
+                let data = SecretBytes::new(fs::read(path)
+                    .with_context(|| format!("Failed to read key store key file {}", path))?);
+                
+                if data.len() == crypto::CHACHA_KEY_SIZE {
+                    return Ok(KeyStoreSecret::Key(data));
+                }
+                
+                Self::hex_key(&data)
+            },
+        }
+    }
+    
+    /// Parse a key given as hex, ignoring surrounding whitespace
+    fn hex_key(hex: &SecretBytes) -> Result<Self> {
+        let hex = std::str::from_utf8(hex.expose())
+            .map_err(|_| anyhow!(SecurityError::KeyError("Key is neither 32 raw bytes nor hex".into())))?;
+        let key = SecretBytes::new(hex_to_bytes(hex.trim())
+            .map_err(|e| anyhow!(SecurityError::KeyError(e)))?);
+        
+        if key.len() != crypto::CHACHA_KEY_SIZE {
+            return Err(anyhow!(SecurityError::KeyError(format!(
+                "Invalid key store key size: {} (expected {})", key.len(), crypto::CHACHA_KEY_SIZE
+            ))));
+        }
+        
+        Ok(KeyStoreSecret::Key(key))
+    }
+}
+
+/// Key-encryption key together with the KDF parameters it was derived with
+#[derive(Clone)]
+pub(crate) struct SealingKey {
+    kdf: u8,
+    params: (u32, u32, u32),
+    salt: [u8; SALT_SIZE],
+    kek: SecretBytes,
+}
+
+impl SealingKey {
+    /// Derive a sealing key with a fresh salt
+    pub(crate) fn derive(secret: &KeyStoreSecret) -> Result<Self> {
+        let mut salt = [0u8; SALT_SIZE];
+        
+        match secret {
+            KeyStoreSecret::Passphrase(_) => {
+                OsRng.fill_bytes(&mut salt);
+                let params = Params::default();
+                Self::from_parts(secret, KDF_ARGON2ID, (params.m_cost(), params.t_cost(), params.p_cost()), salt)
+            },
+            KeyStoreSecret::Key(_) => Self::from_parts(secret, KDF_RAW, (0, 0, 0), salt),
+        }
+    }
+    
+    fn from_parts(secret: &KeyStoreSecret, kdf: u8, params: (u32, u32, u32), salt: [u8; SALT_SIZE]) -> Result<Self> {
+        let kek = match (secret, kdf) {
+            (KeyStoreSecret::Key(key), KDF_RAW) => {
+                if key.len() != crypto::CHACHA_KEY_SIZE {
+                    return Err(anyhow!(SecurityError::KeyError(format!(
+                        "Invalid key store key size: {} (expected {})", key.len(), crypto::CHACHA_KEY_SIZE
+                    ))));
+                }
+                key.clone()
+            },
+            
+            (KeyStoreSecret::Passphrase(passphrase), KDF_ARGON2ID) => {
+                let (m_cost, t_cost, p_cost) = params;
+                let params = Params::new(m_cost, t_cost, p_cost, Some(crypto::CHACHA_KEY_SIZE))
+                    .map_err(|e| anyhow!(SecurityError::KeyError(format!("Invalid Argon2 parameters: {}", e))))?;
+                
+                let mut kek = SecretBytes::new(vec![0u8; crypto::CHACHA_KEY_SIZE]);
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase.expose(), &salt, kek.expose_mut())
+                    .map_err(|e| anyhow!(SecurityError::KeyError(format!("Key derivation failed: {}", e))))?;
+                kek
+            },
+            
+            (KeyStoreSecret::Passphrase(_), _) => return Err(anyhow!(SecurityError::KeyError(
+                "Key store is sealed with a raw key but a passphrase was supplied".into()
+            ))),
+            
+            (KeyStoreSecret::Key(_), _) => return Err(anyhow!(SecurityError::KeyError(
+                "Key store is sealed with a passphrase but a raw key was supplied".into()
+            ))),
+        };
+        
+        Ok(Self { kdf, params, salt, kek })
+    }
+    
+    /// Encode the header fields preceding the nonce
+    fn encode_header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(KEY_STORE_MAGIC);
+        header.push(KEY_STORE_VERSION);
+        header.push(self.kdf);
+        header.extend_from_slice(&self.params.0.to_be_bytes());
+        header.extend_from_slice(&self.params.1.to_be_bytes());
+        header.extend_from_slice(&self.params.2.to_be_bytes());
+        header.extend_from_slice(&self.salt);
+        header
+    }
+}
+
+/// Check whether data looks like a sealed key store
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(KEY_STORE_MAGIC)
+}
+
+/// Seal serialized key store contents
+pub(crate) fn seal(plaintext: &[u8], key: &SealingKey) -> Result<Vec<u8>> {
+    let mut sealed = key.encode_header();
+    let (ciphertext, nonce) = crypto::encrypt_message(plaintext, &key.kek, &sealed)?;
+    
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    
+    Ok(sealed)
+}
+
+/// Open a sealed key store, returning its contents and the key that sealed it
+pub(crate) fn open(data: &[u8], secret: &KeyStoreSecret) -> Result<(Vec<u8>, SealingKey)> {
+    if !is_sealed(data) || data.len() < HEADER_SIZE {
+        return Err(anyhow!(SecurityError::KeyError("Key store file is not a sealed key store".into())));
+    }
+    
+    let version = data[4];
+    if version != KEY_STORE_VERSION {
+        return Err(anyhow!(SecurityError::KeyError(
+            format!("Unsupported key store version: {}", version)
+        )));
+    }
+    
+    let read_u32 = |offset: usize| u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+    let params = (read_u32(6), read_u32(10), read_u32(14));
+    let mut salt = [0u8; SALT_SIZE];
+    salt.copy_from_slice(&data[18..18 + SALT_SIZE]);
+    
+    let key = SealingKey::from_parts(secret, data[5], params, salt)?;
+    
+    let (header, ciphertext) = data.split_at(HEADER_SIZE);
+    let (associated_data, nonce) = header.split_at(HEADER_SIZE - crypto::NONCE_SIZE);
+    let plaintext = crypto::decrypt_message(ciphertext, nonce, &key.kek, associated_data)
+        .map_err(|_| anyhow!(SecurityError::KeyError(
+            "Failed to open key store: wrong passphrase or key, or the file is corrupted".into()
+        )))?;
+    
+    Ok((plaintext, key))
+}
+
+/// Path of a backup generation (1 is the newest)
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", generation));
+    PathBuf::from(name)
+}
+
+/// Atomically replace the file at `path`, keeping `backups` previous generations
+pub fn write_atomic(path: &Path, data: &[u8], backups: usize) -> Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    
+    // Write and sync the new contents before touching anything else
+    {
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        file.write_all(data)
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to sync {}", tmp_path.display()))?;
+    }
+    
+    // Shift backups down one generation, dropping the oldest
+    if backups > 0 && path.exists() {
+        for generation in (1..backups).rev() {
+            let from = backup_path(path, generation);
+            if from.exists() {
+                fs::rename(&from, backup_path(path, generation + 1))
+                    .with_context(|| format!("Failed to rotate backup {}", from.display()))?;
+            }
+        }
+        
+        // A hard link keeps the current store in place until the rename below
+        let newest = backup_path(path, 1);
+        let _ = fs::remove_file(&newest);
+        fs::hard_link(path, &newest)
+            .or_else(|_| fs::copy(path, &newest).map(|_| ()))
+            .with_context(|| format!("Failed to back up {}", path.display()))?;
+    }
+    
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    
+    sync_parent_dir(path)
+}
+
+/// Make renames within the parent directory durable
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync directory {}", parent.display()))
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_seal_open() {
+        let secret = KeyStoreSecret::passphrase("correct horse battery staple");
+        let key = SealingKey::derive(&secret).unwrap();
+        
+        let sealed = seal(b"key material", &key).unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(open(&sealed, &secret).unwrap().0, b"key material");
+        
+        // Wrong passphrase and wrong kind of secret are both rejected
+        assert!(open(&sealed, &KeyStoreSecret::passphrase("wrong")).is_err());
+        assert!(open(&sealed, &KeyStoreSecret::Key(SecretBytes::from_slice(&[0; 32]))).is_err());
+        
+        // Tampering with the header or the contents is detected
+        for offset in [5, 20, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[offset] ^= 0x01;
+            assert!(open(&tampered, &secret).is_err());
+        }
+    }
+    
+    #[test]
+    fn test_write_atomic_rotates_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+        
+        for generation in 0..5u8 {
+            write_atomic(&path, &[generation], 2).unwrap();
+        }
+        
+        assert_eq!(fs::read(&path).unwrap(), vec![4]);
+        assert_eq!(fs::read(backup_path(&path, 1)).unwrap(), vec![3]);
+        assert_eq!(fs::read(backup_path(&path, 2)).unwrap(), vec![2]);
+        assert!(!backup_path(&path, 3).exists());
+        assert!(!dir.path().join("keys.bin.tmp").exists());
+    }
+}
@@ -4,13 +4,36 @@
 //! authentication, and key management.
 
 pub mod key_manager;
+pub mod compact;
 pub mod crypto;
+pub mod handshake;
+pub mod key_backend;
+pub mod key_format;
+pub mod key_ring;
+pub mod key_store;
+pub mod multi_recipient;
+pub mod ratchet;
+pub mod replay;
+pub mod rotation;
+pub mod secret;
+pub mod stream;
+pub mod token;
+pub mod trust;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use thiserror::Error;
 
+use crate::utils::current_time_millis;
+use compact::CompactLink;
+use crypto::CipherSuite;
+use handshake::SessionKeys;
 use key_manager::KeyManager;
+use trust::PeerRole;
+use key_ring::KeyRef;
+use ratchet::{RatchetSchedule, SessionRatchets};
+use replay::{ReplayGuard, SequenceCounter};
 
 #[derive(Debug, Error)]
 pub enum SecurityError {
@@ -28,10 +51,16 @@ pub enum SecurityError {
     
     #[error("Invalid security configuration: {0}")]
     ConfigError(String),
+    
+    #[error("Replay detected: {0}")]
+    ReplayDetected(String),
+    
+    #[error("Handshake failed: {0}")]
+    HandshakeFailed(String),
 }
 
 /// Represents the security mode for a message
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum SecurityMode {
     /// No security applied
     None,
@@ -43,25 +72,160 @@ pub enum SecurityMode {
     Encrypted,
     
     /// Message is both encrypted and signed
+    #[default]
     EncryptedAndSigned,
+    
+    /// Message carries a keyed MAC but is not encrypted
+    Authenticated,
 }
 
-impl Default for SecurityMode {
-    fn default() -> Self {
-        SecurityMode::EncryptedAndSigned
+impl SecurityMode {
+    /// Stable numeric identifier used in the authenticated header encoding
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            SecurityMode::None => 0,
+            SecurityMode::Signed => 1,
+            SecurityMode::Encrypted => 2,
+            SecurityMode::EncryptedAndSigned => 3,
+            SecurityMode::Authenticated => 4,
+        }
+    }
+    
+    /// Inverse of `as_u8`
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(SecurityMode::None),
+            1 => Some(SecurityMode::Signed),
+            2 => Some(SecurityMode::Encrypted),
+            3 => Some(SecurityMode::EncryptedAndSigned),
+            4 => Some(SecurityMode::Authenticated),
+            _ => None,
+        }
+    }
+    
+    /// Whether the payload is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, SecurityMode::Encrypted | SecurityMode::EncryptedAndSigned)
+    }
+    
+    /// Whether the message carries a signature
+    pub fn is_signed(&self) -> bool {
+        matches!(self, SecurityMode::Signed | SecurityMode::EncryptedAndSigned)
+    }
+    
+    /// Whether tampering is detected; AEAD encryption, signatures and MACs all provide this
+    pub fn is_authenticated(&self) -> bool {
+        *self != SecurityMode::None
+    }
+    
+    /// Whether a symmetric key from the encryption key ring is needed
+    pub fn uses_symmetric_key(&self) -> bool {
+        matches!(self, SecurityMode::Encrypted | SecurityMode::EncryptedAndSigned | SecurityMode::Authenticated)
+    }
+    
+    /// Whether this mode provides every protection required by `minimum`
+    pub fn satisfies(&self, minimum: SecurityMode) -> bool {
+        (self.is_authenticated() || !minimum.is_authenticated())
+            && (self.is_encrypted() || !minimum.is_encrypted())
+            && (self.is_signed() || !minimum.is_signed())
     }
 }
 
+/// Default MAC tag length in bytes
+pub const DEFAULT_MAC_TAG_LEN: usize = 16;
+
+/// Prefix identifying a serialized secured message
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"SGSM";
+
+/// Current version of the secured message header
+pub const HEADER_VERSION: u8 = 4;
+
 /// Header for secured messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityHeader {
     pub version: u8,
     pub mode: SecurityMode,
-    pub key_id: String,
-    pub nonce: Vec<u8>,  // For ChaCha20Poly1305
+    pub suite: CipherSuite,  // AEAD protecting the payload, in encrypted modes
+    pub key: KeyRef,  // Encryption key ring and version (the signing key when only signed)
+    pub signing_key: Option<KeyRef>,  // Signing key ring and version, in signed modes
+    pub sender: String,  // Identity of the sending gateway
+    pub sequence: u64,  // Per key ring and sender, for replay protection
+    pub timestamp: u64,  // Milliseconds since the Unix epoch
+    pub nonce: Vec<u8>,  // For the cipher suite
     pub signature: Option<Vec<u8>>,  // For Ed25519 signatures
 }
 
+impl SecurityHeader {
+    /// Canonical encoding of the header fields covered by authentication
+    ///
+    /// Used as AEAD associated data and prefixed to the signed content, so
+    /// that tampering with any of these fields fails decryption or
+    /// verification. The nonce is bound by the AEAD itself and the signature
+    /// cannot cover itself, so both are excluded.
+    pub fn authenticated_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(33 + self.key.name.len() + self.sender.len());
+        
+        data.push(self.version);
+        data.push(self.mode.as_u8());
+        data.push(self.suite.as_u8());
+        
+        // Variable-length fields are length-prefixed so the encoding is unambiguous
+        encode_key_ref(&mut data, &self.key);
+        match &self.signing_key {
+            Some(key) => {
+                data.push(1);
+                encode_key_ref(&mut data, key);
+            },
+            None => data.push(0),
+        }
+        data.extend_from_slice(&(self.sender.len() as u16).to_be_bytes());
+        data.extend_from_slice(self.sender.as_bytes());
+        
+        data.extend_from_slice(&self.sequence.to_be_bytes());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        
+        data
+    }
+    
+    /// ID of the private key signing this message
+    fn signing_id(&self) -> Result<String> {
+        self.signing_key.as_ref()
+            .map(KeyRef::signing_id)
+            .ok_or_else(|| anyhow::anyhow!(SecurityError::AuthenticationFailed("Missing signing key".into())))
+    }
+    
+    /// ID of the public key verifying this message
+    fn verification_id(&self) -> Result<String> {
+        self.signing_key.as_ref()
+            .map(KeyRef::verification_id)
+            .ok_or_else(|| anyhow::anyhow!(SecurityError::AuthenticationFailed("Missing signing key".into())))
+    }
+    
+    /// Content covered by the MAC: the header encoding, the tag length and the payload
+    ///
+    /// Including the tag length means a tag cannot be shortened to weaken it.
+    fn mac_content(&self, tag_len: usize, payload: &[u8]) -> Vec<u8> {
+        let mut content = self.authenticated_data();
+        content.push(tag_len as u8);
+        content.extend_from_slice(payload);
+        content
+    }
+    
+    /// Content covered by the Ed25519 signature: the header encoding followed by the plaintext
+    fn signed_content(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut content = self.authenticated_data();
+        content.extend_from_slice(plaintext);
+        content
+    }
+}
+
+/// Append a length-prefixed key ring name and its version
+fn encode_key_ref(data: &mut Vec<u8>, key: &KeyRef) {
+    data.extend_from_slice(&(key.name.len() as u16).to_be_bytes());
+    data.extend_from_slice(key.name.as_bytes());
+    data.extend_from_slice(&key.version.to_be_bytes());
+}
+
 /// Secured message format
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecuredMessage {
@@ -73,99 +237,298 @@ pub struct SecuredMessage {
 /// Security service for message protection
 pub struct SecurityService {
     key_manager: KeyManager,
+    
+    /// Identity stamped on outbound messages
+    sender_id: String,
+    
+    /// Outbound sequence numbers
+    sequences: SequenceCounter,
+    
+    /// Inbound anti-replay state
+    replay_guard: ReplayGuard,
+    
+    /// Links using the compact envelope, by interface
+    compact_links: HashMap<String, CompactLink>,
+    
+    /// Length of outbound MAC tags, and the minimum accepted inbound
+    mac_tag_len: usize,
+    
+    /// Suite encrypting outbound messages unless another is chosen
+    cipher_suite: CipherSuite,
+    
+    /// Suites accepted for encryption and decryption
+    enabled_suites: Vec<CipherSuite>,
+    
+    /// Forward-secret ratchets of handshake sessions
+    ratchets: SessionRatchets,
 }
 
 impl SecurityService {
     pub fn new(key_manager: KeyManager) -> Self {
-        Self { key_manager }
+        Self {
+            key_manager,
+            sender_id: "secure-gateway".to_string(),
+            sequences: SequenceCounter::new(),
+            replay_guard: ReplayGuard::default(),
+            compact_links: HashMap::new(),
+            mac_tag_len: DEFAULT_MAC_TAG_LEN,
+            cipher_suite: CipherSuite::default(),
+            enabled_suites: CipherSuite::AVAILABLE.to_vec(),
+            ratchets: SessionRatchets::new(),
+        }
+    }
+    
+    /// Access the key manager
+    pub fn key_manager(&self) -> &KeyManager {
+        &self.key_manager
+    }
+    
+    /// Set the identity stamped on outbound messages
+    pub fn set_sender_id(&mut self, sender_id: &str) {
+        self.sender_id = sender_id.to_string();
+    }
+    
+    /// Replace the source of outbound sequence numbers
+    pub fn set_sequence_counter(&mut self, sequences: SequenceCounter) {
+        self.sequences = sequences;
+    }
+    
+    /// Replace the inbound anti-replay settings
+    pub fn set_replay_guard(&mut self, replay_guard: ReplayGuard) {
+        self.replay_guard = replay_guard;
+    }
+    
+    /// Set the MAC tag length for `SecurityMode::Authenticated`
+    pub fn set_mac_tag_len(&mut self, tag_len: usize) -> Result<()> {
+        if !(crypto::MIN_MAC_TAG_LEN..=crypto::MAC_SIZE).contains(&tag_len) {
+            return Err(anyhow::anyhow!(SecurityError::ConfigError(format!(
+                "MAC tag length must be between {} and {} bytes", crypto::MIN_MAC_TAG_LEN, crypto::MAC_SIZE
+            ))));
+        }
+        
+        self.mac_tag_len = tag_len;
+        Ok(())
+    }
+    
+    /// Set the default outbound cipher suite and the suites accepted at all
+    pub fn set_cipher_suites(&mut self, default: CipherSuite, enabled: &[CipherSuite]) -> Result<()> {
+        if let Some(suite) = enabled.iter().find(|suite| !suite.is_available()) {
+            return Err(anyhow::anyhow!(SecurityError::ConfigError(
+                format!("Cipher suite {:?} is not available", suite)
+            )));
+        }
+        
+        if !enabled.contains(&default) {
+            return Err(anyhow::anyhow!(SecurityError::ConfigError(
+                format!("Default cipher suite {:?} is not enabled", default)
+            )));
+        }
+        
+        self.cipher_suite = default;
+        self.enabled_suites = enabled.to_vec();
+        Ok(())
+    }
+    
+    /// Refuse suites that are not enabled
+    fn check_suite(&self, suite: CipherSuite) -> Result<()> {
+        if !self.enabled_suites.contains(&suite) {
+            return Err(anyhow::anyhow!(SecurityError::ConfigError(
+                format!("Cipher suite {:?} is disabled", suite)
+            )));
+        }
+        
+        Ok(())
+    }
+    
+    /// Use the compact envelope on an interface
+    pub fn add_compact_link(&mut self, interface: &str, link: CompactLink) {
+        self.compact_links.insert(interface.to_string(), link);
+    }
+    
+    /// Compact envelope settings of an interface, if it uses them
+    pub fn compact_link(&self, interface: &str) -> Option<&CompactLink> {
+        self.compact_links.get(interface)
+    }
+    
+    /// Ratchet the session keys installed by a handshake forward on `schedule`
+    pub fn start_session_ratchet(&self, keys: &SessionKeys, schedule: RatchetSchedule) -> Result<()> {
+        self.ratchets.start(&self.key_manager, keys, schedule)
+    }
+    
+    /// Session ratchets of this service
+    pub fn session_ratchets(&self) -> &SessionRatchets {
+        &self.ratchets
     }
     
     /// Secure a message with appropriate encryption and/or signatures
-    pub fn secure_message(&self, data: &[u8], mode: SecurityMode, key_id: &str) -> Result<SecuredMessage> {
-        match mode {
+    ///
+    /// The primary version of the `key_name` key ring holding the material
+    /// required by `mode` is used, and recorded in the header.
+    pub fn secure_message(&self, data: &[u8], mode: SecurityMode, key_name: &str) -> Result<SecuredMessage> {
+        self.secure_message_with_keys(data, mode, key_name, key_name)
+    }
+    
+    /// Secure a message using separate encryption and signing key rings
+    ///
+    /// The primary version of each ring is used and recorded in the header.
+    /// Sequence numbers follow the encryption ring, or the signing ring when
+    /// the message is only signed.
+    pub fn secure_message_with_keys(&self, data: &[u8], mode: SecurityMode,
+                                    encryption_ring: &str, signing_ring: &str) -> Result<SecuredMessage> {
+        self.secure_message_with_suite(data, mode, encryption_ring, signing_ring, self.cipher_suite)
+    }
+    
+    /// Secure a message using separate key rings and a specific cipher suite
+    ///
+    /// Ratcheted session rings step their ratchet before the key is chosen.
+    pub fn secure_message_with_suite(&self, data: &[u8], mode: SecurityMode, encryption_ring: &str,
+                                     signing_ring: &str, suite: CipherSuite) -> Result<SecuredMessage> {
+        if mode.uses_symmetric_key() {
+            return self.ratchets.with_send(&self.key_manager, encryption_ring,
+                || self.seal_message(data, mode, encryption_ring, signing_ring, suite));
+        }
+        
+        self.seal_message(data, mode, encryption_ring, signing_ring, suite)
+    }
+    
+    fn seal_message(&self, data: &[u8], mode: SecurityMode, encryption_ring: &str,
+                    signing_ring: &str, suite: CipherSuite) -> Result<SecuredMessage> {
+        if mode.is_encrypted() {
+            self.check_suite(suite)?;
+        }
+        
+        if encryption_ring.len() > u16::MAX as usize || signing_ring.len() > u16::MAX as usize
+            || self.sender_id.len() > u16::MAX as usize {
+            return Err(anyhow::anyhow!(SecurityError::ConfigError(
+                "Key names and sender ID must fit in 65535 bytes".into()
+            )));
+        }
+        
+        let signing_key = match mode {
+            SecurityMode::Signed | SecurityMode::EncryptedAndSigned =>
+                Some(self.key_manager.primary_key(signing_ring, false, true)?),
+            SecurityMode::None | SecurityMode::Encrypted | SecurityMode::Authenticated => None,
+        };
+        
+        let key = match (mode, &signing_key) {
+            (SecurityMode::Signed, Some(signing_key)) => signing_key.clone(),
+            _ if mode.uses_symmetric_key() => self.key_manager.primary_key(encryption_ring, true, false)?,
+            _ => KeyRef::new(encryption_ring, 0),
+        };
+        
+        let mut header = SecurityHeader {
+            version: HEADER_VERSION,
+            mode,
+            suite,
+            sequence: self.sequences.next(&key.name)?,
+            key,
+            signing_key,
+            sender: self.sender_id.clone(),
+            timestamp: current_time_millis(),
+            nonce: vec![],
+            signature: None,
+        };
+        let aad = header.authenticated_data();
+        let mut hmac = None;
+        
+        let payload = match mode {
             SecurityMode::None => {
                 // No security, just wrap in our format
-                Ok(SecuredMessage {
-                    header: SecurityHeader {
-                        version: 1,
-                        mode,
-                        key_id: key_id.to_string(),
-                        nonce: vec![],
-                        signature: None,
-                    },
-                    payload: data.to_vec(),
-                    hmac: None,
-                })
+                data.to_vec()
             },
             
             SecurityMode::Signed => {
-                // Sign the message with Ed25519
-                let signature = crypto::sign_message(data, &self.key_manager.get_signing_key(key_id)?)?;
-                
-                Ok(SecuredMessage {
-                    header: SecurityHeader {
-                        version: 1,
-                        mode,
-                        key_id: key_id.to_string(),
-                        nonce: vec![],
-                        signature: Some(signature),
-                    },
-                    payload: data.to_vec(),
-                    hmac: None,
-                })
+                // Sign the header and message with Ed25519
+                header.signature = Some(self.key_manager.sign(
+                    &header.signing_id()?,
+                    &header.signed_content(data),
+                )?);
+                data.to_vec()
             },
             
             SecurityMode::Encrypted => {
-                // Encrypt the message with ChaCha20Poly1305
-                let (ciphertext, nonce) = crypto::encrypt_message(
+                // Encrypt the message with the cipher suite
+                let (ciphertext, nonce) = self.key_manager.encrypt(
+                    &header.key.encryption_id(),
+                    suite,
                     data, 
-                    &self.key_manager.get_encryption_key(key_id)?
+                    &aad,
                 )?;
                 
-                Ok(SecuredMessage {
-                    header: SecurityHeader {
-                        version: 1,
-                        mode,
-                        key_id: key_id.to_string(),
-                        nonce,
-                        signature: None,
-                    },
-                    payload: ciphertext,
-                    hmac: None,
-                })
+                header.nonce = nonce;
+                ciphertext
             },
             
             SecurityMode::EncryptedAndSigned => {
-                // First sign the plaintext
-                let signature = crypto::sign_message(data, &self.key_manager.get_signing_key(key_id)?)?;
+                // First sign the header and plaintext
+                header.signature = Some(self.key_manager.sign(
+                    &header.signing_id()?,
+                    &header.signed_content(data),
+                )?);
                 
                 // Then encrypt the plaintext (not the signature)
-                let (ciphertext, nonce) = crypto::encrypt_message(
+                let (ciphertext, nonce) = self.key_manager.encrypt(
+                    &header.key.encryption_id(),
+                    suite,
                     data, 
-                    &self.key_manager.get_encryption_key(key_id)?
+                    &aad,
                 )?;
                 
-                Ok(SecuredMessage {
-                    header: SecurityHeader {
-                        version: 1,
-                        mode,
-                        key_id: key_id.to_string(),
-                        nonce,
-                        signature: Some(signature),
-                    },
-                    payload: ciphertext,
-                    hmac: None,
-                })
+                header.nonce = nonce;
+                ciphertext
             },
-        }
+            
+            SecurityMode::Authenticated => {
+                // MAC the header and message with a key derived from the symmetric key
+                hmac = Some(self.key_manager.mac(
+                    &header.key.encryption_id(),
+                    &header.mac_content(self.mac_tag_len, data),
+                    self.mac_tag_len,
+                )?);
+                data.to_vec()
+            },
+        };
+        
+        Ok(SecuredMessage {
+            header,
+            payload,
+            hmac,
+        })
     }
     
     /// Extract the original message from a secured message
+    ///
+    /// Messages that are stale or have already been received are rejected
+    /// with `SecurityError::ReplayDetected`. Messages on a ratcheted session
+    /// ring move the receiving ratchet to their epoch once authenticated.
     pub fn extract_message(&self, secured: &SecuredMessage) -> Result<Vec<u8>> {
-        match secured.header.mode {
+        if secured.header.mode.uses_symmetric_key() {
+            return self.ratchets.with_receive(&self.key_manager, &secured.header.key,
+                || self.open_message(secured));
+        }
+        
+        self.open_message(secured)
+    }
+    
+    fn open_message(&self, secured: &SecuredMessage) -> Result<Vec<u8>> {
+        if secured.header.version != HEADER_VERSION {
+            return Err(anyhow::anyhow!(SecurityError::AuthenticationFailed(
+                format!("Unsupported header version: {}", secured.header.version)
+            )));
+        }
+        
+        if secured.header.mode.is_encrypted() {
+            self.check_suite(secured.header.suite)?;
+        }
+        
+        // Reject obvious replays before doing any cryptographic work
+        self.replay_guard.check(&secured.header)?;
+        let aad = secured.header.authenticated_data();
+        
+        let plaintext = match secured.header.mode {
             SecurityMode::None => {
                 // No security, just return the payload
-                Ok(secured.payload.clone())
+                secured.payload.clone()
             },
             
             SecurityMode::Signed => {
@@ -175,29 +538,34 @@ impl SecurityService {
                 
                 // Verify using the public key
                 crypto::verify_signature(
-                    &secured.payload, 
+                    &secured.header.signed_content(&secured.payload), 
                     signature, 
-                    &self.key_manager.get_verification_key(&secured.header.key_id)?
+                    &self.key_manager.get_peer_verification_key(
+                        &secured.header.verification_id()?, &secured.header.sender, PeerRole::Messaging)?
                 )?;
                 
-                Ok(secured.payload.clone())
+                secured.payload.clone()
             },
             
             SecurityMode::Encrypted => {
                 // Decrypt the payload
-                crypto::decrypt_message(
+                self.key_manager.decrypt(
+                    &secured.header.key.encryption_id(),
+                    secured.header.suite,
                     &secured.payload, 
                     &secured.header.nonce, 
-                    &self.key_manager.get_encryption_key(&secured.header.key_id)?
-                )
+                    &aad,
+                )?
             },
             
             SecurityMode::EncryptedAndSigned => {
                 // First decrypt the payload
-                let plaintext = crypto::decrypt_message(
+                let plaintext = self.key_manager.decrypt(
+                    &secured.header.key.encryption_id(),
+                    secured.header.suite,
                     &secured.payload, 
                     &secured.header.nonce, 
-                    &self.key_manager.get_encryption_key(&secured.header.key_id)?
+                    &aad,
                 )?;
                 
                 // Then verify the signature on the decrypted plaintext
@@ -205,25 +573,275 @@ impl SecurityService {
                     .ok_or_else(|| SecurityError::AuthenticationFailed("Missing signature".into()))?;
                 
                 crypto::verify_signature(
-                    &plaintext, 
+                    &secured.header.signed_content(&plaintext), 
                     signature, 
-                    &self.key_manager.get_verification_key(&secured.header.key_id)?
+                    &self.key_manager.get_peer_verification_key(
+                        &secured.header.verification_id()?, &secured.header.sender, PeerRole::Messaging)?
                 )?;
                 
-                Ok(plaintext)
+                plaintext
             },
-        }
+            
+            SecurityMode::Authenticated => {
+                let tag = secured.hmac.as_ref()
+                    .ok_or_else(|| SecurityError::AuthenticationFailed("Missing MAC".into()))?;
+                
+                if tag.len() < self.mac_tag_len {
+                    return Err(anyhow::anyhow!(SecurityError::AuthenticationFailed(
+                        format!("MAC of {} bytes is shorter than the required {}", tag.len(), self.mac_tag_len)
+                    )));
+                }
+                
+                self.key_manager.verify_mac(
+                    &secured.header.key.encryption_id(),
+                    &secured.header.mac_content(tag.len(), &secured.payload),
+                    tag,
+                )?;
+                
+                secured.payload.clone()
+            },
+        };
+        
+        // Only record the sequence number once the message is authenticated
+        self.replay_guard.accept(&secured.header)?;
+        
+        Ok(plaintext)
     }
     
-    /// Serialize a secured message to bytes
+    /// Serialize a secured message to bytes, prefixed with `ENVELOPE_MAGIC`
     pub fn serialize(&self, message: &SecuredMessage) -> Result<Vec<u8>> {
-        bincode::serialize(message)
-            .map_err(|e| anyhow::anyhow!("Failed to serialize secured message: {}", e))
+        let mut data = ENVELOPE_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, message)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize secured message: {}", e))?;
+        Ok(data)
     }
     
     /// Deserialize bytes to a secured message
     pub fn deserialize(&self, data: &[u8]) -> Result<SecuredMessage> {
-        bincode::deserialize(data)
+        let body = data.strip_prefix(ENVELOPE_MAGIC)
+            .ok_or_else(|| anyhow::anyhow!("Not a secured message envelope"))?;
+        
+        bincode::deserialize(body)
             .map_err(|e| anyhow::anyhow!("Failed to deserialize secured message: {}", e))
     }
-} 
\ No newline at end of file
+    
+    /// Check whether bytes look like a serialized secured message
+    pub fn is_envelope(data: &[u8]) -> bool {
+        data.starts_with(ENVELOPE_MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key_ring::RingMaterial;
+    
+    fn create_service() -> SecurityService {
+        let km = KeyManager::new();
+        km.generate_encryption_key("test", "Test encryption key", None).unwrap();
+        km.generate_keypair("test", "Test keypair", None).unwrap();
+        SecurityService::new(km)
+    }
+    
+    #[test]
+    fn test_secure_extract_roundtrip() {
+        let service = create_service();
+        let data = b"telemetry frame";
+        
+        let secured = service.secure_message(data, SecurityMode::Encrypted, "test").unwrap();
+        assert_ne!(secured.payload, data);
+        assert_eq!(service.extract_message(&secured).unwrap(), data);
+        
+        // Sequence numbers increase per key
+        let next = service.secure_message(data, SecurityMode::None, "test").unwrap();
+        assert_eq!(next.header.sequence, secured.header.sequence + 1);
+    }
+    
+    #[test]
+    fn test_replay_rejected() {
+        let service = create_service();
+        
+        let secured = service.secure_message(b"open valve", SecurityMode::Encrypted, "test").unwrap();
+        assert!(service.extract_message(&secured).is_ok());
+        
+        // Replaying the captured message fails with a distinct error
+        let err = service.extract_message(&secured).unwrap_err();
+        assert!(matches!(err.downcast_ref::<SecurityError>(), Some(SecurityError::ReplayDetected(_))));
+        
+        // A message that fails authentication does not advance the window
+        let mut forged = service.secure_message(b"close valve", SecurityMode::Encrypted, "test").unwrap();
+        let genuine = forged.clone();
+        forged.payload[0] ^= 0xFF;
+        assert!(service.extract_message(&forged).is_err());
+        assert!(service.extract_message(&genuine).is_ok());
+        
+        // Stale messages are rejected
+        let mut stale = service.secure_message(b"status", SecurityMode::Signed, "test").unwrap();
+        stale.header.timestamp -= 60_000;
+        let err = service.extract_message(&stale).unwrap_err();
+        assert!(matches!(err.downcast_ref::<SecurityError>(), Some(SecurityError::ReplayDetected(_))));
+    }
+    
+    #[test]
+    fn test_plaintext_does_not_move_replay_window() {
+        let service = create_service();
+        let pending = service.secure_message(b"open valve", SecurityMode::Encrypted, "test").unwrap();
+        
+        // A plaintext message claiming a far-ahead sequence number is accepted
+        // as plaintext, but must not make the pending message look stale
+        let mut forged = service.secure_message(b"status", SecurityMode::None, "test").unwrap();
+        forged.header.sequence += 1_000_000;
+        assert!(service.extract_message(&forged).is_ok());
+        assert!(service.extract_message(&pending).is_ok());
+    }
+    
+    #[test]
+    fn test_header_tampering_detected() {
+        let service = create_service();
+        service.key_manager.generate_encryption_key("other", "Other encryption key", None).unwrap();
+        service.key_manager.generate_keypair("other", "Other keypair", None).unwrap();
+        
+        for mode in [SecurityMode::Signed, SecurityMode::Encrypted, SecurityMode::EncryptedAndSigned,
+                     SecurityMode::Authenticated] {
+            let secured = service.secure_message(b"set mode 3", mode, "test").unwrap();
+            
+            let mut swapped_key = secured.clone();
+            swapped_key.header.key = KeyRef::new("other", 0);
+            assert!(service.extract_message(&swapped_key).is_err(), "key swap undetected in {:?}", mode);
+            
+            let mut bumped_sequence = secured.clone();
+            bumped_sequence.header.sequence += 1000;
+            assert!(service.extract_message(&bumped_sequence).is_err(), "sequence change undetected in {:?}", mode);
+            
+            let mut renamed_sender = secured.clone();
+            renamed_sender.header.sender = "impostor".to_string();
+            assert!(service.extract_message(&renamed_sender).is_err(), "sender change undetected in {:?}", mode);
+            
+            // None of the failed attempts consumed the genuine sequence number
+            assert_eq!(service.extract_message(&secured).unwrap(), b"set mode 3");
+        }
+        
+        let mut upgraded = service.secure_message(b"arm", SecurityMode::Encrypted, "test").unwrap();
+        upgraded.header.mode = SecurityMode::EncryptedAndSigned;
+        upgraded.header.signature = Some(vec![0; 64]);
+        assert!(service.extract_message(&upgraded).is_err());
+    }
+    
+    #[test]
+    fn test_cipher_suites() {
+        let mut service = create_service();
+        
+        let secured = service.secure_message_with_suite(b"position", SecurityMode::EncryptedAndSigned,
+            "test", "test", CipherSuite::XChaCha20Poly1305).unwrap();
+        assert_eq!(secured.header.suite, CipherSuite::XChaCha20Poly1305);
+        assert_eq!(secured.header.nonce.len(), crypto::XNONCE_SIZE);
+        
+        // The suite is authenticated, so it cannot be swapped
+        let mut downgraded = secured.clone();
+        downgraded.header.suite = CipherSuite::ChaCha20Poly1305;
+        downgraded.header.nonce.truncate(crypto::NONCE_SIZE);
+        assert!(service.extract_message(&downgraded).is_err());
+        assert_eq!(service.extract_message(&secured).unwrap(), b"position");
+        
+        // Disabled suites are refused in both directions
+        let old = service.secure_message(b"status", SecurityMode::Encrypted, "test").unwrap();
+        service.set_cipher_suites(CipherSuite::XChaCha20Poly1305, &[CipherSuite::XChaCha20Poly1305]).unwrap();
+        assert!(service.extract_message(&old).is_err());
+        assert!(service.secure_message_with_suite(b"status", SecurityMode::Encrypted, "test", "test",
+            CipherSuite::ChaCha20Poly1305).is_err());
+        assert_eq!(service.secure_message(b"status", SecurityMode::Encrypted, "test").unwrap().header.suite,
+            CipherSuite::XChaCha20Poly1305);
+        
+        service.set_cipher_suites(CipherSuite::Aes256Gcm, &[CipherSuite::Aes256Gcm]).unwrap();
+        let secured = service.secure_message(b"status", SecurityMode::Encrypted, "test").unwrap();
+        assert_eq!(secured.header.suite, CipherSuite::Aes256Gcm);
+        assert_eq!(service.extract_message(&secured).unwrap(), b"status");
+        
+        assert!(service.set_cipher_suites(CipherSuite::ChaCha20Poly1305, &[CipherSuite::XChaCha20Poly1305]).is_err());
+    }
+    
+    #[test]
+    fn test_key_ring_versions_in_header() {
+        let service = create_service();
+        let km = &service.key_manager;
+        let v1 = km.add_ring_version("link", RingMaterial::Both, "Link key").unwrap();
+        
+        let old = service.secure_message(b"before", SecurityMode::EncryptedAndSigned, "link").unwrap();
+        assert_eq!(old.header.key, v1);
+        
+        // After rotation the new version is used and the old one still decrypts
+        let v2 = km.add_ring_version("link", RingMaterial::Both, "Link key").unwrap();
+        let new = service.secure_message(b"after", SecurityMode::EncryptedAndSigned, "link").unwrap();
+        assert_eq!(new.header.key, v2);
+        
+        assert_eq!(service.extract_message(&new).unwrap(), b"after");
+        assert_eq!(service.extract_message(&old).unwrap(), b"before");
+        
+        // Claiming a different version fails authentication
+        let mut relabelled = service.secure_message(b"again", SecurityMode::EncryptedAndSigned, "link").unwrap();
+        relabelled.header.key = v1;
+        assert!(service.extract_message(&relabelled).is_err());
+    }
+    
+    #[test]
+    fn test_authenticated_mode() {
+        let mut service = create_service();
+        service.set_mac_tag_len(12).unwrap();
+        
+        let secured = service.secure_message(b"alt=1200", SecurityMode::Authenticated, "test").unwrap();
+        assert_eq!(secured.payload, b"alt=1200");
+        assert_eq!(secured.hmac.as_ref().map(Vec::len), Some(12));
+        assert!(secured.header.signature.is_none());
+        
+        let mut tampered = secured.clone();
+        tampered.payload[4] = b'9';
+        assert!(service.extract_message(&tampered).is_err());
+        
+        // A shortened tag fails even when the receiver accepts the shorter length
+        let mut shortened = secured.clone();
+        shortened.hmac.as_mut().unwrap().truncate(8);
+        service.set_mac_tag_len(8).unwrap();
+        assert!(service.extract_message(&shortened).is_err());
+        
+        let mut missing = secured.clone();
+        missing.hmac = None;
+        assert!(service.extract_message(&missing).is_err());
+        
+        assert_eq!(service.extract_message(&secured).unwrap(), b"alt=1200");
+        
+        // Tags shorter than the configured length are refused outright
+        service.set_mac_tag_len(16).unwrap();
+        let short = service.secure_message(b"x", SecurityMode::Authenticated, "test").unwrap();
+        service.set_mac_tag_len(32).unwrap();
+        assert!(service.extract_message(&short).is_err());
+        assert!(service.set_mac_tag_len(4).is_err());
+    }
+    
+    #[test]
+    fn test_separate_encryption_and_signing_keys() {
+        let service = create_service();
+        let km = &service.key_manager;
+        let enc = km.add_ring_version("link-enc", RingMaterial::Encryption, "Link encryption").unwrap();
+        let sig = km.add_ring_version("link-sig", RingMaterial::Signing, "Link signing").unwrap();
+        km.add_ring_version("other-sig", RingMaterial::Signing, "Other signing").unwrap();
+        
+        let secured = service.secure_message_with_keys(b"cmd", SecurityMode::EncryptedAndSigned,
+            "link-enc", "link-sig").unwrap();
+        assert_eq!(secured.header.key, enc);
+        assert_eq!(secured.header.signing_key, Some(sig.clone()));
+        
+        // The signing key is authenticated like the rest of the header
+        let mut resigned = secured.clone();
+        resigned.header.signing_key = Some(KeyRef::new("other-sig", 1));
+        assert!(service.extract_message(&resigned).is_err());
+        assert_eq!(service.extract_message(&secured).unwrap(), b"cmd");
+        
+        // Only signed messages use the signing ring for sequencing
+        let signed = service.secure_message_with_keys(b"status", SecurityMode::Signed, "link-enc", "link-sig").unwrap();
+        assert_eq!(signed.header.key, sig);
+        assert_eq!(service.extract_message(&signed).unwrap(), b"status");
+        
+        // A ring lacking the required material is rejected up front
+        assert!(service.secure_message_with_keys(b"x", SecurityMode::Signed, "link-enc", "link-enc").is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Multi-recipient envelope for fan-out messages
+//!
+//! A message going to several peers is encrypted once under a random
+//! content key, and the content key is wrapped separately under each
+//! recipient's key ring. A recipient unwraps the content key with its own
+//! key and decrypts the payload.
+//!
+//! The header names the fan-out group rather than a key ring; sequence
+//! numbers and replay windows follow the group. Wrapped keys are bound to
+//! the header, and the payload to the header and the recipient list, so
+//! neither can be moved to another message. Every recipient knows the
+//! content key and could re-encrypt the payload for the others, so
+//! messages that recipients must not be able to forge should be signed.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::security::{encode_key_ref, SecurityError, SecurityHeader, SecurityMode, SecurityService, HEADER_VERSION};
+use crate::security::crypto;
+use crate::security::key_ring::KeyRef;
+use crate::security::secret::SecretBytes;
+use crate::security::trust::PeerRole;
+use crate::utils::current_time_millis;
+
+/// Magic bytes prefixing a serialized multi-recipient message
+pub const MULTI_RECIPIENT_MAGIC: &[u8; 4] = b"SGMR";
+
+/// Content key wrapped for one recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedKey {
+    /// Version of the recipient's key ring wrapping the content key
+    pub key: KeyRef,
+    pub nonce: Vec<u8>,
+    pub wrapped: Vec<u8>,
+}
+
+/// Message encrypted once for several recipients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiRecipientMessage {
+    pub header: SecurityHeader,
+    pub recipients: Vec<WrappedKey>,
+    pub payload: Vec<u8>,
+}
+
+impl MultiRecipientMessage {
+    /// Associated data of the payload: the header encoding and the recipient keys
+    fn payload_aad(header: &SecurityHeader, recipients: &[WrappedKey]) -> Vec<u8> {
+        let mut data = header.authenticated_data();
+        data.extend_from_slice(&(recipients.len() as u16).to_be_bytes());
+        for recipient in recipients {
+            encode_key_ref(&mut data, &recipient.key);
+        }
+        data
+    }
+}
+
+impl SecurityService {
+    /// Encrypt a message once for all of the `recipients` key rings
+    ///
+    /// The primary version of each ring wraps the content key. The message
+    /// is signed with the primary version of `signing_ring`, if given.
+    pub fn secure_multi_recipient(&self, data: &[u8], group: &str, recipients: &[&str],
+                                  signing_ring: Option<&str>) -> Result<MultiRecipientMessage> {
+        let suite = self.cipher_suite;
+        self.check_suite(suite)?;
+        
+        if recipients.is_empty() || recipients.len() > u16::MAX as usize {
+            return Err(anyhow!(SecurityError::ConfigError("A message needs 1 to 65535 recipients".into())));
+        }
+        
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = recipients.iter().find(|ring| !seen.insert(**ring)) {
+            return Err(anyhow!(SecurityError::ConfigError(format!("Recipient {} is listed twice", duplicate))));
+        }
+        
+        if std::iter::once(group).chain(recipients.iter().copied()).chain(signing_ring)
+            .any(|name| name.len() > u16::MAX as usize) || self.sender_id.len() > u16::MAX as usize {
+            return Err(anyhow!(SecurityError::ConfigError(
+                "Key names and sender ID must fit in 65535 bytes".into()
+            )));
+        }
+        
+        let signing_key = signing_ring
+            .map(|ring| self.key_manager.primary_key(ring, false, true))
+            .transpose()?;
+        let mode = match signing_key {
+            Some(_) => SecurityMode::EncryptedAndSigned,
+            None => SecurityMode::Encrypted,
+        };
+        
+        let mut header = SecurityHeader {
+            version: HEADER_VERSION,
+            mode,
+            suite,
+            sequence: self.sequences.next(group)?,
+            key: KeyRef::new(group, 0),
+            signing_key,
+            sender: self.sender_id.clone(),
+            timestamp: current_time_millis(),
+            nonce: vec![],
+            signature: None,
+        };
+        
+        // Wrap the content key for each recipient, bound to the header
+        let content_key = crypto::generate_encryption_key();
+        let header_aad = header.authenticated_data();
+        let wrapped_keys = recipients.iter()
+            .map(|ring| self.ratchets.with_send(&self.key_manager, ring, || {
+                let key = self.key_manager.primary_key(ring, true, false)?;
+                let (wrapped, nonce) = self.key_manager.encrypt(&key.encryption_id(), suite,
+                    content_key.expose(), &header_aad)?;
+                Ok(WrappedKey { key, nonce, wrapped })
+            }))
+            .collect::<Result<Vec<_>>>()?;
+        
+        let aad = MultiRecipientMessage::payload_aad(&header, &wrapped_keys);
+        if let Some(signing_key) = &header.signing_key {
+            let mut content = aad.clone();
+            content.extend_from_slice(data);
+            header.signature = Some(self.key_manager.sign(&signing_key.signing_id(), &content)?);
+        }
+        
+        let (payload, nonce) = crypto::encrypt_with_suite(suite, data, &content_key, &aad)?;
+        header.nonce = nonce;
+        
+        Ok(MultiRecipientMessage {
+            header,
+            recipients: wrapped_keys,
+            payload,
+        })
+    }
+    
+    /// Decrypt a multi-recipient message with whichever recipient key this gateway holds
+    pub fn extract_multi_recipient(&self, message: &MultiRecipientMessage) -> Result<Vec<u8>> {
+        let header = &message.header;
+        if header.version != HEADER_VERSION || !header.mode.is_encrypted() {
+            return Err(anyhow!(SecurityError::AuthenticationFailed(
+                format!("Unsupported multi-recipient header: version {}, mode {:?}", header.version, header.mode)
+            )));
+        }
+        
+        if message.recipients.len() > u16::MAX as usize {
+            return Err(anyhow!(SecurityError::AuthenticationFailed("Too many recipients".into())));
+        }
+        
+        self.check_suite(header.suite)?;
+        self.replay_guard.check(header)?;
+        
+        let header_aad = header.authenticated_data();
+        let content_key = message.recipients.iter()
+            .find_map(|recipient| self.ratchets.with_receive(&self.key_manager, &recipient.key, || {
+                let unwrapped = self.key_manager.decrypt(&recipient.key.encryption_id(), header.suite,
+                    &recipient.wrapped, &recipient.nonce, &header_aad)?;
+                Ok(SecretBytes::new(unwrapped))
+            }).ok())
+            .ok_or_else(|| anyhow!(SecurityError::DecryptionFailed("No recipient key unwraps the content key".into())))?;
+        
+        let aad = MultiRecipientMessage::payload_aad(header, &message.recipients);
+        let plaintext = crypto::decrypt_with_suite(header.suite, &message.payload, &header.nonce, &content_key, &aad)?;
+        
+        if header.mode.is_signed() {
+            let signature = header.signature.as_ref()
+                .ok_or_else(|| SecurityError::AuthenticationFailed("Missing signature".into()))?;
+            
+            let mut content = aad;
+            content.extend_from_slice(&plaintext);
+            crypto::verify_signature(
+                &content,
+                signature,
+                &self.key_manager.get_peer_verification_key(
+                    &header.verification_id()?, &header.sender, PeerRole::Messaging)?
+            )?;
+        }
+        
+        // Only record the sequence number once the message is authenticated
+        self.replay_guard.accept(header)?;
+        
+        Ok(plaintext)
+    }
+    
+    /// Serialize a multi-recipient message, prefixed with `MULTI_RECIPIENT_MAGIC`
+    pub fn serialize_multi_recipient(&self, message: &MultiRecipientMessage) -> Result<Vec<u8>> {
+        let mut data = MULTI_RECIPIENT_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, message)
+            .map_err(|e| anyhow!("Failed to serialize multi-recipient message: {}", e))?;
+        Ok(data)
+    }
+    
+    /// Deserialize bytes to a multi-recipient message
+    pub fn deserialize_multi_recipient(&self, data: &[u8]) -> Result<MultiRecipientMessage> {
+        let body = data.strip_prefix(MULTI_RECIPIENT_MAGIC)
+            .ok_or_else(|| anyhow!("Not a multi-recipient message"))?;
+        
+        bincode::deserialize(body)
+            .map_err(|e| anyhow!("Failed to deserialize multi-recipient message: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::key_manager::{KeyManager, KeyType};
+    
+    /// A sender sharing a distinct key with each of three peers
+    fn create_services() -> (SecurityService, Vec<SecurityService>) {
+        let sender = KeyManager::new();
+        sender.generate_keypair("gw-a", "Identity of gw-a", None).unwrap();
+        
+        let peers = ["peer-1", "peer-2", "peer-3"].iter()
+            .map(|ring| {
+                sender.generate_encryption_key(ring, "Peer key", None).unwrap();
+                let km = KeyManager::new();
+                km.import_key(ring, KeyType::Encryption, sender.get_encryption_key(ring).unwrap().expose(),
+                    "Peer key", None).unwrap();
+                km.import_key("gw-a-verify", KeyType::Verification,
+                    &sender.get_verification_key("gw-a-verify").unwrap(), "Sender", None).unwrap();
+                let mut service = SecurityService::new(km);
+                service.set_sender_id(ring);
+                service
+            })
+            .collect();
+        
+        let mut service = SecurityService::new(sender);
+        service.set_sender_id("gw-a");
+        (service, peers)
+    }
+    
+    #[test]
+    fn test_multi_recipient_roundtrip() {
+        let (sender, peers) = create_services();
+        let data = b"mission data load";
+        
+        let message = sender.secure_multi_recipient(data, "fanout", &["peer-1", "peer-2"], Some("gw-a")).unwrap();
+        assert_eq!(message.recipients.len(), 2);
+        let bytes = sender.serialize_multi_recipient(&message).unwrap();
+        
+        for peer in &peers[..2] {
+            let received = peer.deserialize_multi_recipient(&bytes).unwrap();
+            assert_eq!(peer.extract_multi_recipient(&received).unwrap(), data);
+            assert!(peer.extract_multi_recipient(&received).is_err());
+        }
+        
+        // Peers that are not recipients cannot decrypt
+        assert!(peers[2].extract_multi_recipient(&message).is_err());
+        
+        // Duplicate recipients are refused
+        assert!(sender.secure_multi_recipient(data, "fanout", &["peer-1", "peer-1"], None).is_err());
+    }
+    
+    #[test]
+    fn test_multi_recipient_tampering_detected() {
+        let (sender, peers) = create_services();
+        
+        // Dropping another recipient breaks the payload binding
+        let mut stripped = sender.secure_multi_recipient(b"x", "fanout", &["peer-1", "peer-2"], None).unwrap();
+        stripped.recipients.truncate(1);
+        assert!(peers[0].extract_multi_recipient(&stripped).is_err());
+        
+        // A wrapped key cannot be moved to another message
+        let first = sender.secure_multi_recipient(b"x", "fanout", &["peer-1"], None).unwrap();
+        let mut second = sender.secure_multi_recipient(b"y", "fanout", &["peer-1"], None).unwrap();
+        second.recipients = first.recipients.clone();
+        assert!(peers[0].extract_multi_recipient(&second).is_err());
+        
+        // A modified signed payload fails
+        let mut signed = sender.secure_multi_recipient(b"z", "fanout", &["peer-1"], Some("gw-a")).unwrap();
+        signed.payload[0] ^= 0x01;
+        assert!(peers[0].extract_multi_recipient(&signed).is_err());
+        
+        assert_eq!(peers[0].extract_multi_recipient(&first).unwrap(), b"x");
+    }
+}
@@ -0,0 +1,403 @@
+//! Forward-secret ratchet for handshake session keys
+//!
+//! A handshake session key is only used to seed a hash chain. Each step of
+//! the chain yields the encryption key for one epoch and the chain key for
+//! the next, and the chain key is erased once stepped, so a key captured
+//! today does not decrypt traffic from earlier epochs.
+//!
+//! Epoch keys are session keys in the `KeyManager`, stored as versions of
+//! the session key ring (`session/<from>/<to>/v<epoch>`), so the header key
+//! reference tells the receiver which epoch a message belongs to. The
+//! sender moves to the next epoch after a number of messages or a maximum
+//! key age and deletes the previous epoch key. The receiver follows the
+//! sender's epoch, deriving at most `max_skipped_keys` epochs ahead, and
+//! keeps that many earlier epoch keys for messages that arrive late. The
+//! receiver only advances once a message in the new epoch authenticates.
+
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::security::{crypto, SecurityError};
+use crate::security::handshake::SessionKeys;
+use crate::security::key_manager::KeyManager;
+use crate::security::key_ring::KeyRef;
+use crate::security::secret::SecretBytes;
+
+/// HKDF info deriving an epoch key and the next chain key from a chain key
+const RATCHET_INFO: &[u8] = b"secure-gateway session ratchet";
+
+/// HKDF info deriving the first chain key from a handshake session key
+const RATCHET_SEED_INFO: &[u8] = b"secure-gateway session ratchet seed";
+
+/// When the sender moves to the next epoch, and how far the receiver follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RatchetSchedule {
+    /// Messages encrypted under one epoch key
+    pub messages_per_key: u64,
+    
+    /// Longest time an epoch key is used for sending
+    pub max_key_age: Duration,
+    
+    /// Epochs the receiver may skip ahead, and earlier epoch keys it keeps
+    pub max_skipped_keys: u32,
+}
+
+impl Default for RatchetSchedule {
+    fn default() -> Self {
+        Self {
+            messages_per_key: 1000,
+            max_key_age: Duration::from_secs(10 * 60),
+            max_skipped_keys: 32,
+        }
+    }
+}
+
+/// Step a chain key, returning the epoch key and the next chain key
+fn step_chain(chain_key: &SecretBytes) -> Result<(SecretBytes, SecretBytes)> {
+    let mut output = SecretBytes::new(vec![0u8; 2 * crypto::CHACHA_KEY_SIZE]);
+    Hkdf::<Sha256>::new(None, chain_key.expose())
+        .expand(RATCHET_INFO, output.expose_mut())
+        .map_err(|e| anyhow!(SecurityError::KeyError(format!("Ratchet derivation failed: {}", e))))?;
+    
+    let (epoch_key, next_chain_key) = output.expose().split_at(crypto::CHACHA_KEY_SIZE);
+    Ok((SecretBytes::from_slice(epoch_key), SecretBytes::from_slice(next_chain_key)))
+}
+
+/// Derive the chain seed from the handshake key of a session ring, clearing the ring
+///
+/// The derivation runs in the key's backend, so the handshake key itself
+/// never has to leave it.
+fn take_seed(key_manager: &KeyManager, ring: &str) -> Result<SecretBytes> {
+    let seed = key_manager.derive(ring, None, RATCHET_SEED_INFO, crypto::CHACHA_KEY_SIZE)?;
+    
+    // Epoch keys of an earlier session on the same ring must not outrank the new ones
+    for version in key_manager.ring_versions(ring)? {
+        key_manager.delete_key(&KeyRef::new(ring, version).encryption_id())?;
+    }
+    
+    Ok(seed)
+}
+
+fn install_epoch_key(key_manager: &KeyManager, ring: &str, epoch: u32, key: &SecretBytes) -> Result<()> {
+    key_manager.install_session_key(&KeyRef::new(ring, epoch).encryption_id(), key,
+        &format!("Ratchet epoch {} of {}", epoch, ring))
+}
+
+/// Sending half of a session
+struct SendChain {
+    ring: String,
+    chain_key: SecretBytes,
+    epoch: u32,
+    messages: u64,
+    started: Instant,
+    schedule: RatchetSchedule,
+}
+
+impl SendChain {
+    fn new(key_manager: &KeyManager, ring: &str, schedule: RatchetSchedule) -> Result<Self> {
+        let mut chain = Self {
+            ring: ring.to_string(),
+            chain_key: take_seed(key_manager, ring)?,
+            epoch: 0,
+            messages: 0,
+            started: Instant::now(),
+            schedule,
+        };
+        
+        chain.advance(key_manager)?;
+        Ok(chain)
+    }
+    
+    /// Move to the next epoch, erasing the current epoch key
+    fn advance(&mut self, key_manager: &KeyManager) -> Result<()> {
+        let (epoch_key, next_chain_key) = step_chain(&self.chain_key)?;
+        install_epoch_key(key_manager, &self.ring, self.epoch + 1, &epoch_key)?;
+        
+        if self.epoch > 0 {
+            key_manager.delete_key(&KeyRef::new(&self.ring, self.epoch).encryption_id())?;
+        }
+        
+        self.chain_key = next_chain_key;
+        self.epoch += 1;
+        self.messages = 0;
+        self.started = Instant::now();
+        Ok(())
+    }
+    
+    /// Account for one outbound message, advancing first if the epoch is used up
+    fn step(&mut self, key_manager: &KeyManager) -> Result<()> {
+        if self.messages >= self.schedule.messages_per_key || self.started.elapsed() >= self.schedule.max_key_age {
+            self.advance(key_manager)?;
+        }
+        
+        self.messages += 1;
+        Ok(())
+    }
+}
+
+/// Epoch keys derived for an inbound message but not yet committed
+struct PendingAdvance {
+    epoch: u32,
+    chain_key: SecretBytes,
+}
+
+/// Receiving half of a session
+struct ReceiveChain {
+    ring: String,
+    chain_key: SecretBytes,
+    epoch: u32,
+    
+    /// Earlier epochs whose keys are kept for late messages, oldest first
+    retained: VecDeque<u32>,
+    max_skipped: u32,
+}
+
+impl ReceiveChain {
+    fn new(key_manager: &KeyManager, ring: &str, schedule: RatchetSchedule) -> Result<Self> {
+        let seed = take_seed(key_manager, ring)?;
+        let (epoch_key, chain_key) = step_chain(&seed)?;
+        install_epoch_key(key_manager, ring, 1, &epoch_key)?;
+        
+        Ok(Self {
+            ring: ring.to_string(),
+            chain_key,
+            epoch: 1,
+            retained: VecDeque::new(),
+            max_skipped: schedule.max_skipped_keys,
+        })
+    }
+    
+    /// Install the keys up to `epoch`, if it is ahead of the current one
+    fn prepare(&self, key_manager: &KeyManager, epoch: u32) -> Result<Option<PendingAdvance>> {
+        if epoch <= self.epoch {
+            return Ok(None);
+        }
+        
+        if epoch - self.epoch > self.max_skipped {
+            return Err(anyhow!(SecurityError::KeyError(format!(
+                "{} epoch {} is more than {} epochs ahead of {}", self.ring, epoch, self.max_skipped, self.epoch
+            ))));
+        }
+        
+        let mut chain_key = self.chain_key.clone();
+        for next in self.epoch + 1..=epoch {
+            let (epoch_key, next_chain_key) = step_chain(&chain_key)?;
+            if let Err(e) = install_epoch_key(key_manager, &self.ring, next, &epoch_key) {
+                self.abort(key_manager, &PendingAdvance { epoch: next - 1, chain_key });
+                return Err(e);
+            }
+            chain_key = next_chain_key;
+        }
+        
+        Ok(Some(PendingAdvance { epoch, chain_key }))
+    }
+    
+    /// Adopt a pending advance once its message authenticated
+    fn commit(&mut self, key_manager: &KeyManager, pending: PendingAdvance) -> Result<()> {
+        self.retained.extend(self.epoch..pending.epoch);
+        self.chain_key = pending.chain_key;
+        self.epoch = pending.epoch;
+        
+        while self.retained.len() > self.max_skipped as usize {
+            if let Some(epoch) = self.retained.pop_front() {
+                // Messages may have used the key already; it may be gone
+                let _ = key_manager.delete_key(&KeyRef::new(&self.ring, epoch).encryption_id());
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Remove the keys installed by a pending advance
+    fn abort(&self, key_manager: &KeyManager, pending: &PendingAdvance) {
+        for epoch in self.epoch + 1..=pending.epoch {
+            let _ = key_manager.delete_key(&KeyRef::new(&self.ring, epoch).encryption_id());
+        }
+    }
+}
+
+/// Ratchets of the sessions a security service takes part in, by key ring
+#[derive(Default)]
+pub struct SessionRatchets {
+    sending: RwLock<HashMap<String, Arc<Mutex<SendChain>>>>,
+    receiving: RwLock<HashMap<String, Arc<Mutex<ReceiveChain>>>>,
+}
+
+impl SessionRatchets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Start ratcheting the keys installed by a handshake
+    ///
+    /// The handshake keys are erased; from now on the session rings hold
+    /// only epoch keys. Starting again with new handshake keys replaces the
+    /// session's chains.
+    pub fn start(&self, key_manager: &KeyManager, keys: &SessionKeys, schedule: RatchetSchedule) -> Result<()> {
+        let send = SendChain::new(key_manager, &keys.send_key_id, schedule)?;
+        let receive = ReceiveChain::new(key_manager, &keys.receive_key_id, schedule)?;
+        
+        self.sending.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on session ratchets"))?
+            .insert(keys.send_key_id.clone(), Arc::new(Mutex::new(send)));
+        self.receiving.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on session ratchets"))?
+            .insert(keys.receive_key_id.clone(), Arc::new(Mutex::new(receive)));
+        
+        Ok(())
+    }
+    
+    /// Current sending epoch of a ring, if it is ratcheted
+    pub fn send_epoch(&self, ring: &str) -> Result<Option<u32>> {
+        let Some(chain) = self.send_chain(ring)? else {
+            return Ok(None);
+        };
+        
+        let chain = chain.lock().map_err(|_| anyhow!("Failed to acquire lock on session ratchet"))?;
+        Ok(Some(chain.epoch))
+    }
+    
+    fn send_chain(&self, ring: &str) -> Result<Option<Arc<Mutex<SendChain>>>> {
+        Ok(self.sending.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on session ratchets"))?
+            .get(ring)
+            .cloned())
+    }
+    
+    /// Run `send` for one outbound message on `ring`, stepping its ratchet first
+    ///
+    /// The ratchet stays locked while sending so the epoch key cannot be
+    /// erased in between.
+    pub(crate) fn with_send<R>(&self, key_manager: &KeyManager, ring: &str, send: impl FnOnce() -> Result<R>) -> Result<R> {
+        let Some(chain) = self.send_chain(ring)? else {
+            return send();
+        };
+        
+        let mut chain = chain.lock().map_err(|_| anyhow!("Failed to acquire lock on session ratchet"))?;
+        chain.step(key_manager)?;
+        send()
+    }
+    
+    /// Run `open` for an inbound message under `key`, following the sender's epoch
+    ///
+    /// The receiving ratchet only advances if `open` succeeds.
+    pub(crate) fn with_receive<R>(&self, key_manager: &KeyManager, key: &KeyRef, open: impl FnOnce() -> Result<R>) -> Result<R> {
+        let chain = self.receiving.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on session ratchets"))?
+            .get(&key.name)
+            .cloned();
+        let Some(chain) = chain else {
+            return open();
+        };
+        
+        let mut chain = chain.lock().map_err(|_| anyhow!("Failed to acquire lock on session ratchet"))?;
+        let pending = chain.prepare(key_manager, key.version)?;
+        
+        let result = open();
+        match (pending, &result) {
+            (Some(pending), Ok(_)) => chain.commit(key_manager, pending)?,
+            (Some(pending), Err(_)) => chain.abort(key_manager, &pending),
+            (None, _) => {},
+        }
+        
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{SecuredMessage, SecurityMode, SecurityService};
+    use crate::security::handshake::{Initiator, Responder};
+    use crate::security::key_manager::KeyType;
+    
+    /// Security services for two gateways that completed a handshake
+    fn create_session(schedule: RatchetSchedule) -> (SecurityService, SecurityService, SessionKeys) {
+        let km_a = KeyManager::new();
+        let km_b = KeyManager::new();
+        km_a.generate_keypair("gw-a", "Identity of gw-a", None).unwrap();
+        km_b.generate_keypair("gw-b", "Identity of gw-b", None).unwrap();
+        km_b.import_key("gw-a-verify", KeyType::Verification,
+            &km_a.get_verification_key("gw-a-verify").unwrap(), "Peer gw-a", None).unwrap();
+        km_a.import_key("gw-b-verify", KeyType::Verification,
+            &km_b.get_verification_key("gw-b-verify").unwrap(), "Peer gw-b", None).unwrap();
+        
+        let (initiator, init) = Initiator::start("gw-a").unwrap();
+        let (responder, response) = Responder::respond(&km_b, "gw-b", &init).unwrap();
+        let (finish, keys_a) = initiator.finish(&km_a, &response).unwrap();
+        let keys_b = responder.finish(&km_b, &finish).unwrap();
+        
+        let mut a = SecurityService::new(km_a);
+        let mut b = SecurityService::new(km_b);
+        a.set_sender_id("gw-a");
+        b.set_sender_id("gw-b");
+        a.start_session_ratchet(&keys_a, schedule).unwrap();
+        b.start_session_ratchet(&keys_b, schedule).unwrap();
+        
+        (a, b, keys_a)
+    }
+    
+    #[test]
+    fn test_ratchet_forward_secrecy() {
+        let schedule = RatchetSchedule { messages_per_key: 2, max_key_age: Duration::from_secs(3600), max_skipped_keys: 4 };
+        let (a, b, keys) = create_session(schedule);
+        let ring = keys.send_key_id.as_str();
+        
+        // The handshake key itself is gone
+        assert!(a.key_manager().get_encryption_key(ring).is_err());
+        
+        let mut sent = Vec::new();
+        for i in 0..6u8 {
+            sent.push(a.secure_message(&[i], SecurityMode::Encrypted, ring).unwrap());
+        }
+        let epochs: Vec<u32> = sent.iter().map(|secured| secured.header.key.version).collect();
+        assert_eq!(epochs, vec![1, 1, 2, 2, 3, 3]);
+        
+        // Only the current epoch key is left on the sender
+        assert_eq!(a.key_manager().ring_versions(ring).unwrap(), vec![3]);
+        
+        // The receiver follows, skipping epoch 2 and accepting it late
+        assert_eq!(b.extract_message(&sent[0]).unwrap(), vec![0]);
+        assert_eq!(b.extract_message(&sent[4]).unwrap(), vec![4]);
+        assert_eq!(b.extract_message(&sent[2]).unwrap(), vec![2]);
+        assert_eq!(b.extract_message(&sent[5]).unwrap(), vec![5]);
+    }
+    
+    #[test]
+    fn test_ratchet_bounds() {
+        let schedule = RatchetSchedule { messages_per_key: 1, max_key_age: Duration::from_secs(3600), max_skipped_keys: 2 };
+        let (a, b, keys) = create_session(schedule);
+        let ring = keys.send_key_id.as_str();
+        
+        let sent: Vec<SecuredMessage> = (0..6u8)
+            .map(|i| a.secure_message(&[i], SecurityMode::Encrypted, ring).unwrap())
+            .collect();
+        
+        // Too far ahead is refused without losing the current epoch
+        assert!(b.extract_message(&sent[4]).is_err());
+        assert!(b.extract_message(&sent[0]).is_ok());
+        
+        // A forged message in a later epoch does not advance the receiver
+        let mut forged = sent[2].clone();
+        forged.payload[0] ^= 0xFF;
+        assert!(b.extract_message(&forged).is_err());
+        assert_eq!(b.key_manager().ring_versions(&keys.send_key_id).unwrap(), vec![1]);
+        
+        // Keys older than the skip window are erased
+        assert!(b.extract_message(&sent[2]).is_ok());
+        assert!(b.extract_message(&sent[4]).is_ok());
+        assert!(b.extract_message(&sent[1]).is_err());
+        assert!(b.extract_message(&sent[3]).is_ok());
+        
+        // A zero key age ratchets on every message
+        let schedule = RatchetSchedule { max_key_age: Duration::ZERO, ..RatchetSchedule::default() };
+        let (a, _, keys) = create_session(schedule);
+        a.secure_message(b"x", SecurityMode::Encrypted, &keys.send_key_id).unwrap();
+        a.secure_message(b"y", SecurityMode::Encrypted, &keys.send_key_id).unwrap();
+        assert_eq!(a.session_ratchets().send_epoch(&keys.send_key_id).unwrap(), Some(3));
+    }
+}
@@ -0,0 +1,381 @@
+//! Scheduled and on-demand key rotation
+//!
+//! The rotator adds a new version to the default encryption and signing key
+//! rings. Encryption key rings selected by key policies are rotated on their
+//! own when they near their usage limits. Replaced versions are not deleted;
+//! they are set to expire after a grace window so that traffic already in
+//! flight can still be decrypted and verified. Every rotation is recorded in
+//! an audit log and on the `audit` log target.
+
+use anyhow::{anyhow, Result};
+use log::info;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::security::SecurityService;
+use crate::security::key_manager::KeyType;
+use crate::security::key_ring::{KeyRef, RingMaterial};
+use crate::utils::current_time_millis;
+
+/// Ring versions currently used for outbound traffic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveKeys {
+    /// Encryption key version
+    pub encryption_key: KeyRef,
+    
+    /// Signing keypair version (None for an encryption key ring rotated on its own)
+    pub signing_keypair: Option<KeyRef>,
+}
+
+impl fmt::Display for ActiveKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "encryption {}", self.encryption_key)?;
+        
+        match &self.signing_keypair {
+            Some(signing) => write!(f, ", signing {}", signing),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What caused a rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationTrigger {
+    Scheduled,
+    OnDemand,
+    
+    /// The primary version of an encryption key ring neared its usage limits
+    UsageLimit,
+}
+
+/// Audit record of a completed rotation
+#[derive(Debug, Clone)]
+pub struct RotationEvent {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub trigger: RotationTrigger,
+    
+    /// Versions replaced, valid until `retired_until` (None if the ring was empty)
+    pub retired: Option<ActiveKeys>,
+    
+    /// Versions now used for outbound traffic
+    pub activated: ActiveKeys,
+    
+    /// Expiry of the replaced versions, in seconds since the Unix epoch
+    pub retired_until: u64,
+}
+
+/// Rotates the outbound key rings of a security service
+pub struct KeyRotator {
+    security: Arc<SecurityService>,
+    
+    /// Encryption key ring name
+    encryption_ring: String,
+    
+    /// Signing key ring name
+    signing_ring: String,
+    
+    /// Key lifetime before a scheduled rotation, in seconds (None = on demand only)
+    interval_secs: Option<u64>,
+    
+    /// Time replaced keys remain valid, in seconds
+    grace_secs: u64,
+    
+    /// Serializes rotations and records them
+    audit_log: Mutex<Vec<RotationEvent>>,
+}
+
+impl KeyRotator {
+    /// Create a rotator for the given key rings
+    pub fn new(security: Arc<SecurityService>, encryption_ring: &str, signing_ring: &str,
+              interval_secs: Option<u64>, grace_secs: u64) -> Result<Self> {
+        Ok(Self {
+            security,
+            encryption_ring: encryption_ring.to_string(),
+            signing_ring: signing_ring.to_string(),
+            interval_secs,
+            grace_secs,
+            audit_log: Mutex::new(Vec::new()),
+        })
+    }
+    
+    /// Name of the encryption key ring
+    pub fn encryption_ring(&self) -> &str {
+        &self.encryption_ring
+    }
+    
+    /// Name of the signing key ring
+    pub fn signing_ring(&self) -> &str {
+        &self.signing_ring
+    }
+    
+    /// Primary versions of the rings, used for outbound traffic
+    pub fn active_keys(&self) -> Result<ActiveKeys> {
+        let key_manager = self.security.key_manager();
+        
+        Ok(ActiveKeys {
+            encryption_key: key_manager.primary_key(&self.encryption_ring, true, false)?,
+            signing_keypair: Some(key_manager.primary_key(&self.signing_ring, false, true)?),
+        })
+    }
+    
+    /// Check whether the primary encryption key is due for scheduled rotation
+    ///
+    /// A ring without a usable version is always due, so the first check creates it.
+    pub fn rotation_due(&self) -> Result<bool> {
+        let Some(interval) = self.interval_secs else {
+            return Ok(false);
+        };
+        
+        let key_manager = self.security.key_manager();
+        let Ok(primary) = key_manager.primary_key(&self.encryption_ring, true, false) else {
+            return Ok(true);
+        };
+        
+        let now = current_time_millis() / 1000;
+        let created_at = key_manager.get_metadata(&primary.encryption_id())?.created_at;
+        
+        Ok(now.saturating_sub(created_at) >= interval)
+    }
+    
+    /// Check whether the primary version of an encryption key ring has neared its usage limits
+    pub fn usage_rotation_due(&self, ring: &str) -> Result<bool> {
+        let key_manager = self.security.key_manager();
+        match key_manager.primary_key(ring, true, false) {
+            Ok(primary) => key_manager.usage_rotation_due(&primary.encryption_id()),
+            Err(_) => Ok(false),
+        }
+    }
+    
+    /// Add new primary versions to the rings and retire the old ones
+    pub fn rotate(&self, trigger: RotationTrigger) -> Result<RotationEvent> {
+        let key_manager = self.security.key_manager();
+        
+        // Hold the lock throughout so concurrent rotations are serialized
+        let mut audit_log = self.audit_log.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on rotation audit log"))?;
+        
+        let retired = self.active_keys().ok();
+        
+        let activated = if self.encryption_ring == self.signing_ring {
+            let key = key_manager.add_ring_version(&self.encryption_ring, RingMaterial::Both, "Rotated key")?;
+            ActiveKeys { encryption_key: key.clone(), signing_keypair: Some(key) }
+        } else {
+            ActiveKeys {
+                encryption_key: key_manager.add_ring_version(&self.encryption_ring,
+                    RingMaterial::Encryption, "Rotated encryption key")?,
+                signing_keypair: Some(key_manager.add_ring_version(&self.signing_ring,
+                    RingMaterial::Signing, "Rotated signing keypair")?),
+            }
+        };
+        
+        self.record(&mut audit_log, trigger, retired, activated)
+    }
+    
+    /// Add a new primary version to an encryption key ring selected by a key policy
+    ///
+    /// The default rings are rotated together as by `rotate`. Any other ring
+    /// gets a version holding the same material as the one it replaces.
+    pub fn rotate_ring(&self, ring: &str, trigger: RotationTrigger) -> Result<RotationEvent> {
+        if ring == self.encryption_ring || ring == self.signing_ring {
+            return self.rotate(trigger);
+        }
+        
+        let key_manager = self.security.key_manager();
+        let mut audit_log = self.audit_log.lock()
+            .map_err(|_| anyhow!("Failed to acquire lock on rotation audit log"))?;
+        
+        let retired = key_manager.primary_key(ring, true, false).ok();
+        
+        // Keep the ring's signing keypair alongside its encryption key
+        let signing = match &retired {
+            Some(key) => key_manager.ring_version_metadata(key)?
+                .iter()
+                .any(|meta| meta.key_type == KeyType::Signing),
+            None => false,
+        };
+        
+        let (material, description) = if signing {
+            (RingMaterial::Both, "Rotated key")
+        } else {
+            (RingMaterial::Encryption, "Rotated encryption key")
+        };
+        let key = key_manager.add_ring_version(ring, material, description)?;
+        
+        let keys = |key: KeyRef| ActiveKeys { signing_keypair: signing.then(|| key.clone()), encryption_key: key };
+        self.record(&mut audit_log, trigger, retired.map(keys), keys(key))
+    }
+    
+    /// Retire the replaced versions after the grace window and log the rotation
+    fn record(&self, audit_log: &mut Vec<RotationEvent>, trigger: RotationTrigger,
+              retired: Option<ActiveKeys>, activated: ActiveKeys) -> Result<RotationEvent> {
+        let key_manager = self.security.key_manager();
+        
+        let timestamp = current_time_millis();
+        let retired_until = timestamp / 1000 + self.grace_secs;
+        if let Some(retired) = &retired {
+            key_manager.retire_ring_version(&retired.encryption_key, retired_until)?;
+            if let Some(signing) = &retired.signing_keypair {
+                key_manager.retire_ring_version(signing, retired_until)?;
+            }
+        }
+        
+        let event = RotationEvent {
+            timestamp,
+            trigger,
+            retired,
+            activated,
+            retired_until,
+        };
+        
+        match &event.retired {
+            Some(retired) => info!(target: "audit", "Key rotation ({:?}): {} replaced by {}, old keys valid until {}",
+                event.trigger, retired, event.activated, event.retired_until),
+            None => info!(target: "audit", "Key rotation ({:?}): created {}", event.trigger, event.activated),
+        }
+        
+        audit_log.push(event.clone());
+        
+        Ok(event)
+    }
+    
+    /// Rotations performed since startup, oldest first
+    pub fn audit_log(&self) -> Result<Vec<RotationEvent>> {
+        self.audit_log.lock()
+            .map(|log| log.clone())
+            .map_err(|_| anyhow!("Failed to acquire lock on rotation audit log"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeyUsageLimits;
+    use crate::security::SecurityMode;
+    use crate::security::crypto::CipherSuite;
+    use crate::security::key_manager::KeyManager;
+    
+    fn create_rotator(interval_secs: Option<u64>) -> KeyRotator {
+        let km = KeyManager::new();
+        km.generate_encryption_key("enc", "Initial encryption key", None).unwrap();
+        km.generate_keypair("sign", "Initial signing keypair", None).unwrap();
+        
+        KeyRotator::new(Arc::new(SecurityService::new(km)), "enc", "sign", interval_secs, 3600).unwrap()
+    }
+    
+    #[test]
+    fn test_rotation_with_grace() {
+        let rotator = create_rotator(Some(86_400));
+        let km = rotator.security.key_manager();
+        assert!(!rotator.rotation_due().unwrap());
+        
+        let before = rotator.security.secure_message(b"in flight", SecurityMode::Encrypted, "enc").unwrap();
+        assert_eq!(before.header.key, KeyRef::new("enc", 0));
+        
+        let event = rotator.rotate(RotationTrigger::OnDemand).unwrap();
+        let retired = event.retired.clone().unwrap();
+        assert_eq!(retired.encryption_key, KeyRef::new("enc", 0));
+        assert_eq!(event.activated.encryption_key, KeyRef::new("enc", 1));
+        assert_eq!(rotator.active_keys().unwrap(), event.activated);
+        assert!(km.get_signing_key(&event.activated.signing_keypair.as_ref().unwrap().signing_id()).is_ok());
+        
+        // Old versions stay usable until the end of the grace window
+        assert_eq!(km.get_metadata("enc").unwrap().expires_at, Some(event.retired_until));
+        assert_eq!(km.get_metadata("sign-verify").unwrap().expires_at, Some(event.retired_until));
+        assert_eq!(rotator.security.extract_message(&before).unwrap(), b"in flight");
+        
+        let after = rotator.security.secure_message(b"new", SecurityMode::Encrypted, "enc").unwrap();
+        assert_eq!(after.header.key, event.activated.encryption_key);
+        
+        // Rotating again only retires the previous version
+        let second = rotator.rotate(RotationTrigger::Scheduled).unwrap();
+        assert_eq!(second.retired, Some(event.activated.clone()));
+        assert_eq!(second.activated.signing_keypair, Some(KeyRef::new("sign", 2)));
+        assert_eq!(rotator.audit_log().unwrap().len(), 2);
+    }
+    
+    #[test]
+    fn test_resume_and_bootstrap() {
+        let rotator = create_rotator(None);
+        let event = rotator.rotate(RotationTrigger::OnDemand).unwrap();
+        assert!(!rotator.rotation_due().unwrap());
+        
+        // A new rotator over the same store resumes from the primary versions
+        let resumed = KeyRotator::new(Arc::clone(&rotator.security), "enc", "sign", Some(1), 0).unwrap();
+        assert_eq!(resumed.active_keys().unwrap(), event.activated);
+        
+        // Without any keys, rotation is due immediately and creates them
+        let empty = KeyRotator::new(Arc::new(SecurityService::new(KeyManager::new())), "link", "link", Some(86_400), 0).unwrap();
+        assert!(empty.active_keys().is_err());
+        assert!(empty.rotation_due().unwrap());
+        
+        let first = empty.rotate(RotationTrigger::Scheduled).unwrap();
+        assert!(first.retired.is_none());
+        assert_eq!(first.activated.signing_keypair, Some(first.activated.encryption_key.clone()));
+        assert!(!empty.rotation_due().unwrap());
+        assert!(empty.security.secure_message(b"x", SecurityMode::EncryptedAndSigned, "link").is_ok());
+    }
+    
+    #[test]
+    fn test_usage_limits() {
+        let mut km = KeyManager::new();
+        km.set_usage_limits(KeyUsageLimits { max_encryptions: Some(4), max_bytes: Some(1024), rotate_at_percent: Some(50) });
+        let rotator = KeyRotator::new(Arc::new(SecurityService::new(km)), "link", "link", None, 3600).unwrap();
+        let first = rotator.rotate(RotationTrigger::OnDemand).unwrap();
+        let security = &rotator.security;
+        
+        security.secure_message(b"one", SecurityMode::Encrypted, "link").unwrap();
+        assert!(!rotator.usage_rotation_due("link").unwrap());
+        security.secure_message(b"two", SecurityMode::Encrypted, "link").unwrap();
+        assert!(rotator.usage_rotation_due("link").unwrap());
+        
+        // The exhausted key is refused, the rotated one starts from zero
+        let old_id = first.activated.encryption_key.encryption_id();
+        security.key_manager().encrypt(&old_id, CipherSuite::ChaCha20Poly1305, b"three", b"").unwrap();
+        security.key_manager().encrypt(&old_id, CipherSuite::ChaCha20Poly1305, b"four", b"").unwrap();
+        assert!(security.key_manager().encrypt(&old_id, CipherSuite::ChaCha20Poly1305, b"five", b"").is_err());
+        assert_eq!(security.key_manager().key_usage(&old_id).unwrap().encryptions, 4);
+        
+        let event = rotator.rotate(RotationTrigger::UsageLimit).unwrap();
+        assert!(!rotator.usage_rotation_due("link").unwrap());
+        let secured = security.secure_message(b"six", SecurityMode::Encrypted, "link").unwrap();
+        assert_eq!(secured.header.key, event.activated.encryption_key);
+        
+        // Byte limits apply as well
+        let new_id = event.activated.encryption_key.encryption_id();
+        assert!(security.key_manager().encrypt(&new_id, CipherSuite::ChaCha20Poly1305, &[0; 1024], b"").is_err());
+    }
+    
+    #[test]
+    fn test_rotate_policy_rings() {
+        let mut km = KeyManager::new();
+        km.set_usage_limits(KeyUsageLimits { max_encryptions: Some(2), max_bytes: None, rotate_at_percent: Some(50) });
+        km.generate_encryption_key("enc", "Initial encryption key", None).unwrap();
+        km.generate_keypair("sign", "Initial signing keypair", None).unwrap();
+        km.generate_encryption_key("telemetry", "Policy encryption key", None).unwrap();
+        km.add_ring_version("peer", RingMaterial::Both, "Policy key").unwrap();
+        let rotator = KeyRotator::new(Arc::new(SecurityService::new(km)), "enc", "sign", None, 3600).unwrap();
+        let security = &rotator.security;
+        
+        // Usage is checked per ring, not only for the default key
+        security.secure_message(b"one", SecurityMode::Encrypted, "telemetry").unwrap();
+        assert!(rotator.usage_rotation_due("telemetry").unwrap());
+        assert!(!rotator.usage_rotation_due("enc").unwrap());
+        
+        let event = rotator.rotate_ring("telemetry", RotationTrigger::UsageLimit).unwrap();
+        assert_eq!(event.retired.unwrap().encryption_key, KeyRef::new("telemetry", 0));
+        assert_eq!(event.activated, ActiveKeys { encryption_key: KeyRef::new("telemetry", 1), signing_keypair: None });
+        assert_eq!(security.key_manager().get_metadata("telemetry").unwrap().expires_at, Some(event.retired_until));
+        assert!(!rotator.usage_rotation_due("telemetry").unwrap());
+        
+        // A ring holding a signing keypair keeps one in the new version
+        let event = rotator.rotate_ring("peer", RotationTrigger::UsageLimit).unwrap();
+        assert_eq!(event.activated.signing_keypair, Some(KeyRef::new("peer", 2)));
+        assert_eq!(security.key_manager().primary_key("peer", true, true).unwrap(), KeyRef::new("peer", 2));
+        
+        // The default rings are rotated together
+        let event = rotator.rotate_ring("enc", RotationTrigger::UsageLimit).unwrap();
+        assert_eq!(event.activated.signing_keypair, Some(KeyRef::new("sign", 1)));
+        assert_eq!(rotator.audit_log().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,157 @@
+//! Containers for secret key material
+//!
+//! `SecretBytes` owns key bytes, wipes them when dropped, never prints them
+//! through `Debug`, and asks the OS to keep them out of swap where possible.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Heap-allocated secret bytes that are zeroed on drop
+pub struct SecretBytes {
+    bytes: Box<[u8]>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    /// Take ownership of secret bytes
+    ///
+    /// The vector's buffer is reused where possible; any copy made along
+    /// the way is wiped.
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        let bytes = if bytes.len() == bytes.capacity() {
+            bytes.into_boxed_slice()
+        } else {
+            // Shrinking would reallocate and leave the old buffer behind
+            let copy = Box::<[u8]>::from(&bytes[..]);
+            bytes.zeroize();
+            copy
+        };
+        
+        let locked = lock_memory(&bytes);
+        Self { bytes, locked }
+    }
+    
+    /// Copy secret bytes from a slice
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+    
+    /// Access the secret bytes
+    pub fn expose(&self) -> &[u8] {
+        &self.bytes
+    }
+    
+    /// Mutable access to the secret bytes, e.g. to fill them in place
+    pub fn expose_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+    
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        Self::from_slice(&self.bytes)
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        
+        if self.locked {
+            unlock_memory(&self.bytes);
+        }
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.bytes.len())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    /// Constant-time comparison
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes.len() == other.bytes.len()
+            && self.bytes.iter().zip(other.bytes.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+/// Serialized exactly like `Vec<u8>`, so existing key stores remain readable
+impl Serialize for SecretBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bytes.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(Self::new)
+    }
+}
+
+/// Lock a buffer into RAM; returns whether the lock succeeded
+#[cfg(unix)]
+fn lock_memory(bytes: &[u8]) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    
+    // Failure is expected when RLIMIT_MEMLOCK is exhausted; the bytes are still wiped on drop
+    unsafe { libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.len()) == 0 }
+}
+
+/// Unlock a buffer; locks are per page and not counted, so this is best-effort
+/// when several secrets share a page
+#[cfg(unix)]
+fn unlock_memory(bytes: &[u8]) {
+    unsafe {
+        libc::munlock(bytes.as_ptr() as *const libc::c_void, bytes.len());
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_memory(_bytes: &[u8]) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock_memory(_bytes: &[u8]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_secret_bytes() {
+        let mut vec = Vec::with_capacity(64);
+        vec.extend_from_slice(&[0xA5; 32]);
+        let secret = SecretBytes::new(vec);
+        
+        assert_eq!(secret.expose(), &[0xA5; 32]);
+        assert_eq!(format!("{:?}", secret), "SecretBytes([REDACTED; 32])");
+        assert_eq!(secret.clone(), secret);
+        
+        // Serializes the same as the plain bytes
+        let encoded = bincode::serialize(&secret).unwrap();
+        assert_eq!(encoded, bincode::serialize(&vec![0xA5u8; 32]).unwrap());
+        assert_eq!(bincode::deserialize::<SecretBytes>(&encoded).unwrap(), secret);
+    }
+}
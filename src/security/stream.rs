@@ -0,0 +1,360 @@
+//! Chunked streaming encryption for large payloads
+//!
+//! `SecurityService::secure_message` encrypts a payload in one AEAD call,
+//! so the whole payload must be in memory. A secured stream instead splits
+//! the payload into fixed-size chunks, each encrypted separately:
+//!
+//! ```text
+//! magic | frame(header) | frame(chunk 0) | ... | frame(final chunk)
+//! ```
+//!
+//! Each frame is a big-endian `u32` length followed by its bytes. The header
+//! is a bincode `SecurityHeader` whose nonce field holds a random salt; the
+//! chunks are encrypted under a key derived from the ring key and the salt,
+//! so every stream has its own key. A chunk's nonce is its index followed by
+//! a flag marking the final chunk, and the header is its associated data,
+//! so chunks cannot be reordered, moved between streams, or dropped from
+//! the end without failing authentication.
+//!
+//! Plaintext is written out as chunks authenticate. If extraction fails,
+//! the output written so far must be discarded.
+
+use anyhow::{anyhow, Result};
+use rand::{rngs::OsRng, RngCore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::security::{SecurityError, SecurityHeader, SecurityMode, SecurityService, HEADER_VERSION};
+use crate::security::crypto::{self, CipherSuite, TAG_SIZE};
+use crate::security::secret::SecretBytes;
+use crate::utils::current_time_millis;
+
+/// Magic bytes opening a secured stream
+pub const STREAM_MAGIC: &[u8; 4] = b"SGST";
+
+/// Default plaintext bytes per chunk
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest plaintext chunk accepted
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Largest serialized stream header accepted
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Size of the per-stream salt carried in the header nonce
+const SALT_SIZE: usize = 32;
+
+/// HKDF info deriving a stream key from a ring key and salt
+const STREAM_KEY_INFO: &[u8] = b"secure-gateway stream key";
+
+/// Nonce of a chunk: zero padding, the chunk index and the final-chunk flag
+fn chunk_nonce(suite: CipherSuite, index: u64, last: bool) -> Vec<u8> {
+    let mut nonce = vec![0u8; suite.nonce_size()];
+    let flag = nonce.len() - 1;
+    nonce[flag - 8..flag].copy_from_slice(&index.to_be_bytes());
+    nonce[flag] = last as u8;
+    nonce
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    Ok(())
+}
+
+/// Read a frame, or `None` at the end of the input
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        let read = reader.read(&mut len[filled..]).await?;
+        if read == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!(SecurityError::DecryptionFailed("Stream truncated inside a frame".into())));
+        }
+        filled += read;
+    }
+    
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(anyhow!(SecurityError::DecryptionFailed(format!("Stream frame of {} bytes is too large", len))));
+    }
+    
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame).await
+        .map_err(|_| anyhow!(SecurityError::DecryptionFailed("Stream truncated inside a frame".into())))?;
+    Ok(Some(frame))
+}
+
+/// Read up to `buf.len()` bytes, stopping early only at the end of the input
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = reader.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+fn truncated() -> anyhow::Error {
+    anyhow!(SecurityError::DecryptionFailed("Stream ended before its final chunk".into()))
+}
+
+impl SecurityService {
+    /// Encrypt everything read from `reader` as a secured stream written to `writer`
+    ///
+    /// The primary version of the `key_name` key ring is used with the
+    /// default cipher suite, and the stream counts against its usage limits
+    /// as it is written. Returns the number of plaintext bytes.
+    pub async fn secure_stream<R, W>(&self, reader: &mut R, writer: &mut W, key_name: &str,
+                                     chunk_size: usize) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(anyhow!(SecurityError::ConfigError(
+                format!("Stream chunk size must be between 1 and {} bytes", MAX_CHUNK_SIZE)
+            )));
+        }
+        
+        let suite = self.cipher_suite;
+        self.check_suite(suite)?;
+        
+        if key_name.len() > u16::MAX as usize || self.sender_id.len() > u16::MAX as usize {
+            return Err(anyhow!(SecurityError::ConfigError(
+                "Key names and sender ID must fit in 65535 bytes".into()
+            )));
+        }
+        
+        let (header, stream_key) = self.ratchets.with_send(&self.key_manager, key_name, || {
+            let key = self.key_manager.primary_key(key_name, true, false)?;
+            let mut salt = vec![0u8; SALT_SIZE];
+            OsRng.fill_bytes(&mut salt);
+            
+            self.key_manager.record_usage(&key.encryption_id(), u64::from(suite.has_short_nonce()), 0)?;
+            let stream_key = self.key_manager.derive(&key.encryption_id(), Some(&salt), STREAM_KEY_INFO,
+                crypto::CHACHA_KEY_SIZE)?;
+            let header = SecurityHeader {
+                version: HEADER_VERSION,
+                mode: SecurityMode::Encrypted,
+                suite,
+                sequence: self.sequences.next(&key.name)?,
+                key,
+                signing_key: None,
+                sender: self.sender_id.clone(),
+                timestamp: current_time_millis(),
+                nonce: salt,
+                signature: None,
+            };
+            Ok((header, stream_key))
+        })?;
+        
+        let key_id = header.key.encryption_id();
+        let aad = header.authenticated_data();
+        writer.write_all(STREAM_MAGIC).await?;
+        write_frame(writer, &bincode::serialize(&header)
+            .map_err(|e| anyhow!("Failed to serialize stream header: {}", e))?).await?;
+        
+        // Read one chunk ahead so the final chunk can be flagged
+        let mut current = vec![0u8; chunk_size];
+        let mut current_len = read_chunk(reader, &mut current).await?;
+        let mut next = vec![0u8; chunk_size];
+        let mut index = 0u64;
+        let mut total = 0u64;
+        
+        loop {
+            let next_len = if current_len == chunk_size { read_chunk(reader, &mut next).await? } else { 0 };
+            let last = next_len == 0;
+            
+            self.key_manager.record_usage(&key_id, 0, current_len as u64)?;
+            let ciphertext = crypto::encrypt_with_nonce(suite, &current[..current_len],
+                &chunk_nonce(suite, index, last), &stream_key, &aad)?;
+            write_frame(writer, &ciphertext).await?;
+            total += current_len as u64;
+            
+            if last {
+                break;
+            }
+            
+            std::mem::swap(&mut current, &mut next);
+            current_len = next_len;
+            index += 1;
+        }
+        
+        writer.flush().await?;
+        Ok(total)
+    }
+    
+    /// Decrypt a secured stream from `reader`, writing the plaintext to `writer`
+    ///
+    /// Fails if any chunk does not authenticate or the stream ends before
+    /// its final chunk; the output written so far must then be discarded.
+    /// Returns the number of plaintext bytes.
+    pub async fn extract_stream<R, W>(&self, reader: &mut R, writer: &mut W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut magic = [0u8; STREAM_MAGIC.len()];
+        reader.read_exact(&mut magic).await
+            .map_err(|_| anyhow!(SecurityError::AuthenticationFailed("Not a secured stream".into())))?;
+        if &magic != STREAM_MAGIC {
+            return Err(anyhow!(SecurityError::AuthenticationFailed("Not a secured stream".into())));
+        }
+        
+        let header: SecurityHeader = bincode::deserialize(&read_frame(reader, MAX_HEADER_SIZE).await?
+            .ok_or_else(truncated)?)
+            .map_err(|e| anyhow!(SecurityError::AuthenticationFailed(format!("Invalid stream header: {}", e))))?;
+        
+        if header.version != HEADER_VERSION || header.mode != SecurityMode::Encrypted {
+            return Err(anyhow!(SecurityError::AuthenticationFailed(
+                format!("Unsupported stream header: version {}, mode {:?}", header.version, header.mode)
+            )));
+        }
+        
+        self.check_suite(header.suite)?;
+        self.replay_guard.check(&header)?;
+        
+        let suite = header.suite;
+        let aad = header.authenticated_data();
+        let max_frame = MAX_CHUNK_SIZE + TAG_SIZE;
+        let first = read_frame(reader, max_frame).await?.ok_or_else(truncated)?;
+        
+        // Authenticating the first chunk authenticates the header, which may
+        // move a session ratchet to the header's epoch
+        let (stream_key, mut plaintext, mut last) = self.ratchets.with_receive(&self.key_manager, &header.key, || {
+            let stream_key = self.key_manager.derive(&header.key.encryption_id(), Some(&header.nonce),
+                STREAM_KEY_INFO, crypto::CHACHA_KEY_SIZE)?;
+            let (plaintext, last) = open_chunk(suite, &first, 0, &stream_key, &aad)?;
+            Ok((stream_key, plaintext, last))
+        })?;
+        
+        // Only record the sequence number once the stream is authenticated
+        self.replay_guard.accept(&header)?;
+        
+        let mut index = 0u64;
+        let mut total = 0u64;
+        loop {
+            writer.write_all(&plaintext).await?;
+            total += plaintext.len() as u64;
+            
+            let frame = read_frame(reader, max_frame).await?;
+            if last {
+                if frame.is_some() {
+                    return Err(anyhow!(SecurityError::DecryptionFailed("Data after the final stream chunk".into())));
+                }
+                break;
+            }
+            
+            index += 1;
+            (plaintext, last) = open_chunk(suite, &frame.ok_or_else(truncated)?, index, &stream_key, &aad)?;
+        }
+        
+        writer.flush().await?;
+        Ok(total)
+    }
+}
+
+/// Decrypt a chunk, returning its plaintext and whether it is the final chunk
+fn open_chunk(suite: CipherSuite, ciphertext: &[u8], index: u64, key: &SecretBytes,
+              aad: &[u8]) -> Result<(Vec<u8>, bool)> {
+    if let Ok(plaintext) = crypto::decrypt_with_suite(suite, ciphertext, &chunk_nonce(suite, index, false), key, aad) {
+        return Ok((plaintext, false));
+    }
+    
+    let plaintext = crypto::decrypt_with_suite(suite, ciphertext, &chunk_nonce(suite, index, true), key, aad)
+        .map_err(|_| anyhow!(SecurityError::DecryptionFailed(format!("Stream chunk {} failed authentication", index))))?;
+    Ok((plaintext, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::key_manager::{KeyManager, KeyUsage};
+    
+    fn create_service() -> SecurityService {
+        let km = KeyManager::new();
+        km.generate_encryption_key("test", "Test encryption key", None).unwrap();
+        SecurityService::new(km)
+    }
+    
+    /// Offsets of the frames following the magic
+    fn frame_offsets(stream: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut offset = STREAM_MAGIC.len();
+        while offset < stream.len() {
+            offsets.push(offset);
+            offset += 4 + u32::from_be_bytes(stream[offset..offset + 4].try_into().unwrap()) as usize;
+        }
+        offsets
+    }
+    
+    #[tokio::test]
+    async fn test_stream_roundtrip() {
+        let service = create_service();
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        
+        for len in [0, 1000, 1024, data.len()] {
+            let mut secured = Vec::new();
+            let written = service.secure_stream(&mut &data[..len], &mut secured, "test", 1024).await.unwrap();
+            assert_eq!(written, len as u64);
+            
+            let mut extracted = Vec::new();
+            let read = service.extract_stream(&mut &secured[..], &mut extracted).await.unwrap();
+            assert_eq!(read, len as u64);
+            assert_eq!(extracted, &data[..len]);
+        }
+        
+        // Streams are subject to replay protection
+        let mut secured = Vec::new();
+        service.secure_stream(&mut &data[..], &mut secured, "test", DEFAULT_CHUNK_SIZE).await.unwrap();
+        assert!(service.extract_stream(&mut &secured[..], &mut Vec::new()).await.is_ok());
+        let err = service.extract_stream(&mut &secured[..], &mut Vec::new()).await.err().unwrap();
+        assert!(matches!(err.downcast_ref::<SecurityError>(), Some(SecurityError::ReplayDetected(_))));
+        
+        // Each stream counts once against the key, with all of its bytes
+        let usage = service.key_manager().key_usage("test").unwrap();
+        assert_eq!(usage, KeyUsage { encryptions: 5, bytes: 1000 + 1024 + 2 * data.len() as u64 });
+    }
+    
+    #[tokio::test]
+    async fn test_stream_tampering_detected() {
+        let service = create_service();
+        let data = vec![0x5A; 4096];
+        
+        let mut streams = Vec::new();
+        for _ in 0..4 {
+            let mut secured = Vec::new();
+            service.secure_stream(&mut &data[..], &mut secured, "test", 1000).await.unwrap();
+            streams.push(secured);
+        }
+        let frames = frame_offsets(&streams[0]);
+        assert_eq!(frames.len(), 6);
+        
+        // Dropping the final chunk is detected even at a frame boundary
+        let truncated = &streams[0][..frames[5]];
+        assert!(service.extract_stream(&mut &truncated[..], &mut Vec::new()).await.is_err());
+        
+        // Swapping two chunks fails authentication
+        let secured = &streams[1];
+        let mut swapped = secured[..frames[1]].to_vec();
+        swapped.extend_from_slice(&secured[frames[2]..frames[3]]);
+        swapped.extend_from_slice(&secured[frames[1]..frames[2]]);
+        swapped.extend_from_slice(&secured[frames[3]..]);
+        assert!(service.extract_stream(&mut &swapped[..], &mut Vec::new()).await.is_err());
+        
+        // So does a flipped ciphertext bit
+        let mut flipped = streams[2].clone();
+        flipped[frames[3] + 10] ^= 0x01;
+        assert!(service.extract_stream(&mut &flipped[..], &mut Vec::new()).await.is_err());
+        
+        // Untouched streams are still accepted after the failed attempts
+        assert!(service.extract_stream(&mut &streams[1][..], &mut Vec::new()).await.is_ok());
+        assert!(service.extract_stream(&mut &streams[3][..], &mut Vec::new()).await.is_ok());
+    }
+}
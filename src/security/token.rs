@@ -0,0 +1,337 @@
+//! Software stand-in for a PKCS#11 token
+//!
+//! Behaves the way the gateway relies on a hardware token behaving: the
+//! token must be logged in with its user PIN before use, secret and private
+//! keys are sensitive objects that cannot be read back once generated or
+//! imported, and signing, encryption, decryption, MACs and key derivation
+//! happen inside the token. Public verification keys are ordinary objects and can be read.
+//! Objects only live as long as the process, so this is meant for tests and
+//! for exercising the token code path without a device.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::security::{crypto, SecurityError};
+use crate::security::crypto::{CipherSuite, NONCE_SIZE};
+use crate::security::key_backend::KeyStore;
+use crate::security::key_manager::{KeyEntry, KeyMetadata, KeyType, KeyUsage};
+use crate::security::secret::SecretBytes;
+
+/// Key object held by the token
+struct TokenObject {
+    metadata: KeyMetadata,
+    value: SecretBytes,
+}
+
+impl TokenObject {
+    /// Only public keys may leave the token
+    fn is_extractable(&self) -> bool {
+        self.metadata.key_type == KeyType::Verification
+    }
+}
+
+/// In-memory token with PKCS#11 object semantics
+pub struct SoftwareToken {
+    label: String,
+    pin: SecretBytes,
+    logged_in: AtomicBool,
+    objects: RwLock<HashMap<String, TokenObject>>,
+}
+
+impl SoftwareToken {
+    /// Create an empty token protected by a user PIN
+    pub fn new(label: &str, pin: &[u8]) -> Self {
+        Self {
+            label: label.to_string(),
+            pin: SecretBytes::from_slice(pin),
+            logged_in: AtomicBool::new(false),
+            objects: RwLock::new(HashMap::new()),
+        }
+    }
+    
+    /// Open a user session
+    pub fn login(&self, pin: &[u8]) -> Result<()> {
+        if !crypto::constant_time_eq(pin, self.pin.expose()) {
+            return Err(anyhow!(SecurityError::AuthenticationFailed(
+                format!("Incorrect PIN for token {}", self.label)
+            )));
+        }
+        
+        self.logged_in.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    
+    /// Close the user session; keys are unusable until the next login
+    pub fn logout(&self) {
+        self.logged_in.store(false, Ordering::SeqCst);
+    }
+    
+    fn check_session(&self) -> Result<()> {
+        if !self.logged_in.load(Ordering::SeqCst) {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Token {} is not logged in", self.label)
+            )));
+        }
+        
+        Ok(())
+    }
+    
+    /// Run an operation with the value of a key object of the given type
+    fn with_key<R>(&self, id: &str, key_type: KeyType, operation: impl FnOnce(&SecretBytes) -> Result<R>) -> Result<R> {
+        self.check_session()?;
+        
+        let objects = self.objects.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on token objects"))?;
+        
+        let object = objects.get(id)
+            .filter(|object| object.metadata.key_type == key_type)
+            .ok_or_else(|| anyhow!(SecurityError::KeyError(
+                format!("No {:?} key {} on token {}", key_type, id, self.label)
+            )))?;
+        
+        operation(&object.value)
+    }
+}
+
+impl KeyStore for SoftwareToken {
+    fn describe(&self) -> String {
+        format!("token {}", self.label)
+    }
+    
+    fn metadata(&self, id: &str) -> Result<Option<KeyMetadata>> {
+        self.check_session()?;
+        
+        let objects = self.objects.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on token objects"))?;
+        
+        Ok(objects.get(id).map(|object| object.metadata.clone()))
+    }
+    
+    fn list(&self) -> Result<Vec<KeyMetadata>> {
+        self.check_session()?;
+        
+        let objects = self.objects.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on token objects"))?;
+        
+        Ok(objects.values().map(|object| object.metadata.clone()).collect())
+    }
+    
+    /// Import keys; secret and private keys become sensitive on import
+    fn insert(&self, entries: Vec<KeyEntry>) -> Result<()> {
+        self.check_session()?;
+        
+        let mut objects = self.objects.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on token objects"))?;
+        
+        for entry in entries {
+            objects.insert(entry.metadata.id.clone(), TokenObject {
+                metadata: entry.metadata,
+                value: entry.key_data,
+            });
+        }
+        
+        Ok(())
+    }
+    
+    fn set_expiry(&self, id: &str, expires_at: Option<u64>) -> Result<()> {
+        self.check_session()?;
+        
+        let mut objects = self.objects.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on token objects"))?;
+        
+        let object = objects.get_mut(id)
+            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
+        object.metadata.expires_at = expires_at;
+        
+        Ok(())
+    }
+    
+    fn set_usage(&self, id: &str, usage: KeyUsage) -> Result<()> {
+        self.check_session()?;
+        
+        let mut objects = self.objects.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on token objects"))?;
+        
+        let object = objects.get_mut(id)
+            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
+        object.metadata.usage = usage;
+        
+        Ok(())
+    }
+    
+    fn remove(&self, id: &str) -> Result<bool> {
+        self.check_session()?;
+        
+        let mut objects = self.objects.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on token objects"))?;
+        
+        Ok(objects.remove(id).is_some())
+    }
+    
+    fn export(&self, id: &str) -> Result<SecretBytes> {
+        self.check_session()?;
+        
+        let objects = self.objects.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on token objects"))?;
+        
+        let object = objects.get(id)
+            .ok_or_else(|| SecurityError::KeyError(format!("Key not found: {}", id)))?;
+        
+        if !object.is_extractable() {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Key {} cannot be extracted from token {}", id, self.label)
+            )));
+        }
+        
+        Ok(object.value.clone())
+    }
+    
+    fn sign(&self, id: &str, message: &[u8]) -> Result<Vec<u8>> {
+        self.with_key(id, KeyType::Signing, |key| crypto::sign_message(message, key))
+    }
+    
+    fn encrypt(&self, id: &str, suite: CipherSuite, plaintext: &[u8], associated_data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.with_key(id, KeyType::Encryption, |key| crypto::encrypt_with_suite(suite, plaintext, key, associated_data))
+    }
+    
+    fn decrypt(&self, id: &str, suite: CipherSuite, ciphertext: &[u8], nonce: &[u8],
+               associated_data: &[u8]) -> Result<Vec<u8>> {
+        self.with_key(id, KeyType::Encryption,
+            |key| crypto::decrypt_with_suite(suite, ciphertext, nonce, key, associated_data))
+    }
+    
+    fn seal(&self, id: &str, nonce: &[u8; NONCE_SIZE], plaintext: &[u8], associated_data: &[u8],
+            tag_len: usize) -> Result<Vec<u8>> {
+        self.with_key(id, KeyType::Encryption,
+            |key| crypto::encrypt_truncated(plaintext, nonce, key, associated_data, tag_len))
+    }
+    
+    fn open(&self, id: &str, nonce: &[u8; NONCE_SIZE], data: &[u8], associated_data: &[u8],
+            tag_len: usize) -> Result<Vec<u8>> {
+        self.with_key(id, KeyType::Encryption,
+            |key| crypto::decrypt_truncated(data, nonce, key, associated_data, tag_len))
+    }
+    
+    fn mac(&self, id: &str, message: &[u8], tag_len: usize) -> Result<Vec<u8>> {
+        self.with_key(id, KeyType::Encryption, |key| crypto::compute_mac(message, key, tag_len))
+    }
+    
+    fn verify_mac(&self, id: &str, message: &[u8], tag: &[u8]) -> Result<()> {
+        self.with_key(id, KeyType::Encryption, |key| crypto::verify_mac(message, tag, key))
+    }
+    
+    fn derive(&self, id: &str, salt: Option<&[u8]>, info: &[u8], len: usize) -> Result<SecretBytes> {
+        self.with_key(id, KeyType::Encryption, |key| crypto::derive_key(key, salt, info, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{SecurityMode, SecurityService};
+    use crate::security::compact::CompactLink;
+    use crate::security::key_manager::KeyManager;
+    use crate::security::handshake::{Initiator, Responder};
+    use crate::security::key_ring::RingMaterial;
+    use crate::security::ratchet::RatchetSchedule;
+    
+    #[test]
+    fn test_keys_stay_on_token() {
+        let token = SoftwareToken::new("test", b"1234");
+        assert!(token.list().is_err());
+        assert!(token.login(b"0000").is_err());
+        token.login(b"1234").unwrap();
+        
+        let km = KeyManager::with_store(Box::new(token));
+        km.generate_encryption_key("link", "Link key", None).unwrap();
+        km.generate_keypair("link", "Link keypair", None).unwrap();
+        
+        // Secret and private keys cannot be read, public keys can
+        assert!(km.get_encryption_key("link").is_err());
+        assert!(km.get_signing_key("link-signing").is_err());
+        assert!(km.get_verification_key("link-verify").is_ok());
+        
+        // The security service works unchanged on top of the token
+        let security = SecurityService::new(km);
+        let secured = security.secure_message(b"telemetry", SecurityMode::EncryptedAndSigned, "link").unwrap();
+        assert_eq!(security.extract_message(&secured).unwrap(), b"telemetry");
+    }
+    
+    #[test]
+    fn test_operations_need_session() {
+        let token = SoftwareToken::new("test", b"1234");
+        token.login(b"1234").unwrap();
+        token.generate_keypair(
+            KeyMetadata { id: "k-signing".into(), key_type: KeyType::Signing, created_at: 0, expires_at: None, description: String::new(), usage: KeyUsage::default() },
+            KeyMetadata { id: "k-verify".into(), key_type: KeyType::Verification, created_at: 0, expires_at: None, description: String::new(), usage: KeyUsage::default() },
+        ).unwrap();
+        
+        assert!(token.sign("k-signing", b"message").is_ok());
+        assert!(token.sign("k-verify", b"message").is_err());
+        
+        token.logout();
+        assert!(token.sign("k-signing", b"message").is_err());
+        assert!(token.export("k-verify").is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_every_mode_on_token() {
+        let token = SoftwareToken::new("test", b"1234");
+        token.login(b"1234").unwrap();
+        let km = KeyManager::with_store(Box::new(token));
+        km.add_ring_version("link", RingMaterial::Both, "Link key").unwrap();
+        
+        let mut security = SecurityService::new(km);
+        security.set_sender_id("gw-a");
+        
+        for mode in [SecurityMode::None, SecurityMode::Signed, SecurityMode::Encrypted,
+                     SecurityMode::EncryptedAndSigned, SecurityMode::Authenticated] {
+            let secured = security.secure_message(b"telemetry", mode, "link").unwrap();
+            assert_eq!(security.extract_message(&secured).unwrap(), b"telemetry", "{:?} failed", mode);
+        }
+        
+        let link = CompactLink::new("gw-a", vec!["link".to_string()], 8).unwrap();
+        for mode in [SecurityMode::Encrypted, SecurityMode::Authenticated] {
+            let envelope = security.secure_compact(b"alt=1200", mode, "link", &link).unwrap();
+            assert_eq!(security.extract_compact(&envelope, &link).unwrap(), b"alt=1200", "compact {:?} failed", mode);
+        }
+        
+        let data = vec![0x42u8; 3000];
+        let mut stream = Vec::new();
+        security.secure_stream(&mut &data[..], &mut stream, "link", 1024).await.unwrap();
+        let mut plaintext = Vec::new();
+        security.extract_stream(&mut &stream[..], &mut plaintext).await.unwrap();
+        assert_eq!(plaintext, data);
+        
+        // Ratchets derive their seed from the handshake key through the backend
+        let create_token_manager = |name: &str| {
+            let token = SoftwareToken::new(name, b"1234");
+            token.login(b"1234").unwrap();
+            let km = KeyManager::with_store(Box::new(token));
+            km.generate_keypair(name, "Identity", None).unwrap();
+            km
+        };
+        let km_a = create_token_manager("gw-a");
+        let km_b = create_token_manager("gw-b");
+        km_b.import_key("gw-a-verify", KeyType::Verification,
+            &km_a.get_verification_key("gw-a-verify").unwrap(), "Peer gw-a", None).unwrap();
+        km_a.import_key("gw-b-verify", KeyType::Verification,
+            &km_b.get_verification_key("gw-b-verify").unwrap(), "Peer gw-b", None).unwrap();
+        
+        let (initiator, init) = Initiator::start("gw-a").unwrap();
+        let (responder, response) = Responder::respond(&km_b, "gw-b", &init).unwrap();
+        let (finish, keys_a) = initiator.finish(&km_a, &response).unwrap();
+        let keys_b = responder.finish(&km_b, &finish).unwrap();
+        
+        let mut a = SecurityService::new(km_a);
+        a.set_sender_id("gw-a");
+        let b = SecurityService::new(km_b);
+        a.start_session_ratchet(&keys_a, RatchetSchedule::default()).unwrap();
+        b.start_session_ratchet(&keys_b, RatchetSchedule::default()).unwrap();
+        
+        let secured = a.secure_message(b"set mode 3", SecurityMode::Encrypted, &keys_a.send_key_id).unwrap();
+        assert_eq!(b.extract_message(&secured).unwrap(), b"set mode 3");
+    }
+}
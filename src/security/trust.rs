@@ -0,0 +1,401 @@
+//! Peer trust store
+//!
+//! Without a trust store, any verification key in the `KeyManager` is
+//! trusted. With one, a verification key is only served if the matching
+//! private key is held locally or if a root authority has endorsed it for a
+//! peer. Endorsements carry the peer's identity, a validity period and the
+//! roles the key may be used for, and are signed with the root's Ed25519
+//! key. A revocation list, also signed by the root, is checked on every
+//! lookup.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::RwLock;
+
+use crate::config::TrustStoreConfig;
+use crate::security::SecurityError;
+use crate::security::crypto::{self, ED25519_PUBLIC_KEY_SIZE};
+use crate::security::secret::SecretBytes;
+use crate::utils::{current_time_millis, hex_to_bytes};
+
+/// Domain separation for endorsement signatures
+const ENDORSEMENT_CONTEXT: &[u8] = b"secure-gateway endorsement v1";
+
+/// Domain separation for revocation list signatures
+const REVOCATION_CONTEXT: &[u8] = b"secure-gateway revocations v1";
+
+/// SHA-256 fingerprint of a verification key
+pub type KeyFingerprint = [u8; 32];
+
+/// Compute the fingerprint identifying a key in revocation lists
+pub fn fingerprint(key: &[u8]) -> KeyFingerprint {
+    Sha256::digest(key).into()
+}
+
+/// What an endorsed key may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PeerRole {
+    /// Signing secured messages
+    Messaging,
+    
+    /// Authenticating session handshakes
+    Handshake,
+}
+
+impl PeerRole {
+    fn as_u8(&self) -> u8 {
+        match self {
+            PeerRole::Messaging => 0,
+            PeerRole::Handshake => 1,
+        }
+    }
+}
+
+/// Root authority statement binding a verification key to a peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endorsement {
+    /// Peer identity, as used for the sender of its messages
+    pub peer: String,
+    
+    /// Ed25519 verification key
+    pub verification_key: Vec<u8>,
+    
+    /// Start of validity, in seconds since the Unix epoch
+    pub not_before: u64,
+    
+    /// End of validity, in seconds since the Unix epoch
+    pub not_after: u64,
+    
+    pub roles: Vec<PeerRole>,
+    
+    /// Root authority signature over the fields above
+    pub signature: Vec<u8>,
+}
+
+impl Endorsement {
+    /// Create and sign an endorsement with the root authority's private key
+    pub fn issue(peer: &str, verification_key: &[u8], not_before: u64, not_after: u64,
+                 roles: Vec<PeerRole>, root_key: &SecretBytes) -> Result<Self> {
+        let mut endorsement = Self {
+            peer: peer.to_string(),
+            verification_key: verification_key.to_vec(),
+            not_before,
+            not_after,
+            roles,
+            signature: vec![],
+        };
+        
+        endorsement.signature = crypto::sign_message(&endorsement.signed_content(), root_key)?;
+        Ok(endorsement)
+    }
+    
+    /// Canonical encoding covered by the signature
+    fn signed_content(&self) -> Vec<u8> {
+        let mut content = ENDORSEMENT_CONTEXT.to_vec();
+        
+        content.extend_from_slice(&(self.peer.len() as u16).to_be_bytes());
+        content.extend_from_slice(self.peer.as_bytes());
+        content.extend_from_slice(&(self.verification_key.len() as u16).to_be_bytes());
+        content.extend_from_slice(&self.verification_key);
+        content.extend_from_slice(&self.not_before.to_be_bytes());
+        content.extend_from_slice(&self.not_after.to_be_bytes());
+        content.push(self.roles.len() as u8);
+        content.extend(self.roles.iter().map(PeerRole::as_u8));
+        
+        content
+    }
+    
+    /// Check that the endorsement is valid at `now` (seconds since the Unix epoch)
+    fn check_validity(&self, now: u64) -> Result<()> {
+        if now < self.not_before || now > self.not_after {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Endorsement of {} is not valid at this time", self.peer)
+            )));
+        }
+        
+        Ok(())
+    }
+}
+
+/// Root authority list of revoked verification keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevocationList {
+    /// Seconds since the Unix epoch; older lists never replace newer ones
+    pub issued_at: u64,
+    
+    /// Fingerprints of revoked keys
+    pub revoked: Vec<KeyFingerprint>,
+    
+    /// Root authority signature over the fields above
+    pub signature: Vec<u8>,
+}
+
+impl RevocationList {
+    /// Create and sign a revocation list with the root authority's private key
+    pub fn issue(issued_at: u64, revoked: Vec<KeyFingerprint>, root_key: &SecretBytes) -> Result<Self> {
+        let mut list = Self {
+            issued_at,
+            revoked,
+            signature: vec![],
+        };
+        
+        list.signature = crypto::sign_message(&list.signed_content(), root_key)?;
+        Ok(list)
+    }
+    
+    /// Canonical encoding covered by the signature
+    fn signed_content(&self) -> Vec<u8> {
+        let mut content = REVOCATION_CONTEXT.to_vec();
+        
+        content.extend_from_slice(&self.issued_at.to_be_bytes());
+        content.extend_from_slice(&(self.revoked.len() as u32).to_be_bytes());
+        for fingerprint in &self.revoked {
+            content.extend_from_slice(fingerprint);
+        }
+        
+        content
+    }
+}
+
+/// Currently applied revocations
+#[derive(Debug, Default)]
+struct Revocations {
+    issued_at: u64,
+    revoked: HashSet<KeyFingerprint>,
+}
+
+/// Endorsed peer keys and revocations under one root authority
+pub struct TrustStore {
+    /// Root authority verification key
+    root_key: Vec<u8>,
+    
+    /// Endorsements by verification key ID
+    endorsements: RwLock<HashMap<String, Endorsement>>,
+    
+    revocations: RwLock<Revocations>,
+}
+
+impl TrustStore {
+    /// Create an empty trust store for a root authority key
+    pub fn new(root_key: &[u8]) -> Result<Self> {
+        if root_key.len() != ED25519_PUBLIC_KEY_SIZE {
+            return Err(anyhow!(SecurityError::ConfigError(
+                format!("Invalid root key size: {} (expected {})", root_key.len(), ED25519_PUBLIC_KEY_SIZE)
+            )));
+        }
+        
+        Ok(Self {
+            root_key: root_key.to_vec(),
+            endorsements: RwLock::new(HashMap::new()),
+            revocations: RwLock::new(Revocations::default()),
+        })
+    }
+    
+    /// Load a trust store and its endorsements and revocations from configuration
+    ///
+    /// The endorsements file is a JSON object mapping verification key IDs
+    /// to endorsements; the revocation list file is a JSON `RevocationList`.
+    pub fn from_config(config: &TrustStoreConfig) -> Result<Self> {
+        let root_key = hex_to_bytes(config.root_key.trim())
+            .map_err(|e| anyhow!(SecurityError::ConfigError(format!("Invalid root key: {}", e))))?;
+        let store = Self::new(&root_key)?;
+        
+        // Apply revocations first so revoked endorsements are refused
+        if let Some(path) = &config.revocation_list_path {
+            let list: RevocationList = serde_json::from_slice(&fs::read(path)
+                .with_context(|| format!("Failed to read revocation list {}", path))?)
+                .with_context(|| format!("Failed to parse revocation list {}", path))?;
+            store.update_revocations(&list)?;
+        }
+        
+        if let Some(path) = &config.endorsements_path {
+            let endorsements: HashMap<String, Endorsement> = serde_json::from_slice(&fs::read(path)
+                .with_context(|| format!("Failed to read endorsements {}", path))?)
+                .with_context(|| format!("Failed to parse endorsements {}", path))?;
+            
+            for (id, endorsement) in endorsements {
+                store.add_endorsement(&id, endorsement)
+                    .with_context(|| format!("Rejected endorsement for {}", id))?;
+            }
+        }
+        
+        Ok(store)
+    }
+    
+    /// Add a root-signed endorsement for the verification key `id`
+    pub fn add_endorsement(&self, id: &str, endorsement: Endorsement) -> Result<()> {
+        if endorsement.verification_key.len() != ED25519_PUBLIC_KEY_SIZE {
+            return Err(anyhow!(SecurityError::KeyError(
+                format!("Invalid endorsed key size: {}", endorsement.verification_key.len())
+            )));
+        }
+        
+        crypto::verify_signature(&endorsement.signed_content(), &endorsement.signature, &self.root_key)
+            .context("Endorsement is not signed by the root authority")?;
+        self.check_not_revoked(&endorsement.verification_key)?;
+        
+        self.endorsements.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on endorsements"))?
+            .insert(id.to_string(), endorsement);
+        
+        Ok(())
+    }
+    
+    /// Endorsement of a verification key, if any
+    pub fn endorsement(&self, id: &str) -> Result<Option<Endorsement>> {
+        self.endorsements.read()
+            .map(|endorsements| endorsements.get(id).cloned())
+            .map_err(|_| anyhow!("Failed to acquire read lock on endorsements"))
+    }
+    
+    /// Replace the revocation list with a newer one signed by the root authority
+    pub fn update_revocations(&self, list: &RevocationList) -> Result<()> {
+        crypto::verify_signature(&list.signed_content(), &list.signature, &self.root_key)
+            .context("Revocation list is not signed by the root authority")?;
+        
+        let mut revocations = self.revocations.write()
+            .map_err(|_| anyhow!("Failed to acquire write lock on revocations"))?;
+        
+        if list.issued_at < revocations.issued_at {
+            return Err(anyhow!(SecurityError::ConfigError(
+                "Revocation list is older than the one in use".into()
+            )));
+        }
+        
+        *revocations = Revocations {
+            issued_at: list.issued_at,
+            revoked: list.revoked.iter().copied().collect(),
+        };
+        
+        Ok(())
+    }
+    
+    /// Fail if a verification key has been revoked
+    pub fn check_not_revoked(&self, key: &[u8]) -> Result<()> {
+        let revocations = self.revocations.read()
+            .map_err(|_| anyhow!("Failed to acquire read lock on revocations"))?;
+        
+        if revocations.revoked.contains(&fingerprint(key)) {
+            return Err(anyhow!(SecurityError::KeyError("Verification key has been revoked".into())));
+        }
+        
+        Ok(())
+    }
+    
+    /// Verification key `id` if it is endorsed, currently valid and not revoked
+    ///
+    /// When `peer` is given, the endorsement must name that peer and allow `role`.
+    pub fn endorsed_key(&self, id: &str, peer: Option<&str>, role: PeerRole) -> Result<Option<Vec<u8>>> {
+        let Some(endorsement) = self.endorsement(id)? else {
+            return Ok(None);
+        };
+        
+        endorsement.check_validity(current_time_millis() / 1000)?;
+        self.check_not_revoked(&endorsement.verification_key)?;
+        
+        if let Some(peer) = peer {
+            if endorsement.peer != peer {
+                return Err(anyhow!(SecurityError::AuthenticationFailed(
+                    format!("Key {} is endorsed for {}, not {}", id, endorsement.peer, peer)
+                )));
+            }
+            
+            if !endorsement.roles.contains(&role) {
+                return Err(anyhow!(SecurityError::AuthenticationFailed(
+                    format!("Key {} is not endorsed for {:?}", id, role)
+                )));
+            }
+        }
+        
+        Ok(Some(endorsement.verification_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::key_manager::{KeyManager, KeyType};
+    
+    fn create_store() -> (TrustStore, SecretBytes) {
+        let (root_private, root_public) = crypto::generate_signing_keypair().unwrap();
+        (TrustStore::new(&root_public).unwrap(), root_private)
+    }
+    
+    fn endorse(peer: &str, key: &[u8], roles: Vec<PeerRole>, root: &SecretBytes) -> Endorsement {
+        let now = current_time_millis() / 1000;
+        Endorsement::issue(peer, key, now - 60, now + 3600, roles, root).unwrap()
+    }
+    
+    #[test]
+    fn test_endorsed_keys() {
+        let (store, root) = create_store();
+        let (_, peer_key) = crypto::generate_signing_keypair().unwrap();
+        
+        store.add_endorsement("link/v1-verify", endorse("gw-b", &peer_key, vec![PeerRole::Messaging], &root)).unwrap();
+        
+        assert_eq!(store.endorsed_key("link/v1-verify", Some("gw-b"), PeerRole::Messaging).unwrap(), Some(peer_key.clone()));
+        assert!(store.endorsed_key("link/v1-verify", Some("gw-c"), PeerRole::Messaging).is_err());
+        assert!(store.endorsed_key("link/v1-verify", Some("gw-b"), PeerRole::Handshake).is_err());
+        assert_eq!(store.endorsed_key("other-verify", None, PeerRole::Messaging).unwrap(), None);
+        
+        // Endorsements not signed by the root, altered, or expired are refused
+        let (impostor, _) = crypto::generate_signing_keypair().unwrap();
+        assert!(store.add_endorsement("x", endorse("gw-b", &peer_key, vec![PeerRole::Messaging], &impostor)).is_err());
+        
+        let mut widened = endorse("gw-b", &peer_key, vec![PeerRole::Messaging], &root);
+        widened.roles.push(PeerRole::Handshake);
+        assert!(store.add_endorsement("x", widened).is_err());
+        
+        let expired = Endorsement::issue("gw-b", &peer_key, 0, 1, vec![PeerRole::Messaging], &root).unwrap();
+        store.add_endorsement("old-verify", expired).unwrap();
+        assert!(store.endorsed_key("old-verify", None, PeerRole::Messaging).is_err());
+    }
+    
+    #[test]
+    fn test_revocation() {
+        let (store, root) = create_store();
+        let (_, peer_key) = crypto::generate_signing_keypair().unwrap();
+        store.add_endorsement("gw-b-verify", endorse("gw-b", &peer_key, vec![PeerRole::Handshake], &root)).unwrap();
+        
+        let list = RevocationList::issue(100, vec![fingerprint(&peer_key)], &root).unwrap();
+        store.update_revocations(&list).unwrap();
+        assert!(store.endorsed_key("gw-b-verify", Some("gw-b"), PeerRole::Handshake).is_err());
+        
+        // Older or unsigned lists cannot lift a revocation
+        assert!(store.update_revocations(&RevocationList::issue(50, vec![], &root).unwrap()).is_err());
+        let mut forged = RevocationList::issue(200, vec![], &root).unwrap();
+        forged.revoked.push([0; 32]);
+        assert!(store.update_revocations(&forged).is_err());
+        
+        store.update_revocations(&RevocationList::issue(200, vec![], &root).unwrap()).unwrap();
+        assert!(store.endorsed_key("gw-b-verify", Some("gw-b"), PeerRole::Handshake).unwrap().is_some());
+    }
+    
+    #[test]
+    fn test_key_manager_provenance() {
+        let (store, root) = create_store();
+        let (_, peer_key) = crypto::generate_signing_keypair().unwrap();
+        
+        let mut km = KeyManager::new();
+        km.generate_keypair("local", "Own keypair", None).unwrap();
+        km.import_key("legacy-verify", KeyType::Verification, &peer_key, "Unendorsed", None).unwrap();
+        km.set_trust_store(store);
+        
+        // Own keys remain usable; keys without provenance do not
+        let local_key = km.get_verification_key("local-verify").unwrap();
+        assert!(km.get_verification_key("legacy-verify").is_err());
+        assert!(km.import_key("peer-verify", KeyType::Verification, &peer_key, "Peer", None).is_err());
+        
+        km.import_endorsed_key("peer-verify", endorse("gw-b", &peer_key, vec![PeerRole::Messaging], &root)).unwrap();
+        assert_eq!(km.get_peer_verification_key("peer-verify", "gw-b", PeerRole::Messaging).unwrap(), peer_key);
+        assert!(km.get_peer_verification_key("peer-verify", "gw-c", PeerRole::Messaging).is_err());
+        
+        // Revocation applies to local keys too
+        let list = RevocationList::issue(1, vec![fingerprint(&local_key)], &root).unwrap();
+        km.trust_store().unwrap().update_revocations(&list).unwrap();
+        assert!(km.get_verification_key("local-verify").is_err());
+    }
+}
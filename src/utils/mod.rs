@@ -24,7 +24,7 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
 
 /// Parse a hexadecimal string to bytes
 pub fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, String> {
-    if hex.len() % 2 != 0 {
+    if !hex.len().is_multiple_of(2) {
         return Err("Invalid hex string length".to_string());
     }
     